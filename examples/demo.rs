@@ -0,0 +1,34 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! The demo scene, plus a system to make one of the entities in it drift
+//! along so there's something moving. Run it from the repository root so it
+//! can find `./data`:
+//!
+//! ```sh
+//! cargo run --example demo
+//! ```
+
+use embryo::{entity::transform_component::TransformComponent, App, System, SystemContext};
+
+fn physics(ctx: &SystemContext) {
+    let transforms = &mut ctx
+        .entities
+        .get_component_vec_mut::<TransformComponent>()
+        .unwrap();
+    if let Some(mut e) = transforms.get_mut(31) {
+        e.displace_by(glam::vec3(0.0, 0.0, 0.005));
+    }
+}
+
+fn main() {
+    App::from_args()
+        .scene("./data/scenes/default.toml")
+        .add_system(System::new("physics", physics).writes::<TransformComponent>())
+        .run();
+}
@@ -20,6 +20,120 @@ pub fn component_id_derive(input: proc_macro::TokenStream) -> proc_macro::TokenS
     .into()
 }
 
+/// Implements `Reflect`, exposing every named field (except the ones marked
+/// `#[reflect(skip)]`) by name. Works on structs and on enums, where the
+/// fields are those of whichever variant the value currently is. Everything
+/// it emits is spelled out in full from `::embryo`, so it works wherever it's
+/// used, without having to import anything first.
+#[proc_macro_derive(Reflect, attributes(reflect))]
+pub fn reflect_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let ast: syn::DeriveInput = syn::parse(input).unwrap();
+    let type_name = &ast.ident;
+    let name = &ast.ident.to_string();
+
+    // The path to match each variant (just `Self` for structs) along with
+    // the names and types of its reflected fields
+    let arms: Vec<(proc_macro2::TokenStream, Vec<(syn::Ident, String)>)> = match &ast.data {
+        syn::Data::Struct(data) => vec![(quote! { Self }, reflected_fields(&data.fields))],
+        syn::Data::Enum(data) => data
+            .variants
+            .iter()
+            .map(|variant| {
+                let variant_name = &variant.ident;
+                (
+                    quote! { Self::#variant_name },
+                    reflected_fields(&variant.fields),
+                )
+            })
+            .collect(),
+        syn::Data::Union(_) => panic!("Reflect can't be derived for unions"),
+    };
+
+    let field_infos = arms.iter().map(|(path, fields)| {
+        let infos = fields.iter().map(|(ident, ty)| {
+            let field_name = ident.to_string();
+            quote! { ::embryo::entity::reflect::FieldInfo { name: #field_name, type_name: #ty } }
+        });
+        quote! { #path { .. } => ::std::vec![#(#infos),*], }
+    });
+    let field_arms = arms.iter().flat_map(|(path, fields)| {
+        fields.iter().map(move |(ident, _)| {
+            let field_name = ident.to_string();
+            quote! { (#path { #ident, .. }, #field_name) => ::std::option::Option::Some(#ident), }
+        })
+    });
+    let field_arms_mut = field_arms.clone();
+
+    quote! {
+        impl ::embryo::entity::reflect::Reflect for #type_name {
+            fn type_name(&self) -> &'static str {
+                #name
+            }
+            fn fields(&self) -> ::std::vec::Vec<::embryo::entity::reflect::FieldInfo> {
+                match self {
+                    #(#field_infos)*
+                }
+            }
+            fn field(
+                &self,
+                name: &str,
+            ) -> ::std::option::Option<&dyn ::embryo::entity::reflect::Reflect> {
+                match (self, name) {
+                    #(#field_arms)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+            fn field_mut(
+                &mut self,
+                name: &str,
+            ) -> ::std::option::Option<&mut dyn ::embryo::entity::reflect::Reflect> {
+                match (self, name) {
+                    #(#field_arms_mut)*
+                    _ => ::std::option::Option::None,
+                }
+            }
+            fn as_any(&self) -> &dyn ::std::any::Any {
+                self
+            }
+            fn as_any_mut(&mut self) -> &mut dyn ::std::any::Any {
+                self
+            }
+        }
+    }
+    .into()
+}
+
+fn reflected_fields(fields: &syn::Fields) -> Vec<(syn::Ident, String)> {
+    match fields {
+        syn::Fields::Named(fields) => fields
+            .named
+            .iter()
+            .filter(|field| !field.attrs.iter().any(is_reflect_skip))
+            .map(|field| {
+                let ty = &field.ty;
+                (
+                    field.ident.clone().unwrap(),
+                    quote!(#ty).to_string().replace(' ', ""),
+                )
+            })
+            .collect(),
+        syn::Fields::Unit => vec![],
+        syn::Fields::Unnamed(_) => panic!("Reflect only supports named fields"),
+    }
+}
+
+fn is_reflect_skip(attr: &syn::Attribute) -> bool {
+    match attr.parse_meta() {
+        Ok(syn::Meta::List(list)) if list.path.is_ident("reflect") => {
+            list.nested.iter().any(|nested| match nested {
+                syn::NestedMeta::Meta(syn::Meta::Path(path)) => path.is_ident("skip"),
+                _ => false,
+            })
+        }
+        _ => false,
+    }
+}
+
 #[proc_macro_derive(VertexAttribPointers, attributes(location, divisor))]
 pub fn vertex_attrib_pointers_derive(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let ast = syn::parse(input).unwrap();
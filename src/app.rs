@@ -0,0 +1,409 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! The way into the engine. `App` collects everything a game wants set up on
+//! its `GameState` (which scene to start in, systems, listeners) and then
+//! takes over the main thread to open the window and start the update and
+//! render threads, or run headless.
+
+use std::{
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use crossbeam_channel::{bounded, unbounded, Receiver, Sender};
+
+use crate::{
+    args::Args,
+    controllers::{ControllerRequest, Controllers},
+    dead_drop::DeadDrop,
+    event_bus::Listener,
+    headless,
+    input::FrameInput,
+    render_thread::RendererState,
+    resource_manager::ResourceManager,
+    scheduler::System,
+    update_thread::{spawn_update_thread, GameState, GameStateEvent},
+    utils::config::{self, WindowMode},
+    SendableGl, ShareablePtr, CONFIG,
+};
+
+pub const DEFAULT_SCENE: &str = "./data/scenes/default.toml";
+pub const DEFAULT_PREFABS: &str = "./data/prefabs";
+
+/// Whatever a game wants done to the `GameState` before the first update.
+/// These run on the update thread, in the order they were added.
+pub type SetupFn = Box<dyn FnOnce(&mut GameState) + Send>;
+
+pub struct App {
+    args: Args,
+    scene: String,
+    prefabs: String,
+    setup: Vec<SetupFn>,
+}
+
+impl Default for App {
+    fn default() -> Self {
+        Self::with_args(Args::default())
+    }
+}
+
+impl App {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// An app configured from the command line (see `Args`), which is
+    /// usually what you want.
+    pub fn from_args() -> Self {
+        Self::with_args(Args::parse(std::env::args()))
+    }
+
+    /// Since the config is read the first time anything needs it, this has to
+    /// come before anything else touches `CONFIG` for `--config` and `--set`
+    /// to take effect.
+    pub fn with_args(args: Args) -> Self {
+        config::set_sources(args.config_sources());
+        Self {
+            args,
+            scene: DEFAULT_SCENE.to_string(),
+            prefabs: DEFAULT_PREFABS.to_string(),
+            setup: vec![],
+        }
+    }
+
+    /// The scene to load at startup, instead of `./data/scenes/default.toml`
+    pub fn scene(mut self, path: impl Into<String>) -> Self {
+        self.scene = path.into();
+        self
+    }
+
+    /// Where to load prefabs from, instead of `./data/prefabs`. It's fine for
+    /// the directory not to exist, if the game doesn't use any.
+    pub fn prefabs(mut self, dir: impl Into<String>) -> Self {
+        self.prefabs = dir.into();
+        self
+    }
+
+    pub fn headless(mut self, headless: bool) -> Self {
+        self.args.headless = headless;
+        self
+    }
+
+    pub fn add_system(self, system: System) -> Self {
+        self.setup(move |game_state| game_state.add_system(system))
+    }
+
+    pub fn add_listener(self, listener: Listener) -> Self {
+        self.setup(move |game_state| game_state.add_listener(listener))
+    }
+
+    /// For anything else that needs doing to the game state once the scene is
+    /// loaded, like adding resources.
+    pub fn setup(mut self, f: impl FnOnce(&mut GameState) + Send + 'static) -> Self {
+        self.setup.push(Box::new(f));
+        self
+    }
+
+    /// Runs the game until the window's closed (or, headless, until the
+    /// update loop stops on its own). This is expected to be called from the
+    /// main thread, since that's where SDL wants its events handled.
+    pub fn run(self) {
+        // Leave the logger alone if the game has set up its own
+        let _ = simplelog::TermLogger::init(
+            log::LevelFilter::Trace,
+            simplelog::Config::default(),
+            simplelog::TerminalMode::Mixed,
+            simplelog::ColorChoice::Always,
+        );
+        info!(
+            r#"
+
+                                 @@@@@@@@@@@
+                              @@    @@@@@    @@
+                           @@@  @@@       @@@  @@@
+                          @@ @@ @       @@@@  @@ @@
+                         @  @   @     @@    @   @  @
+                        @  @  @@     @@      @@  @  @
+                       @@ @  @      @@    @@  @@  @ @@
+                       @  @  @@@@@@ @@         @  @  @
+                       @  @       @@  @@       @  @  @
+                       @  @    @@ @            @  @  @
+                       @@ @   @@   @@@        @@  @ @@
+                        @  @   @             @@  @  @
+                         @  @@  @@         @@   @  @
+                          @@ @@    @@@@@@@    @@ @@
+                           @@@  @@@       @@@  @@@
+                              @@    @@@@@    @@
+                                 @@@@@@@@@@@
+
+
+ _____           _                      _____             _
+| ____|_ __ ___ | |__  _ __ _   _  ___ | ____|_ __   __ _(_)_ __   ___
+|  _| | '_ ` _ \| '_ \| '__| | | |/ _ \|  _| | '_ \ / _` | | '_ \ / _ \
+| |___| | | | | | |_) | |  | |_| | (_) | |___| | | | (_| | | | | |  __/
+|_____|_| |_| |_|_.__/|_|   \__, |\___/|_____|_| |_|\__, |_|_| |_|\___|
+                            |___/                   |___/ v 0.1.0
+
+"#
+        );
+        info!("Beginning initialization process...");
+
+        let orig_hook = std::panic::take_hook();
+        std::panic::set_hook(Box::new(move |panic_info| {
+            // invoke the default handler and exit the process
+            orig_hook(panic_info);
+            std::process::exit(1);
+        }));
+
+        let running = Arc::new(AtomicBool::new(true));
+        let watcher = config::watch(Duration::from_secs(1), running.clone());
+        if let Err(e) = &watcher {
+            warn!("Couldn't start watching the config for changes: {}", e);
+        }
+
+        let headless = self.args.headless;
+        let setup = self.into_setup();
+        if headless {
+            headless::run_headless(setup, running.clone());
+        } else {
+            run_windowed(setup, running.clone());
+        }
+
+        running.store(false, std::sync::atomic::Ordering::SeqCst);
+        if let Ok(watcher) = watcher {
+            watcher.thread().unpark();
+            let _ = watcher.join();
+        }
+    }
+
+    /// Everything that happens to the game state before the update loop
+    /// starts, rolled into one. Replays and recordings have to start last, so
+    /// they see the world exactly as it is on the first update.
+    fn into_setup(self) -> impl FnOnce(&mut GameState) + Send + 'static {
+        let Self {
+            args,
+            scene,
+            prefabs,
+            setup,
+        } = self;
+        move |game_state| {
+            game_state.load_initial_entities(&prefabs, &scene);
+            for f in setup {
+                f(game_state);
+            }
+            if let Some(path) = args.replay {
+                if let Err(e) = game_state.start_replay(&path) {
+                    panic!("Couldn't start replay: {}", e);
+                }
+            } else if let Some(path) = args.record {
+                if let Err(e) = game_state.start_recording(&path) {
+                    panic!("Couldn't start recording: {}", e);
+                }
+            }
+        }
+    }
+}
+
+fn run_windowed(setup: impl FnOnce(&mut GameState) + Send + 'static, running: Arc<AtomicBool>) {
+    ///////// Initialize SDL2 window
+
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let mut controllers = Controllers::new(sdl_context.game_controller().unwrap());
+    let _image_context = sdl2::image::init(sdl2::image::InitFlag::all());
+
+    debug!("SDL context created");
+
+    let gl_attr = video_subsystem.gl_attr();
+    gl_attr.set_double_buffer(true);
+    gl_attr.set_context_profile(sdl2::video::GLProfile::Core);
+    gl_attr.set_context_version(4, 6);
+
+    let mut window_builder = video_subsystem.window("Project Gilgamesh v0.1.0", 1920, 1080);
+    window_builder.opengl();
+
+    match CONFIG.load().graphics.fullscreen_mode {
+        WindowMode::Windowed => {
+            window_builder.position_centered();
+        }
+        WindowMode::WindowedFullscreen => {
+            window_builder.fullscreen_desktop();
+        }
+        WindowMode::Fullscreen => {
+            window_builder.fullscreen();
+        }
+    }
+
+    let window = window_builder.build().expect("Could not create OS window!");
+
+    sdl_context.mouse().set_relative_mouse_mode(true);
+
+    debug!("SDL window created");
+
+    ///////// Initialize OpenGL
+
+    let _gl_context = window.gl_create_context().unwrap();
+    let gl = SendableGl(gl::Gl::load_with(|s| {
+        video_subsystem.gl_get_proc_address(s) as *const std::os::raw::c_void
+    }));
+    unsafe {
+        gl.ClampColor(gl::CLAMP_READ_COLOR, gl::FIXED_ONLY);
+    }
+    if CONFIG.load().performance.cap_render_fps {
+        let _ = video_subsystem.gl_set_swap_interval(1);
+    } else {
+        let _ = video_subsystem.gl_set_swap_interval(0);
+    }
+
+    debug!("OpenGL context created and configured");
+
+    info!("Game window created");
+
+    ///////// Initalize game
+
+    let (width, height) = window.size();
+
+    let resource_manager = ResourceManager::new();
+
+    ///////// Game loop
+
+    ////// Update thread
+
+    let render_world_state = DeadDrop::default();
+    let (event_sender, event_receiver): (Sender<GameStateEvent>, Receiver<GameStateEvent>) =
+        unbounded();
+    let (controller_sender, controller_receiver): (
+        Sender<ControllerRequest>,
+        Receiver<ControllerRequest>,
+    ) = unbounded();
+
+    let update_thread = spawn_update_thread(
+        resource_manager.clone(),
+        render_world_state.clone(),
+        event_receiver.clone(),
+        controller_sender,
+        (width, height),
+        running.clone(),
+        setup,
+    );
+
+    ////// Render thread
+
+    // Now we need to transfer the window's GL context to the render thread, to
+    // free us up to focus on just the window itself and render on a different
+    // thread, which involves... unsafe shenanigans.
+    //
+    // See https://github.com/vheuken/SDL-Render-Thread-Example/blob/master/main.cpp for a worked example of what I'm trying to do.
+    unsafe {
+        sdl2::sys::SDL_GL_MakeCurrent(
+            window.raw(),
+            std::ptr::null::<sdl2::sys::SDL_GLContext>() as *mut std::ffi::c_void,
+        );
+    }
+
+    let (renderer_ready_sender, renderer_ready) = bounded::<()>(1);
+    let shareable_window = ShareablePtr(window.raw());
+    let shareable_gl_context;
+    unsafe {
+        shareable_gl_context = ShareablePtr(_gl_context.raw());
+    }
+
+    {
+        let running = running.clone();
+        std::thread::Builder::new()
+            .name("render".to_string())
+            .spawn(move || {
+                let res =
+                    core_affinity::get_core_ids().map(|ids| core_affinity::set_for_current(ids[1]));
+                if res.is_some_and(|r| r) {
+                    let window = shareable_window;
+                    let window = window.0;
+                    unsafe {
+                        let gl_context = shareable_gl_context;
+                        let gl_context = gl_context.0;
+                        sdl2::sys::SDL_GL_MakeCurrent(
+                            window as *mut sdl2::sys::SDL_Window,
+                            gl_context as *mut std::ffi::c_void,
+                        );
+                    }
+                    let _ = renderer_ready_sender.send(());
+                    let mut renderer_state =
+                        RendererState::new(gl, resource_manager.clone(), width, height);
+                    renderer_state.load_shaders();
+                    debug!("Render thread started");
+                    renderer_state.render_loop(
+                        render_world_state,
+                        // NOTE: We want to do this with a callback so that the rest
+                        // of the render thread has no access to the window
+                        // pointer. This has to be done on the thread where the
+                        // GL context is current, because despite taking a
+                        // window pointer, this only really talks to the GL
+                        // driver, to tell it to swap buffers in FB0
+                        move || unsafe {
+                            sdl2::sys::SDL_GL_SwapWindow(window);
+                        },
+                        running,
+                    );
+                }
+            })
+            .expect("Couldn't start the render thread");
+    }
+
+    // Only continue when the other thread is done making these consistent. If
+    // it quit without getting that far, the sender's dropped and this returns
+    // straight away.
+    if renderer_ready.recv().is_err() {
+        error!("Render thread quit before taking over the GL context");
+    }
+    debug!("Event loop thread started");
+
+    let mut event_pump = sdl_context.event_pump().unwrap();
+    let mouse_util = sdl_context.mouse();
+
+    while running.load(std::sync::atomic::Ordering::SeqCst) {
+        if let Some(event) = event_pump.wait_event_timeout(7) {
+            match event {
+                sdl2::event::Event::KeyDown {
+                    scancode: Some(sdl2::keyboard::Scancode::Escape),
+                    ..
+                } => {
+                    mouse_util.set_relative_mouse_mode(!mouse_util.relative_mouse_mode());
+                }
+                sdl2::event::Event::Quit { .. } => {
+                    running.store(false, std::sync::atomic::Ordering::SeqCst);
+                }
+                _ => {
+                    controllers.handle_event(&event);
+                    event_sender.send(GameStateEvent::SDLEvent(event)).unwrap();
+                }
+            }
+        }
+
+        for request in controller_receiver.try_iter() {
+            controllers.handle_request(request);
+        }
+
+        if mouse_util.relative_mouse_mode() {
+            let mouse_state = event_pump.relative_mouse_state();
+            event_sender
+                .send(GameStateEvent::FrameEvent(FrameInput {
+                    keys: event_pump.keyboard_state().pressed_scancodes().collect(),
+                    mouse_buttons: mouse_state.pressed_mouse_buttons().collect(),
+                    mouse_motion: (mouse_state.x(), mouse_state.y()),
+                }))
+                .unwrap();
+        }
+    }
+
+    // Give the update thread a chance to finish writing out whatever it's
+    // recording
+    if let Ok(update_thread) = update_thread {
+        let _ = update_thread.join();
+    }
+}
@@ -0,0 +1,68 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Command line arguments. There aren't enough of them to be worth pulling
+//! in a whole argument parsing library for.
+
+use std::path::PathBuf;
+
+use crate::utils::config::ConfigSources;
+
+#[derive(Clone, Debug, Default)]
+pub struct Args {
+    /// `--headless`: run the simulation without a window or a GPU
+    pub headless: bool,
+    /// `--record <file>`: write all input to a file as it comes in
+    pub record: Option<PathBuf>,
+    /// `--replay <file>`: take input from a recording instead
+    pub replay: Option<PathBuf>,
+    /// `--config <file>`: the game's config file, instead of
+    /// `./data/config.toml`
+    pub config: Option<PathBuf>,
+    /// `--set <section.key=value>`: overrides a config value, on top of all
+    /// the config files. Can be given more than once.
+    pub config_overrides: Vec<String>,
+}
+
+impl Args {
+    pub fn parse(args: impl IntoIterator<Item = String>) -> Self {
+        let mut parsed = Args::default();
+        // Skip the program name
+        let mut args = args.into_iter().skip(1);
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--headless" => parsed.headless = true,
+                "--record" => parsed.record = Some(Self::value_for(&arg, args.next()).into()),
+                "--replay" => parsed.replay = Some(Self::value_for(&arg, args.next()).into()),
+                "--config" => parsed.config = Some(Self::value_for(&arg, args.next()).into()),
+                "--set" => parsed
+                    .config_overrides
+                    .push(Self::value_for(&arg, args.next())),
+                _ => warn!("Ignoring unknown argument {:?}", arg),
+            }
+        }
+        parsed
+    }
+
+    fn value_for(flag: &str, value: Option<String>) -> String {
+        match value {
+            Some(value) => value,
+            None => panic!("{} needs a value to go with it", flag),
+        }
+    }
+
+    /// Where the config should be read from, given these arguments.
+    pub fn config_sources(&self) -> ConfigSources {
+        let mut sources = ConfigSources::default();
+        if let Some(path) = &self.config {
+            sources.game = path.clone();
+        }
+        sources.overrides = self.config_overrides.clone();
+        sources
+    }
+}
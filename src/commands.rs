@@ -0,0 +1,166 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Structural changes to the world (spawning and despawning entities, adding
+//! and removing components, reparenting) can't happen while systems are
+//! running, since they'd need exclusive access to component storage that's
+//! borrowed all over the place. So instead they get written down in a
+//! `CommandBuffer`, which can be written to from any thread, and applied
+//! later by `GameState::update`, when nothing's borrowed.
+//!
+//! Commands are applied in the order they were written, and commands from
+//! systems in the order the systems were registered, so the result doesn't
+//! depend on how the systems happened to get scheduled.
+
+use std::sync::Mutex;
+
+use crate::{
+    entity::{hierarchy_component::HierarchyComponent, Component, Entity},
+    update_thread::GameState,
+};
+
+type Command = Box<dyn FnOnce(&mut GameState) + Send>;
+type SpawnStep = Box<dyn FnOnce(&mut GameState, Entity) + Send>;
+
+#[derive(Default)]
+pub struct CommandBuffer {
+    commands: Mutex<Vec<Command>>,
+}
+
+impl CommandBuffer {
+    /// Queues an arbitrary change to the game state.
+    pub fn add(&self, command: impl FnOnce(&mut GameState) + Send + 'static) {
+        self.commands.lock().unwrap().push(Box::new(command));
+    }
+
+    /// Spawns a new entity with whatever components get added to the
+    /// returned builder. The command is queued once the builder is dropped.
+    pub fn spawn(&self) -> SpawnCommand<'_> {
+        SpawnCommand {
+            buffer: self,
+            steps: vec![],
+        }
+    }
+
+    /// Deletes the entity, running its components' remove hooks.
+    pub fn despawn(&self, entity: Entity) {
+        self.add(move |game_state| {
+            if let Err(e) = game_state.delete_entity(entity) {
+                warn!("Couldn't despawn entity: {}", e);
+            }
+        });
+    }
+
+    /// Deletes the entity and everything below it in the hierarchy.
+    pub fn despawn_recursive(&self, entity: Entity) {
+        self.add(move |game_state| {
+            if let Err(e) = game_state.despawn_recursive(entity) {
+                warn!("Couldn't despawn entity: {}", e);
+            }
+        });
+    }
+
+    /// Adds the component to the entity (or replaces the one it had),
+    /// running its hooks.
+    pub fn insert<T: Component + 'static>(&self, entity: Entity, component: T) {
+        self.add(move |game_state| {
+            if let Err(e) = game_state.add_component(entity, component) {
+                warn!("Couldn't add {}: {}", T::get_id(), e);
+            }
+        });
+    }
+
+    pub fn remove<T: Component + 'static>(&self, entity: Entity) {
+        self.add(move |game_state| {
+            if let Err(e) = game_state.remove_component::<T>(entity) {
+                warn!("Couldn't remove {}: {}", T::get_id(), e);
+            }
+        });
+    }
+
+    /// Moves the entity under a new parent, or makes it a root if there
+    /// isn't one.
+    pub fn set_parent(&self, child: Entity, parent: Option<Entity>) {
+        self.add(move |game_state| match parent {
+            Some(parent) => {
+                if let Err(e) = game_state.entities.set_parent(child, parent) {
+                    warn!("Couldn't reparent entity: {}", e);
+                }
+            }
+            None => game_state.entities.remove_parent(child),
+        });
+    }
+
+    /// Moves everything written to `other` to the end of this one.
+    pub fn append(&self, other: CommandBuffer) {
+        let other = other.commands.into_inner().unwrap();
+        self.commands.lock().unwrap().extend(other);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.commands.lock().unwrap().is_empty()
+    }
+
+    fn take(&self) -> Vec<Command> {
+        std::mem::take(&mut *self.commands.lock().unwrap())
+    }
+}
+
+/// Builds up a new entity to be spawned by a `CommandBuffer`.
+pub struct SpawnCommand<'a> {
+    buffer: &'a CommandBuffer,
+    steps: Vec<SpawnStep>,
+}
+
+impl SpawnCommand<'_> {
+    pub fn with<T: Component + 'static>(mut self, component: T) -> Self {
+        self.steps.push(Box::new(move |game_state, e| {
+            game_state
+                .add_component(e, component)
+                .expect("Freshly spawned entities are alive");
+        }));
+        self
+    }
+
+    pub fn parent(mut self, parent: Entity) -> Self {
+        self.steps.push(Box::new(move |game_state, e| {
+            if let Err(err) = game_state.add_component(e, HierarchyComponent::new(parent)) {
+                warn!("Couldn't parent spawned entity: {}", err);
+            }
+        }));
+        self
+    }
+}
+
+impl Drop for SpawnCommand<'_> {
+    fn drop(&mut self) {
+        let steps = std::mem::take(&mut self.steps);
+        self.buffer.add(move |game_state| {
+            let e = game_state.gen_entity();
+            for step in steps {
+                step(game_state, e);
+            }
+        });
+    }
+}
+
+impl GameState {
+    /// Applies every queued command, including any queued by the commands
+    /// themselves.
+    pub fn apply_commands(&mut self) {
+        loop {
+            let commands = self.commands.take();
+            if commands.is_empty() {
+                break;
+            }
+            for command in commands {
+                command(self);
+            }
+        }
+    }
+}
@@ -0,0 +1,151 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Game controllers, on the event loop's side. SDL only sends events for
+//! controllers that have been opened, and they have to be used from the
+//! thread that owns the event pump, so this keeps track of which ones are
+//! plugged in and does anything to them (rumble) that the update thread asks
+//! for. Their button and axis events go on to the update thread like any
+//! other event, to end up in `InputState`.
+
+use std::collections::HashMap;
+
+use sdl2::{controller::GameController, event::Event, sys, GameControllerSubsystem};
+
+/// Things the update thread wants done to controllers.
+#[derive(Copy, Clone, Debug)]
+pub enum ControllerRequest {
+    /// Rumbles every connected controller. Strengths are from 0.0 to 1.0.
+    Rumble {
+        low_frequency: f32,
+        high_frequency: f32,
+        duration_ms: u32,
+    },
+}
+
+pub struct Controllers {
+    subsystem: GameControllerSubsystem,
+    /// Keyed by joystick instance ID, which is what SDL identifies them by
+    /// in every event after they've been added
+    open: HashMap<u32, GameController>,
+}
+
+impl Controllers {
+    pub fn new(subsystem: GameControllerSubsystem) -> Self {
+        Self {
+            subsystem,
+            open: HashMap::new(),
+        }
+    }
+
+    /// Opens controllers as they're plugged in and drops them when they're
+    /// unplugged. SDL sends an added event for every controller that's
+    /// already plugged in at startup, so this covers those too.
+    pub fn handle_event(&mut self, event: &Event) {
+        match *event {
+            Event::ControllerDeviceAdded { which, .. } => match self.subsystem.open(which) {
+                Ok(controller) => {
+                    info!("Controller connected: {}", controller.name());
+                    self.open.insert(controller.instance_id(), controller);
+                }
+                Err(e) => warn!("Couldn't open controller {}: {}", which, e),
+            },
+            Event::ControllerDeviceRemoved { which, .. } => {
+                if let Some(controller) = self.open.remove(&which) {
+                    info!("Controller disconnected: {}", controller.name());
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn handle_request(&mut self, request: ControllerRequest) {
+        match request {
+            ControllerRequest::Rumble {
+                low_frequency,
+                high_frequency,
+                duration_ms,
+            } => {
+                let strength = |s: f32| (s.clamp(0.0, 1.0) * u16::MAX as f32) as u16;
+                for controller in self.open.values_mut() {
+                    // Plenty of controllers can't rumble, which is fine
+                    if let Err(e) = controller.set_rumble(
+                        strength(low_frequency),
+                        strength(high_frequency),
+                        duration_ms,
+                    ) {
+                        trace!("Couldn't rumble {}: {}", controller.name(), e);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn connected(&self) -> usize {
+        self.open.len()
+    }
+}
+
+/// A controller that doesn't exist, made with SDL's virtual joystick API, so
+/// controller handling can be tried out without having to plug one in. It
+/// shows up like any other controller (including the hotplug events) and
+/// goes away when it's dropped.
+///
+/// Changes only turn into events the next time SDL pumps events, on the
+/// thread with the event pump.
+pub struct VirtualController {
+    device_index: i32,
+    joystick: *mut sys::SDL_Joystick,
+}
+
+impl VirtualController {
+    pub fn attach() -> Result<Self, String> {
+        unsafe {
+            let device_index = sys::SDL_JoystickAttachVirtual(
+                sys::SDL_JoystickType::SDL_JOYSTICK_TYPE_GAMECONTROLLER,
+                sys::SDL_GameControllerAxis::SDL_CONTROLLER_AXIS_MAX as i32,
+                sys::SDL_GameControllerButton::SDL_CONTROLLER_BUTTON_MAX as i32,
+                0,
+            );
+            if device_index < 0 {
+                return Err(sdl2::get_error());
+            }
+            let joystick = sys::SDL_JoystickOpen(device_index);
+            if joystick.is_null() {
+                let error = sdl2::get_error();
+                sys::SDL_JoystickDetachVirtual(device_index);
+                return Err(error);
+            }
+            Ok(Self {
+                device_index,
+                joystick,
+            })
+        }
+    }
+
+    pub fn set_button(&mut self, button: sdl2::controller::Button, pressed: bool) {
+        unsafe {
+            sys::SDL_JoystickSetVirtualButton(self.joystick, button as i32, pressed as u8);
+        }
+    }
+
+    pub fn set_axis(&mut self, axis: sdl2::controller::Axis, value: i16) {
+        unsafe {
+            sys::SDL_JoystickSetVirtualAxis(self.joystick, axis as i32, value);
+        }
+    }
+}
+
+impl Drop for VirtualController {
+    fn drop(&mut self) {
+        unsafe {
+            sys::SDL_JoystickClose(self.joystick);
+            sys::SDL_JoystickDetachVirtual(self.device_index);
+        }
+    }
+}
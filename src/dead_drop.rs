@@ -26,6 +26,17 @@ impl<T> DeadDrop<T> {
         *v = Some(new_value);
     }
 
+    /// Like `send`, but if the last value hasn't been picked up yet, the new
+    /// one gets folded into it with `merge(old, new)` instead of replacing
+    /// it, for when values are changes rather than whole states.
+    pub fn send_or_merge(&self, new_value: T, merge: impl FnOnce(&mut T, T)) {
+        let mut v = self.value.lock().unwrap();
+        match v.as_mut() {
+            Some(old) => merge(old, new_value),
+            None => *v = Some(new_value),
+        }
+    }
+
     pub fn recv(&self) -> Option<T> {
         let mut v = self.value.lock().unwrap();
         std::mem::replace(&mut *v, None)
@@ -0,0 +1,293 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Events sent *to* entities, Caves of Qud style: instead of everything
+//! that cares about, say, an entity taking damage having to go looking for
+//! it, the damage event is sent to the entity and passed down through each
+//! of its components that handles it, in priority order. Each one can change
+//! the event (armor reducing the damage), cancel it (invulnerability), or
+//! send it on to another entity (a shield redirecting it to its bearer).
+//!
+//! Components opt in by implementing `HandleEvent<E>` and being registered
+//! with `EntitySystem::register_handler`. Events are queued by
+//! `EntitySystem::send_event` (from anywhere, including systems) and
+//! processed once per update by `EntitySystem::process_actor_events`, in
+//! parallel across entities, since handlers only ever touch the entity the
+//! event was sent to.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::Mutex,
+};
+
+use rayon::prelude::*;
+
+use super::{change_detection::Mut, query::FetchMut, Component, ComponentID, Entity, EntitySystem};
+
+/// How many times events of one type can be retargeted in a single update
+/// before we decide entities are just passing them back and forth forever.
+const MAX_RETARGET_ROUNDS: usize = 16;
+
+/// An event on its way through the components of the entity it was sent to.
+pub struct ActorEvent<E> {
+    pub payload: E,
+    target: Entity,
+    cancelled: bool,
+    retarget: Option<Entity>,
+}
+
+impl<E> ActorEvent<E> {
+    pub fn target(&self) -> Entity {
+        self.target
+    }
+
+    /// Stops the event here, so no handlers after this one see it.
+    pub fn cancel(&mut self) {
+        self.cancelled = true;
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled
+    }
+
+    /// Stops the event here and sends it on (with its payload as it is now)
+    /// to another entity, where it starts again from the first handler.
+    pub fn retarget(&mut self, entity: Entity) {
+        self.retarget = Some(entity);
+    }
+}
+
+/// A component that wants to see (and maybe change) events of type `E` sent
+/// to its entity.
+pub trait HandleEvent<E>: Component {
+    /// Handlers on the same entity run from lowest to highest priority.
+    fn priority() -> i32
+    where
+        Self: Sized,
+    {
+        0
+    }
+
+    /// The component is handed over as a `Mut`, so it only counts as changed
+    /// if the handler actually changes it.
+    fn handle(this: &mut Mut<'_, Self>, event: &mut ActorEvent<E>)
+    where
+        Self: Sized;
+}
+
+/// A handler's component storage, borrowed for the duration of processing
+/// so each entity's component can be lent out to whichever thread is
+/// handling that entity's events.
+trait LockedHandler<E>: Sync {
+    /// Runs the handler if the entity has the component. Returns whether the
+    /// event should keep going.
+    ///
+    /// # Safety
+    ///
+    /// Nothing else may be handling events for this entity at the same time.
+    unsafe fn handle(&self, event: &mut ActorEvent<E>) -> bool;
+}
+
+struct Locked<'w, C>(FetchMut<'w, C>);
+
+// Each entity's component is only ever touched by the thread handling that
+// entity's events, see `LockedHandler::handle`
+unsafe impl<C: Component> Sync for Locked<'_, C> {}
+
+impl<C: HandleEvent<E> + 'static, E> LockedHandler<E> for Locked<'_, C> {
+    unsafe fn handle(&self, event: &mut ActorEvent<E>) -> bool {
+        if let Some(mut c) = self.0.get_mut(event.target.id) {
+            C::handle(&mut c, event);
+        }
+        !event.cancelled && event.retarget.is_none()
+    }
+}
+
+struct Handler<E> {
+    component: ComponentID,
+    priority: i32,
+    lock: for<'w> fn(&'w EntitySystem) -> Option<Box<dyn LockedHandler<E> + 'w>>,
+}
+
+/// The queue and handlers for one type of event.
+struct Channel<E> {
+    queue: Mutex<Vec<(Entity, E)>>,
+    /// Sorted by priority
+    handlers: Vec<Handler<E>>,
+}
+
+trait AnyChannel: Send + Sync {
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+    fn process(&self, entities: &EntitySystem);
+}
+
+impl<E: Send + 'static> AnyChannel for Channel<E> {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn as_any_mut(&mut self) -> &mut dyn Any {
+        self
+    }
+
+    fn process(&self, entities: &EntitySystem) {
+        let locked: Vec<Box<dyn LockedHandler<E>>> = self
+            .handlers
+            .iter()
+            .filter_map(|h| (h.lock)(entities))
+            .collect();
+
+        for _ in 0..MAX_RETARGET_ROUNDS {
+            let events = std::mem::take(&mut *self.queue.lock().unwrap());
+            if events.is_empty() {
+                return;
+            }
+
+            // Group events by the entity they're for (keeping them in the
+            // order they were sent), so that each entity's events are
+            // handled by one thread, one after another
+            let mut groups: Vec<(Entity, Vec<E>)> = vec![];
+            let mut group_of: HashMap<Entity, usize> = HashMap::new();
+            for (entity, event) in events {
+                if !entities.is_alive(entity) {
+                    continue;
+                }
+                let group = *group_of.entry(entity).or_insert_with(|| {
+                    groups.push((entity, vec![]));
+                    groups.len() - 1
+                });
+                groups[group].1.push(event);
+            }
+
+            let retargeted: Vec<Vec<(Entity, E)>> = groups
+                .into_par_iter()
+                .map(|(target, events)| {
+                    let mut retargeted = vec![];
+                    for payload in events {
+                        let mut event = ActorEvent {
+                            payload,
+                            target,
+                            cancelled: false,
+                            retarget: None,
+                        };
+                        for handler in locked.iter() {
+                            // Entities are alive and only show up in one
+                            // group, so nobody else is touching this one
+                            if !unsafe { handler.handle(&mut event) } {
+                                break;
+                            }
+                        }
+                        if let (false, Some(new_target)) = (event.cancelled, event.retarget) {
+                            retargeted.push((new_target, event.payload));
+                        }
+                    }
+                    retargeted
+                })
+                .collect();
+
+            // Retargeted events get handled next round, in the same order
+            // as the events they came from
+            self.queue
+                .lock()
+                .unwrap()
+                .extend(retargeted.into_iter().flatten());
+        }
+
+        let dropped = std::mem::take(&mut *self.queue.lock().unwrap()).len();
+        if dropped > 0 {
+            error!(
+                "Dropping {} {} events that were still being retargeted after {} rounds",
+                dropped,
+                std::any::type_name::<E>(),
+                MAX_RETARGET_ROUNDS
+            );
+        }
+    }
+}
+
+/// Every type of event entities can be sent, along with its handlers.
+#[derive(Default)]
+pub struct ActorEvents {
+    channels: HashMap<TypeId, Box<dyn AnyChannel>>,
+    /// Event types in the order their first handler was registered, which
+    /// is the order they're processed in
+    order: Vec<TypeId>,
+}
+
+impl EntitySystem {
+    /// Makes `C` one of the handlers for events of type `E` sent to entities
+    /// that have one.
+    pub fn register_handler<C: HandleEvent<E> + 'static, E: Send + 'static>(&mut self) {
+        let type_id = TypeId::of::<E>();
+        if !self.actor_events.channels.contains_key(&type_id) {
+            self.actor_events.order.push(type_id);
+        }
+        let channel = self
+            .actor_events
+            .channels
+            .entry(type_id)
+            .or_insert_with(|| {
+                Box::new(Channel::<E> {
+                    queue: Mutex::new(vec![]),
+                    handlers: vec![],
+                })
+            })
+            .as_any_mut()
+            .downcast_mut::<Channel<E>>()
+            .expect("Channels are keyed by their event type");
+
+        channel.handlers.retain(|h| h.component != C::get_id());
+        let handler = Handler {
+            component: C::get_id(),
+            priority: C::priority(),
+            lock: |entities| {
+                let set = entities.get_component_vec_mut::<C>()?;
+                Some(Box::new(Locked(FetchMut::new(set))))
+            },
+        };
+        // After any handlers with the same priority, so ties go to whichever
+        // was registered first
+        let index = channel
+            .handlers
+            .partition_point(|h| h.priority <= handler.priority);
+        channel.handlers.insert(index, handler);
+    }
+
+    /// Queues an event to be handled by the entity's components the next
+    /// time actor events are processed. Events nothing handles are dropped.
+    pub fn send_event<E: Send + 'static>(&self, entity: Entity, event: E) {
+        match self.actor_events.channels.get(&TypeId::of::<E>()) {
+            Some(channel) => channel
+                .as_any()
+                .downcast_ref::<Channel<E>>()
+                .expect("Channels are keyed by their event type")
+                .queue
+                .lock()
+                .unwrap()
+                .push((entity, event)),
+            None => trace!(
+                "Nothing handles {} events, dropping one",
+                std::any::type_name::<E>()
+            ),
+        }
+    }
+
+    /// Runs every queued actor event through its target's handlers, one event
+    /// type at a time, and entities in parallel. Events sent to dead entities
+    /// are dropped.
+    ///
+    /// This borrows the storage of every handling component, so it can't be
+    /// called from inside a system.
+    pub fn process_actor_events(&self) {
+        for type_id in self.actor_events.order.iter() {
+            self.actor_events.channels[type_id].process(self);
+        }
+    }
+}
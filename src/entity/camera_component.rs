@@ -6,16 +6,31 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
-use crate::entity::{Component, ComponentID};
-use render_gl_derive::ComponentId;
+use serde::{Deserialize, Serialize};
+
+use crate::entity::{reflect::Reflect, Component, ComponentID, Entity};
+use crate::update_thread::GameState;
 
 use crate::utils::Degrees;
 
-#[derive(ComponentId)]
+#[derive(Serialize, Deserialize, Reflect)]
+#[serde(deny_unknown_fields)]
 pub struct CameraComponent {
     pub fov: Degrees,
 }
 
+impl Component for CameraComponent {
+    fn get_id() -> ComponentID {
+        "CameraComponent"
+    }
+    fn remove_hook(&mut self, current_entity: Entity, game_state: &mut GameState) {
+        game_state.deregister_camera(current_entity)
+    }
+    fn replace_hook(&mut self, _old: Self, _current_entity: Entity, _game_state: &mut GameState) {
+        // Stay the current camera (if we were), just with the new settings
+    }
+}
+
 impl CameraComponent {
     pub fn project(&self, width: u32, height: u32) -> glam::Mat4 {
         glam::Mat4::perspective_rh_gl(
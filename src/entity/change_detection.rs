@@ -0,0 +1,162 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use std::{
+    ops::{Deref, DerefMut},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use atomic_refcell::AtomicRefMut;
+
+use super::sparse_set::SparseSet;
+
+/// A point in the world's history. Every change to a component is stamped
+/// with the tick it happened during, and anyone who wants to know what
+/// changed just remembers the last tick they looked at.
+pub type Tick = u64;
+
+/// The world's current tick, shared between the entity system and all of
+/// its component storage, so storage can stamp changes without having to be
+/// told what time it is on every access.
+#[derive(Clone, Debug)]
+pub struct TickCounter(Arc<AtomicU64>);
+
+impl Default for TickCounter {
+    fn default() -> Self {
+        // Start after 0, so that "changed since tick 0" means "ever"
+        Self(Arc::new(AtomicU64::new(1)))
+    }
+}
+
+impl TickCounter {
+    pub fn current(&self) -> Tick {
+        self.0.load(Ordering::Acquire)
+    }
+
+    /// Ends the current tick, returning it. Anything that changes after this
+    /// gets stamped with a later tick, so whoever called this can use the
+    /// returned tick as their "changed since" marker next time around.
+    pub fn increment(&self) -> Tick {
+        self.0.fetch_add(1, Ordering::AcqRel)
+    }
+}
+
+/// When a single component was added to its entity, and when it was last
+/// mutably accessed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct ComponentTicks {
+    pub added: Tick,
+    pub changed: Tick,
+}
+
+impl ComponentTicks {
+    pub fn new(tick: Tick) -> Self {
+        Self {
+            added: tick,
+            changed: tick,
+        }
+    }
+
+    pub fn is_added_since(&self, since: Tick) -> bool {
+        self.added > since
+    }
+
+    /// Adding a component counts as changing it.
+    pub fn is_changed_since(&self, since: Tick) -> bool {
+        self.changed > since
+    }
+}
+
+/// A mutable reference to a component that marks it as changed the moment
+/// it's actually mutated (not just when it's borrowed), so that looking
+/// something up mutably and then deciding not to touch it doesn't make
+/// everybody downstream redo their work.
+pub struct Mut<'a, T> {
+    value: &'a mut T,
+    ticks: &'a mut ComponentTicks,
+    tick: Tick,
+}
+
+impl<'a, T> Mut<'a, T> {
+    pub(crate) fn new(value: &'a mut T, ticks: &'a mut ComponentTicks, tick: Tick) -> Self {
+        Self { value, ticks, tick }
+    }
+
+    pub fn ticks(&self) -> ComponentTicks {
+        *self.ticks
+    }
+
+    /// Marks the component as changed without touching it, for when it
+    /// needs to be reprocessed because of something outside of it changing.
+    pub fn set_changed(&mut self) {
+        self.ticks.changed = self.tick;
+    }
+
+    /// Gets at the component without marking it as changed. Only for
+    /// bookkeeping nobody downstream cares about!
+    pub fn bypass_change_detection(&mut self) -> &mut T {
+        self.value
+    }
+
+    /// Turns this into a plain reference, marking the component as changed.
+    pub fn into_inner(self) -> &'a mut T {
+        self.ticks.changed = self.tick;
+        self.value
+    }
+}
+
+impl<T> Deref for Mut<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.value
+    }
+}
+
+impl<T> DerefMut for Mut<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.ticks.changed = self.tick;
+        self.value
+    }
+}
+
+/// What `EntitySystem::get_component_mut` hands out: like `Mut`, but it also
+/// holds on to the borrow of the component's storage.
+pub struct ComponentMut<'a, T> {
+    set: AtomicRefMut<'a, SparseSet<T>>,
+    index: usize,
+}
+
+impl<'a, T> ComponentMut<'a, T> {
+    pub(crate) fn new(set: AtomicRefMut<'a, SparseSet<T>>, index: usize) -> Self {
+        Self { set, index }
+    }
+
+    pub fn set_changed(&mut self) {
+        self.set.get_mut_by_index(self.index).set_changed();
+    }
+
+    pub fn ticks(&self) -> ComponentTicks {
+        self.set.ticks_by_index(self.index)
+    }
+}
+
+impl<T> Deref for ComponentMut<'_, T> {
+    type Target = T;
+    fn deref(&self) -> &Self::Target {
+        self.set.get_by_index(self.index)
+    }
+}
+
+impl<T> DerefMut for ComponentMut<'_, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.set.get_mut_by_index(self.index).into_inner()
+    }
+}
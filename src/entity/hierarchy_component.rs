@@ -1,7 +1,41 @@
-use super::{Component, Entity};
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
 
+use std::fmt::Display;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    serialization::{EntityMap, MapEntities},
+    update_thread::GameState,
+};
+
+use super::{
+    transform_component::TransformComponent, Component, Entity, EntityError, EntitySystem,
+};
+
+/// Links an entity into the transform/ownership hierarchy. Both ends of a
+/// parent-child link have one of these: the child's `parent` points up, and
+/// the parent's `children` points down, and `depth` is how many parents are
+/// above this entity (roots are 0).
+///
+/// Don't edit `children` or `depth` yourself, use `EntitySystem::set_parent`
+/// and friends (or add a fresh `HierarchyComponent::new(parent)`), which keep
+/// both ends of every link and all the depths consistent.
+///
+/// Only `parent` gets saved, since adding the component back rebuilds the
+/// rest.
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct HierarchyComponent {
-    pub parent: Entity,
+    pub parent: Option<Entity>,
+    #[serde(skip)]
+    pub children: Vec<Entity>,
+    #[serde(skip)]
     pub depth: usize,
 }
 
@@ -9,21 +43,237 @@ impl Component for HierarchyComponent {
     fn get_id() -> super::ComponentID {
         "HierarchyComponent"
     }
-    fn add_hook(
-        &mut self,
-        current_entity: Entity,
-        game_state: &mut crate::update_thread::GameState,
-    ) {
-        let parent_depth = game_state
+    fn add_hook(&mut self, current_entity: Entity, game_state: &mut GameState) {
+        // Let the entity system do the actual linking, since it has to touch
+        // the parent and all of our descendants too, and then just copy what
+        // it came up with so that adding ourselves doesn't clobber it.
+        let result = match self.parent {
+            Some(parent) => game_state.entities.set_parent(current_entity, parent),
+            None => {
+                game_state.entities.remove_parent(current_entity);
+                Ok(())
+            }
+        };
+        if let Err(e) = result {
+            error!("Couldn't link {:?} into hierarchy: {}", current_entity, e);
+            self.parent = None;
+        }
+        if let Ok(linked) = game_state
+            .entities
+            .get_component::<HierarchyComponent>(current_entity)
+        {
+            *self = linked.clone();
+        }
+    }
+
+    fn remove_hook(&mut self, current_entity: Entity, game_state: &mut GameState) {
+        game_state
+            .entities
+            .unlink_from_hierarchy(current_entity, self);
+    }
+
+    fn replace_hook(&mut self, old: Self, current_entity: Entity, game_state: &mut GameState) {
+        // Replacing the component only changes who the parent is, so put the
+        // old links back (so our children stay attached) and relink the same
+        // way a fresh add would.
+        game_state
             .entities
-            .get_component::<HierarchyComponent>(self.parent)
-            .map_or(0, |p| p.depth);
-        self.depth = parent_depth + 1;
+            .add_component(current_entity, old)
+            .expect("Hooks are only called for live entities");
+        self.add_hook(current_entity, game_state);
     }
 }
 
 impl HierarchyComponent {
     pub fn new(parent: Entity) -> Self {
-        Self { parent, depth: 0 }
+        Self {
+            parent: Some(parent),
+            children: vec![],
+            depth: 0,
+        }
+    }
+
+    pub fn root() -> Self {
+        Self {
+            parent: None,
+            children: vec![],
+            depth: 0,
+        }
+    }
+}
+
+impl MapEntities for HierarchyComponent {
+    fn map_entities(&mut self, map: &EntityMap) {
+        // A parent that wasn't saved along with us just makes us a root
+        self.parent = self.parent.and_then(|p| map.get(&p.id).copied());
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HierarchyError {
+    /// One of the entities has been deleted (or its ID recycled)
+    DeadEntity(Entity),
+    /// The parent is the child itself, or one of the child's descendants
+    Cycle { child: Entity, parent: Entity },
+}
+
+impl Display for HierarchyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HierarchyError::DeadEntity(e) => write!(f, "entity {:?} is not alive", e),
+            HierarchyError::Cycle { child, parent } => write!(
+                f,
+                "making {:?} the parent of {:?} would create a cycle",
+                parent, child
+            ),
+        }
+    }
+}
+
+impl EntitySystem {
+    pub fn parent_of(&self, entity: Entity) -> Option<Entity> {
+        self.get_component::<HierarchyComponent>(entity)
+            .ok()
+            .and_then(|hc| hc.parent)
+    }
+
+    pub fn children_of(&self, entity: Entity) -> Vec<Entity> {
+        self.get_component::<HierarchyComponent>(entity)
+            .map_or(vec![], |hc| hc.children.clone())
+    }
+
+    /// Every entity below this one in the hierarchy, breadth first (so
+    /// parents always come before their children).
+    pub fn descendants_of(&self, entity: Entity) -> Vec<Entity> {
+        let Some(hcs) = self.get_component_vec::<HierarchyComponent>() else {
+            return vec![];
+        };
+        let mut descendants = vec![];
+        let mut frontier = std::collections::VecDeque::from([entity]);
+        while let Some(e) = frontier.pop_front() {
+            if let Some(hc) = hcs.get(e.id) {
+                descendants.extend(hc.children.iter().copied());
+                frontier.extend(hc.children.iter().copied());
+            }
+        }
+        descendants
+    }
+
+    /// Makes `parent` the parent of `child`, detaching it from its old parent
+    /// if it had one, and updating the depth of `child` and everything below
+    /// it. Refuses to create cycles.
+    pub fn set_parent(&mut self, child: Entity, parent: Entity) -> Result<(), HierarchyError> {
+        for e in [child, parent] {
+            if !self.is_alive(e) {
+                return Err(HierarchyError::DeadEntity(e));
+            }
+        }
+
+        // Walk up from the new parent: if we run into the child, the child
+        // would end up being its own ancestor.
+        let mut ancestor = Some(parent);
+        while let Some(a) = ancestor {
+            if a == child {
+                return Err(HierarchyError::Cycle { child, parent });
+            }
+            ancestor = self.parent_of(a);
+        }
+
+        self.detach_from_parent(child);
+
+        for e in [parent, child] {
+            if self.get_component::<HierarchyComponent>(e).is_err() {
+                self.add_component(e, HierarchyComponent::root())
+                    .expect("Checked that the entity is alive above");
+            }
+        }
+
+        let parent_depth = {
+            let mut phc = self
+                .get_component_mut::<HierarchyComponent>(parent)
+                .unwrap();
+            phc.children.push(child);
+            phc.depth
+        };
+        self.get_component_mut::<HierarchyComponent>(child)
+            .unwrap()
+            .parent = Some(parent);
+        self.update_depths(child, parent_depth + 1);
+        self.mark_transform_dirty(child);
+        Ok(())
+    }
+
+    /// Turns `child` into a root, if it had a parent.
+    pub fn remove_parent(&mut self, child: Entity) {
+        if self.get_component::<HierarchyComponent>(child).is_ok() {
+            self.detach_from_parent(child);
+            self.update_depths(child, 0);
+            self.mark_transform_dirty(child);
+        }
+    }
+
+    /// Deletes the entity and everything below it in the hierarchy. This
+    /// doesn't run any component hooks, see `GameState::despawn_recursive`.
+    pub fn despawn_recursive(&mut self, entity: Entity) -> Result<(), EntityError> {
+        self.check_alive(entity)?;
+        // Children first, so nobody gets orphaned along the way
+        for e in self.descendants_of(entity).into_iter().rev() {
+            self.delete_entity(e)?;
+        }
+        self.delete_entity(entity)
+    }
+
+    /// Unlinks an entity whose `HierarchyComponent` has just been taken off
+    /// from both ends of the hierarchy: it's removed from its parent's
+    /// children, and its own children become roots, so nothing is left
+    /// pointing at it (or at an ID that's about to be recycled).
+    pub(super) fn unlink_from_hierarchy(&mut self, entity: Entity, hc: &HierarchyComponent) {
+        if let Some(parent) = hc.parent {
+            if let Ok(mut phc) = self.get_component_mut::<HierarchyComponent>(parent) {
+                phc.children.retain(|c| *c != entity);
+            }
+        }
+        for &child in hc.children.iter() {
+            if let Ok(mut chc) = self.get_component_mut::<HierarchyComponent>(child) {
+                chc.parent = None;
+            }
+            self.update_depths(child, 0);
+            self.mark_transform_dirty(child);
+        }
+    }
+
+    /// The entity's world transform depends on its place in the hierarchy,
+    /// so it needs to be recomputed whenever that changes.
+    fn mark_transform_dirty(&self, entity: Entity) {
+        if let Ok(mut tc) = self.get_component_mut::<TransformComponent>(entity) {
+            tc.set_changed();
+        }
+    }
+
+    fn detach_from_parent(&mut self, child: Entity) {
+        let old_parent = self
+            .get_component_mut::<HierarchyComponent>(child)
+            .ok()
+            .and_then(|mut hc| hc.parent.take());
+        if let Some(old_parent) = old_parent {
+            if let Ok(mut phc) = self.get_component_mut::<HierarchyComponent>(old_parent) {
+                phc.children.retain(|c| *c != child);
+            }
+        }
+    }
+
+    /// Sets the depth of `root` and pushes the change down to all of its
+    /// descendants.
+    fn update_depths(&mut self, root: Entity, depth: usize) {
+        let Some(mut hcs) = self.get_component_vec_mut::<HierarchyComponent>() else {
+            return;
+        };
+        let mut frontier = vec![(root, depth)];
+        while let Some((e, depth)) = frontier.pop() {
+            if let Some(mut hc) = hcs.get_mut(e.id) {
+                hc.depth = depth;
+                frontier.extend(hc.children.iter().map(|c| (*c, depth + 1)));
+            }
+        }
     }
 }
@@ -6,18 +6,20 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
-use crate::{render_gl::data::Cvec3, render_thread::ShaderLight};
+use serde::{Deserialize, Serialize};
 
-use super::*;
+use super::{reflect::Reflect, *};
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize, Reflect)]
+#[serde(deny_unknown_fields)]
 pub struct Attenuation {
     pub constant: f32,
     pub linear: f32,
     pub quadratic: f32,
 }
 
-#[derive(Clone)]
+#[derive(Clone, Serialize, Deserialize, Reflect)]
+#[serde(tag = "kind", deny_unknown_fields)]
 pub enum LightComponent {
     Ambient {
         ambient: glam::Vec3,
@@ -47,4 +49,11 @@ impl Component for LightComponent {
     fn add_hook(&mut self, current_entity: Entity, game_state: &mut GameState) {
         game_state.register_light(current_entity)
     }
+    fn remove_hook(&mut self, current_entity: Entity, game_state: &mut GameState) {
+        game_state.deregister_light(current_entity)
+    }
+    fn replace_hook(&mut self, _old: Self, _current_entity: Entity, _game_state: &mut GameState) {
+        // Already registered, and the light's parameters are read straight
+        // off the component whenever they're sent to the renderer
+    }
 }
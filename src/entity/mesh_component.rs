@@ -6,29 +6,23 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
-use crate::rayon::iter::ParallelIterator;
 use crate::CONFIG;
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::ffi::CString;
-use std::rc::Rc;
-use std::thread::{self, Thread};
 
 use bytes::BytesMut;
 use gl::Gl;
 use gltf::image::Format;
-use gltf::Gltf;
-use rayon::prelude::ParallelBridge;
+use serde::{Deserialize, Serialize};
 
-use crate::entity::{Component, ComponentID};
-use crate::render_gl::data::{
-    self, Cvec2, Cvec3, Cvec4, InstanceTransformVertex, VertexNormTex, VertexNormTexTan,
-};
+use crate::entity::{reflect::Reflect, Component, ComponentID};
+use crate::render_gl::data::{Cvec2, Cvec3, Cvec4, InstanceTransformVertex, VertexNormTexTan};
 use crate::render_gl::objects::{BufferObject, VertexArray};
-use crate::render_gl::textures::{AbstractTexture, Texture, RGB8};
+use crate::render_gl::textures::{AbstractTexture, Texture};
 use crate::render_gl::{
     objects::{self, Buffer},
     shaders::Program,
-    textures::{self, TextureParameters},
+    textures::TextureParameters,
 };
 use crate::utils::zip;
 
@@ -38,14 +32,13 @@ type TextureID = usize;
 
 #[derive(Debug, Clone, Copy)]
 enum FactorOrTexture {
-    Factor(f32),
     Vec3(Cvec3),
     Vec4(Cvec4),
     Texture(TextureID),
 }
 
 pub struct Material {
-    name: String,
+    pub name: String,
 
     diffuse: FactorOrTexture,
     specular: FactorOrTexture,
@@ -119,9 +112,6 @@ impl Material {
                     false,
                 );
             }
-            _ => {
-                unreachable!()
-            }
         }
     }
 }
@@ -229,9 +219,6 @@ impl Model {
     }
 
     fn process_node(n: gltf::Mesh, buffers: &Vec<gltf::buffer::Data>) -> Option<MeshNode> {
-        let time = std::time::Instant::now();
-
-        let prim_start = time.elapsed().as_millis();
         let primitives = n
             .primitives()
             .map(|prim| {
@@ -277,7 +264,6 @@ impl Model {
                 }
             })
             .collect();
-        let prim_end = time.elapsed().as_millis();
 
         Some(MeshNode {
             name: n.name().unwrap_or("UnknownMesh").to_string(),
@@ -365,35 +351,28 @@ impl Model {
                 1.0,
             ),
             R16G16B16 => (
-                std::mem::transmute::<[u8; 2], u16>(components[0..2].try_into().unwrap()).to_le()
-                    as f32,
-                std::mem::transmute::<[u8; 2], u16>(components[2..4].try_into().unwrap()).to_le()
-                    as f32,
-                std::mem::transmute::<[u8; 2], u16>(components[4..6].try_into().unwrap()).to_le()
-                    as f32,
+                u16::from_ne_bytes(components[0..2].try_into().unwrap()).to_le() as f32,
+                u16::from_ne_bytes(components[2..4].try_into().unwrap()).to_le() as f32,
+                u16::from_ne_bytes(components[4..6].try_into().unwrap()).to_le() as f32,
                 1.0,
             ),
             R16G16B16A16 => (
-                std::mem::transmute::<[u8; 2], u16>(components[0..2].try_into().unwrap()).to_le()
-                    as f32,
-                std::mem::transmute::<[u8; 2], u16>(components[2..4].try_into().unwrap()).to_le()
-                    as f32,
-                std::mem::transmute::<[u8; 2], u16>(components[4..6].try_into().unwrap()).to_le()
-                    as f32,
-                std::mem::transmute::<[u8; 2], u16>(components[6..8].try_into().unwrap()).to_le()
-                    as f32,
+                u16::from_ne_bytes(components[0..2].try_into().unwrap()).to_le() as f32,
+                u16::from_ne_bytes(components[2..4].try_into().unwrap()).to_le() as f32,
+                u16::from_ne_bytes(components[4..6].try_into().unwrap()).to_le() as f32,
+                u16::from_ne_bytes(components[6..8].try_into().unwrap()).to_le() as f32,
             ),
             R32G32B32FLOAT => (
-                std::mem::transmute::<[u8; 4], f32>(components[0..4].try_into().unwrap()),
-                std::mem::transmute::<[u8; 4], f32>(components[4..8].try_into().unwrap()),
-                std::mem::transmute::<[u8; 4], f32>(components[8..12].try_into().unwrap()),
+                f32::from_ne_bytes(components[0..4].try_into().unwrap()),
+                f32::from_ne_bytes(components[4..8].try_into().unwrap()),
+                f32::from_ne_bytes(components[8..12].try_into().unwrap()),
                 1.0,
             ),
             R32G32B32A32FLOAT => (
-                std::mem::transmute::<[u8; 4], f32>(components[0..4].try_into().unwrap()),
-                std::mem::transmute::<[u8; 4], f32>(components[4..8].try_into().unwrap()),
-                std::mem::transmute::<[u8; 4], f32>(components[8..12].try_into().unwrap()),
-                std::mem::transmute::<[u8; 4], f32>(components[12..16].try_into().unwrap()),
+                f32::from_ne_bytes(components[0..4].try_into().unwrap()),
+                f32::from_ne_bytes(components[4..8].try_into().unwrap()),
+                f32::from_ne_bytes(components[8..12].try_into().unwrap()),
+                f32::from_ne_bytes(components[12..16].try_into().unwrap()),
             ),
             _ => panic!(
                 "Metallic roughness texture should have green and blue components in glTF 2.0!"
@@ -425,7 +404,6 @@ impl Model {
                 let current_pixel = current_pixel as usize;
                 let current_byte = current_pixel * bytes_per_pixel;
                 let components = &image.pixels[current_byte..(current_byte + bytes_per_pixel)];
-                use Format::*;
 
                 unsafe {
                     let (_, roughness, metalness, _) =
@@ -587,7 +565,7 @@ impl Model {
             gl,
             gl::ARRAY_BUFFER,
             gl::STREAM_DRAW,
-            (CONFIG.performance.max_batch_size * 3) as usize,
+            (CONFIG.load().performance.max_batch_size * 3) as usize,
         ));
         self.textures = Some(
             self.textures_raw
@@ -673,8 +651,10 @@ impl Mesh {
     }
 }
 
+#[derive(Serialize, Deserialize, Reflect)]
 pub struct ModelComponent {
     pub path: String,
+    #[serde(default)]
     pub shader_program: usize,
 }
 
@@ -689,4 +669,24 @@ impl Component for ModelComponent {
     ) {
         game_state.load_model_for(current_entity, self)
     }
+    fn remove_hook(
+        &mut self,
+        current_entity: Entity,
+        game_state: &mut crate::update_thread::GameState,
+    ) {
+        game_state.unload_model_for(current_entity, self)
+    }
+    fn replace_hook(
+        &mut self,
+        old: Self,
+        current_entity: Entity,
+        game_state: &mut crate::update_thread::GameState,
+    ) {
+        // Don't make the resource manager drop and reload a model we're
+        // still using
+        if old.path != self.path {
+            game_state.unload_model_for(current_entity, &old);
+            game_state.load_model_for(current_entity, self);
+        }
+    }
 }
@@ -6,21 +6,30 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
-use std::{
-    any::Any,
-    cell::{Ref, RefCell, RefMut},
-    collections::HashMap,
-    sync::atomic::AtomicBool,
-};
+use std::{any::Any, collections::HashMap};
+
+use atomic_refcell::{AtomicRef, AtomicRefCell, AtomicRefMut};
+use serde::{Deserialize, Serialize};
 
-use crate::{systems, update_thread::GameState};
+use crate::update_thread::GameState;
 
-use self::mesh_component::ModelComponent;
+use self::{
+    actor_events::ActorEvents,
+    change_detection::{ComponentMut, ComponentTicks, Tick, TickCounter},
+    hierarchy_component::HierarchyComponent,
+    query::{Query, QueryParam},
+    sparse_set::SparseSet,
+};
 
+pub mod actor_events;
 pub mod camera_component;
+pub mod change_detection;
 pub mod hierarchy_component;
 pub mod light_component;
 pub mod mesh_component;
+pub mod query;
+pub mod reflect;
+pub mod sparse_set;
 pub mod terrain_component;
 pub mod transform_component;
 pub mod ui_component;
@@ -28,32 +37,94 @@ pub mod ui_component;
 pub type ComponentID = &'static str;
 pub type EntityID = usize;
 
-pub trait Component {
+/// Components can hook into their own lifecycle to keep the rest of the game
+/// state in sync with them (registering lights, loading models, and so on).
+/// Hooks only run for changes made through `GameState` (`add_component`,
+/// `remove_component`, `delete_entity`, `despawn_recursive`), since they need
+/// the whole game state to work with; the raw methods on `EntitySystem`
+/// don't run them.
+pub trait Component: Send + Sync {
     fn get_id() -> ComponentID;
-    fn add_hook(&mut self, current_entity: Entity, game_state: &mut GameState) {}
+
+    /// Runs just before the component is added to an entity that didn't
+    /// already have one.
+    fn add_hook(&mut self, _current_entity: Entity, _game_state: &mut GameState) {}
+
+    /// Runs just after the component has been taken off an entity, either
+    /// because it was removed or because the entity was deleted.
+    fn remove_hook(&mut self, _current_entity: Entity, _game_state: &mut GameState) {}
+
+    /// Runs instead of `add_hook` when the entity already had one of these,
+    /// with the old component (already taken out of storage). By default
+    /// that's treated as removing the old one and then adding the new one.
+    fn replace_hook(&mut self, mut old: Self, current_entity: Entity, game_state: &mut GameState)
+    where
+        Self: Sized,
+    {
+        old.remove_hook(current_entity, game_state);
+        self.add_hook(current_entity, game_state);
+    }
 }
 
-#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq)]
+#[derive(Copy, Clone, Debug, Hash, PartialEq, Eq, Serialize, Deserialize)]
 pub struct Entity {
     pub id: EntityID,
     pub generation: usize,
 }
 
-pub trait ComponentVec {
-    fn add_new_entity_col(&mut self);
+/// Why an entity or one of its components couldn't be accessed.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum EntityError {
+    /// The entity has been deleted, or the handle is from before its ID was
+    /// recycled, or the ID was never handed out at all.
+    Dead(Entity),
+    /// The entity is alive, but doesn't have this component.
+    MissingComponent(Entity, ComponentID),
+}
+
+impl std::fmt::Display for EntityError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EntityError::Dead(e) => write!(
+                f,
+                "entity {} (generation {}) is not alive",
+                e.id, e.generation
+            ),
+            EntityError::MissingComponent(e, cid) => {
+                write!(f, "entity {} has no {}", e.id, cid)
+            }
+        }
+    }
+}
+
+/// A component that's been taken out of storage, waiting to have its
+/// `remove_hook` run once the game state is free to be borrowed again.
+pub type PendingRemoveHook = Box<dyn FnOnce(Entity, &mut GameState)>;
+
+pub trait ComponentVec: Send + Sync {
     fn remove_entity_col(&mut self, eid: EntityID);
+    fn take_entity_col(&mut self, eid: EntityID) -> Option<PendingRemoveHook>;
+    fn clear_removed_through(&mut self, tick: Tick);
 
     fn as_any(&self) -> &dyn Any;
     fn as_any_mut(&mut self) -> &mut dyn Any;
 }
 
-type ComponentVecConcrete<T> = RefCell<Vec<Option<T>>>;
+type ComponentVecConcrete<T> = AtomicRefCell<SparseSet<T>>;
 impl<T: Component + 'static> ComponentVec for ComponentVecConcrete<T> {
-    fn add_new_entity_col(&mut self) {
-        self.get_mut().push(None);
-    }
     fn remove_entity_col(&mut self, eid: EntityID) {
-        self.get_mut()[eid] = None;
+        self.get_mut().remove(eid);
+    }
+
+    fn take_entity_col(&mut self, eid: EntityID) -> Option<PendingRemoveHook> {
+        self.get_mut().remove(eid).map(|mut c| {
+            Box::new(move |e: Entity, game_state: &mut GameState| c.remove_hook(e, game_state))
+                as PendingRemoveHook
+        })
+    }
+
+    fn clear_removed_through(&mut self, tick: Tick) {
+        self.get_mut().clear_removed_through(tick);
     }
 
     fn as_any(&self) -> &dyn Any {
@@ -65,160 +136,228 @@ impl<T: Component + 'static> ComponentVec for ComponentVecConcrete<T> {
 }
 
 pub struct EntitySystem {
+    /// Number of entity IDs ever handed out (live or free)
     pub entity_count: EntityID,
-    pub current_generation: usize,
-    pub entity_generations: HashMap<EntityID, usize>,
+    /// The current generation of each entity ID. Bumped whenever the entity
+    /// using that ID is deleted, so old handles stop matching immediately.
+    pub entity_generations: Vec<usize>,
+    /// Whether each entity ID is currently in use
+    alive: Vec<bool>,
     pub free_entities: Vec<EntityID>,
     pub components: HashMap<ComponentID, Box<dyn ComponentVec>>,
-    dirty_flag: AtomicBool,
+    /// The world's change tick, shared with all of the component storage.
+    /// See `change_detection`.
+    clock: TickCounter,
+    /// Handlers and queued events for `send_event`
+    actor_events: ActorEvents,
+}
+
+impl Default for EntitySystem {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl EntitySystem {
     pub fn new() -> Self {
         Self {
-            current_generation: 0,
             entity_count: 0,
             components: HashMap::new(),
-            entity_generations: HashMap::new(),
+            entity_generations: vec![],
+            alive: vec![],
             free_entities: vec![],
-            dirty_flag: AtomicBool::new(true),
+            clock: TickCounter::default(),
+            actor_events: ActorEvents::default(),
         }
     }
 
-    pub fn dirty(&self) -> bool {
-        self.dirty_flag.load(std::sync::atomic::Ordering::Relaxed)
+    /// The tick changes are currently being stamped with.
+    pub fn change_tick(&self) -> Tick {
+        self.clock.current()
+    }
+
+    /// Ends the current tick and returns it: after this, "changed since" the
+    /// returned tick means changed after this call.
+    pub fn increment_change_tick(&self) -> Tick {
+        self.clock.increment()
     }
 
-    pub fn clear_dirty(&self) {
-        self.dirty_flag
-            .store(false, std::sync::atomic::Ordering::Relaxed);
+    /// Whether this handle still refers to a live entity (as opposed to a
+    /// deleted one, or an older user of a recycled ID).
+    pub fn is_alive(&self, entity: Entity) -> bool {
+        self.alive.get(entity.id).copied().unwrap_or(false)
+            && self.entity_generations[entity.id] == entity.generation
+    }
+
+    pub(crate) fn check_alive(&self, entity: Entity) -> Result<(), EntityError> {
+        if self.is_alive(entity) {
+            Ok(())
+        } else {
+            Err(EntityError::Dead(entity))
+        }
     }
 
     pub fn gen_entity(&mut self) -> Entity {
-        let e = if let Some(eid) = self.free_entities.pop() {
+        if let Some(eid) = self.free_entities.pop() {
+            self.alive[eid] = true;
             Entity {
                 id: eid,
-                generation: self.current_generation,
+                generation: self.entity_generations[eid],
             }
         } else {
-            // New entity handle
+            // New entity handle. Component storage is sparse, so nothing
+            // else needs to know about this until it gets components.
             self.entity_count += 1;
-
-            // Add all the entity's components to the registry
-            for (_cid, component_list) in self.components.iter_mut() {
-                component_list.add_new_entity_col();
-            }
+            self.entity_generations.push(0);
+            self.alive.push(true);
 
             Entity {
                 id: self.entity_count - 1,
-                generation: self.current_generation,
+                generation: 0,
             }
-        };
-        self.entity_generations.insert(e.id, e.generation);
-        self.dirty_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-        e
+        }
     }
 
-    pub fn delete_entity(&mut self, entity: Entity) {
-        if entity.generation != self.entity_generations[&entity.id] {
-            println!("WARNING: Tried to use recycled entity ID to refer to old entity");
-            return;
+    pub fn delete_entity(&mut self, entity: Entity) -> Result<(), EntityError> {
+        self.check_alive(entity)?;
+        if let Ok(Some(hc)) = self.remove_component::<HierarchyComponent>(entity) {
+            self.unlink_from_hierarchy(entity, &hc);
         }
-        self.current_generation += 1;
         for (_cid, component_list) in self.components.iter_mut() {
             component_list.remove_entity_col(entity.id);
         }
+        self.entity_generations[entity.id] += 1;
+        self.alive[entity.id] = false;
         self.free_entities.push(entity.id);
-        self.dirty_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
     }
 
-    pub fn add_component<T: Component + 'static>(&mut self, entity: Entity, c: T) {
-        if entity.generation != self.entity_generations[&entity.id] {
-            println!("WARNING: Tried to use recycled entity ID to refer to old entity");
-            return;
-        }
+    pub fn add_component<T: Component + 'static>(
+        &mut self,
+        entity: Entity,
+        c: T,
+    ) -> Result<(), EntityError> {
+        self.check_alive(entity)?;
 
         if let Some(component_vec) = self
             .components
             .get_mut(&T::get_id())
             .and_then(|x| x.as_any_mut().downcast_mut::<ComponentVecConcrete<T>>())
         {
-            component_vec.get_mut()[entity.id] = Some(c);
+            component_vec.get_mut().insert(entity.id, c);
         } else {
-            let mut h: Vec<Option<T>> = Vec::new();
-            h.resize_with(self.entity_count, || None);
-
-            h[entity.id] = Some(c);
+            let mut h = SparseSet::new(self.clock.clone());
+            h.insert(entity.id, c);
             self.components
-                .insert(T::get_id(), Box::new(RefCell::new(h)));
+                .insert(T::get_id(), Box::new(AtomicRefCell::new(h)));
         }
-        self.dirty_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
+        Ok(())
     }
 
-    // Returns true if an asset unload cycle is needed after deleting this component
-    pub fn remove_component<T: Component + 'static>(&mut self, entity: Entity) -> bool {
-        if entity.generation != self.entity_generations[&entity.id] {
-            println!("WARNING: Tried to use recycled entity ID to refer to old entity");
-            return false;
-        }
+    /// Takes the component off the entity and returns it, if it had one.
+    pub fn remove_component<T: Component + 'static>(
+        &mut self,
+        entity: Entity,
+    ) -> Result<Option<T>, EntityError> {
+        self.check_alive(entity)?;
 
-        if let Some(component_vec) = self.components.get_mut(&T::get_id()) {
-            component_vec.remove_entity_col(entity.id);
-        }
-        self.dirty_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-        T::get_id() == ModelComponent::get_id()
+        let removed = self
+            .components
+            .get_mut(&T::get_id())
+            .and_then(|x| x.as_any_mut().downcast_mut::<ComponentVecConcrete<T>>())
+            .and_then(|component_vec| component_vec.get_mut().remove(entity.id));
+        Ok(removed)
     }
 
-    pub fn get_component<T: Component + 'static>(&self, entity: Entity) -> Option<Ref<T>> {
-        if entity.generation != self.entity_generations[&entity.id] {
-            println!("WARNING: Tried to use recycled entity ID to refer to old entity in a situation where a result is required");
-            return None;
-        }
+    /// Takes every component off the entity (but leaves the entity alive),
+    /// handing back their remove hooks to be run.
+    pub fn take_all_components(
+        &mut self,
+        entity: Entity,
+    ) -> Result<Vec<PendingRemoveHook>, EntityError> {
+        self.check_alive(entity)?;
 
-        self.get_component_vec::<T>().and_then(|v| {
-            let val = Ref::map(v, |vec: &Vec<Option<T>>| &vec[entity.id]);
-            if val.is_some() {
-                Some(Ref::map(val, |x| x.as_ref().unwrap()))
-            } else {
-                None
-            }
-        })
+        let hooks = self
+            .components
+            .values_mut()
+            .filter_map(|component_list| component_list.take_entity_col(entity.id))
+            .collect();
+        Ok(hooks)
     }
 
-    pub fn get_component_mut<T: Component + 'static>(&self, entity: Entity) -> Option<RefMut<T>> {
-        if entity.generation != self.entity_generations[&entity.id] {
-            println!("WARNING: Tried to use recycled entity ID to refer to old entity in a situation where a result is required");
-            return None;
-        }
+    pub fn get_component<T: Component + 'static>(
+        &self,
+        entity: Entity,
+    ) -> Result<AtomicRef<'_, T>, EntityError> {
+        self.check_alive(entity)?;
 
-        self.dirty_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
-        self.get_component_vec_mut::<T>().and_then(|v| {
-            let val = RefMut::map(v, |vec: &mut Vec<Option<T>>| &mut vec[entity.id]);
-            if val.is_some() {
-                Some(RefMut::map(val, |x| x.as_mut().unwrap()))
-            } else {
-                None
-            }
-        })
+        self.get_component_vec::<T>()
+            .and_then(|v| AtomicRef::filter_map(v, |set: &SparseSet<T>| set.get(entity.id)))
+            .ok_or(EntityError::MissingComponent(entity, T::get_id()))
     }
 
+    /// The component is only marked as changed if it's actually mutated
+    /// through the returned reference.
+    pub fn get_component_mut<T: Component + 'static>(
+        &self,
+        entity: Entity,
+    ) -> Result<ComponentMut<'_, T>, EntityError> {
+        self.check_alive(entity)?;
+
+        self.get_component_vec_mut::<T>()
+            .and_then(|set| {
+                let index = set.dense_index(entity.id)?;
+                Some(ComponentMut::new(set, index))
+            })
+            .ok_or(EntityError::MissingComponent(entity, T::get_id()))
+    }
+
+    /// When the entity's component was added and last changed.
+    pub fn component_ticks<T: Component + 'static>(
+        &self,
+        entity: Entity,
+    ) -> Result<ComponentTicks, EntityError> {
+        self.check_alive(entity)?;
+
+        self.get_component_vec::<T>()
+            .and_then(|set| set.ticks(entity.id))
+            .ok_or(EntityError::MissingComponent(entity, T::get_id()))
+    }
+
+    /// Whether any `T` has been added or changed after `since`.
+    pub fn any_changed_since<T: Component + 'static>(&self, since: Tick) -> bool {
+        self.get_component_vec::<T>()
+            .is_some_and(|set| set.iter_changed_since(since).next().is_some())
+    }
+
+    /// Entities that lost their `T` (including by being deleted) after
+    /// `since`. They might not be alive anymore, hence just the IDs.
+    pub fn removed_since<T: Component + 'static>(&self, since: Tick) -> Vec<EntityID> {
+        self.get_component_vec::<T>()
+            .map_or(vec![], |set| set.removed_since(since).collect())
+    }
+
+    /// Forgets about component removals from `tick` or earlier. Call this
+    /// once everyone who might ask about removals has caught up past it.
+    pub fn clear_removed_through(&mut self, tick: Tick) {
+        for component_list in self.components.values_mut() {
+            component_list.clear_removed_through(tick);
+        }
+    }
+
+    /// The live entity currently using this ID, if there is one.
     pub fn get_current_entity_from_id(&self, eid: EntityID) -> Option<Entity> {
-        if !self.free_entities.contains(&eid) {
-            self.entity_generations.get(&eid).map(|gen| Entity {
+        if self.alive.get(eid).copied().unwrap_or(false) {
+            Some(Entity {
                 id: eid,
-                generation: *gen,
+                generation: self.entity_generations[eid],
             })
         } else {
             None
         }
     }
 
-    pub fn get_component_vec<T: Component + 'static>(&self) -> Option<Ref<Vec<Option<T>>>> {
+    pub fn get_component_vec<T: Component + 'static>(&self) -> Option<AtomicRef<'_, SparseSet<T>>> {
         self.components.get(T::get_id()).map(|x| {
             x.as_any()
                 .downcast_ref::<ComponentVecConcrete<T>>()
@@ -227,9 +366,9 @@ impl EntitySystem {
         })
     }
 
-    pub fn get_component_vec_mut<T: Component + 'static>(&self) -> Option<RefMut<Vec<Option<T>>>> {
-        self.dirty_flag
-            .store(true, std::sync::atomic::Ordering::Relaxed);
+    pub fn get_component_vec_mut<T: Component + 'static>(
+        &self,
+    ) -> Option<AtomicRefMut<'_, SparseSet<T>>> {
         self.components.get(T::get_id()).map(|x| {
             x.as_any()
                 .downcast_ref::<ComponentVecConcrete<T>>()
@@ -238,36 +377,87 @@ impl EntitySystem {
         })
     }
 
-    pub fn get_with_component<'a, T: Component + 'static>(
-        &'a self,
-        ts: &'a Ref<Vec<Option<T>>>,
-    ) -> impl Iterator<Item = (EntityID, &T)> {
-        ts.iter()
-            .enumerate()
-            .filter_map(|(i, mc)| Some((i, mc.as_ref()?)))
-    }
-
-    // Lifetimes mean that self has to live at least as long as ts and us, I
-    // think? Which is fine, since ts and us are *drawn from self*
-    pub fn get_with_components<'a, T: Component + 'static, U: Component + 'static>(
-        &'a self,
-        ts: &'a Ref<Vec<Option<T>>>,
-        us: &'a Ref<Vec<Option<U>>>,
-    ) -> impl Iterator<Item = (EntityID, &T, &U)> {
-        ts.iter()
-            .enumerate()
-            .zip(us.iter())
-            .filter_map(|((i, t), u)| Some((i, t.as_ref()?, u.as_ref()?)))
-    }
-
-    pub fn get_with_components_mut<'a, T: Component + 'static, U: Component + 'static>(
-        &'a self,
-        ts: &'a mut RefMut<Vec<Option<T>>>,
-        us: &'a mut RefMut<Vec<Option<U>>>,
-    ) -> impl Iterator<Item = (EntityID, &mut T, &mut U)> {
-        ts.iter_mut()
-            .enumerate()
-            .zip(us.iter_mut())
-            .filter_map(|((i, t), u)| Some((i, t.as_mut()?, u.as_mut()?)))
+    /// Query for every entity that matches `Q`, e.g.
+    /// `entities.query::<(&TransformComponent, &mut LightComponent, Option<&HierarchyComponent>)>()`.
+    /// See `query::QueryParam` for what can go in the tuple.
+    pub fn query<Q: QueryParam>(&self) -> Query<'_, Q> {
+        Query::new(self, 0)
+    }
+
+    /// Like `query`, but with an extra set of filters (`With<T>`,
+    /// `Without<T>`, `Added<T>`, `Changed<T>`, or tuples of them) that have to
+    /// match but aren't yielded.
+    pub fn query_filtered<Q: QueryParam, F: QueryParam>(&self) -> Query<'_, Q, F> {
+        Query::new(self, 0)
+    }
+
+    /// Like `query_filtered`, but `Added<T>` and `Changed<T>` only match
+    /// components added or changed after `since`, instead of ever. Systems
+    /// should usually use `SystemContext::query_filtered` instead, which
+    /// fills in the last time the system ran.
+    pub fn query_since<Q: QueryParam, F: QueryParam>(&self, since: Tick) -> Query<'_, Q, F> {
+        Query::new(self, since)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Health(usize);
+    impl Component for Health {
+        fn get_id() -> ComponentID {
+            "Health"
+        }
+    }
+
+    #[test]
+    fn recycled_ids_never_revive_stale_handles() {
+        let mut entities = EntitySystem::new();
+        let mut live: Vec<Entity> = (0..16).map(|_| entities.gen_entity()).collect();
+        for (i, e) in live.iter().enumerate() {
+            entities.add_component(*e, Health(i)).unwrap();
+        }
+        let mut stale = vec![];
+
+        for i in 0..10_000 {
+            // Delete one from somewhere in the middle, so freed IDs don't just
+            // come back in the order they were handed out
+            let dead = live.swap_remove((i * 7) % live.len());
+            entities.delete_entity(dead).unwrap();
+            assert!(!entities.is_alive(dead));
+            assert_eq!(entities.delete_entity(dead), Err(EntityError::Dead(dead)));
+
+            let new = entities.gen_entity();
+            assert_eq!(new.id, dead.id, "freed ID wasn't reused");
+            assert!(new.generation > dead.generation);
+            entities.add_component(new, Health(i)).unwrap();
+
+            // The old handle points at the same slot as the new entity, but
+            // mustn't see it
+            assert!(!entities.is_alive(dead));
+            assert!(matches!(
+                entities.get_component::<Health>(dead),
+                Err(EntityError::Dead(e)) if e == dead
+            ));
+            assert_eq!(entities.get_component::<Health>(new).unwrap().0, i);
+
+            stale.push(dead);
+            live.push(new);
+        }
+
+        // All those entities fit in the original 16 IDs
+        assert_eq!(entities.entity_count, 16);
+        for e in stale {
+            assert!(!entities.is_alive(e));
+            assert!(matches!(
+                entities.get_component::<Health>(e),
+                Err(EntityError::Dead(_))
+            ));
+        }
+        for e in live {
+            assert!(entities.is_alive(e));
+            assert!(entities.get_component::<Health>(e).is_ok());
+        }
     }
 }
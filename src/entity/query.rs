@@ -0,0 +1,387 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use std::{marker::PhantomData, ptr::NonNull};
+
+use atomic_refcell::{AtomicRef, AtomicRefMut};
+
+use super::{
+    change_detection::{ComponentTicks, Mut, Tick},
+    sparse_set::SparseSet,
+    Component, Entity, EntityID, EntitySystem,
+};
+
+/// Something that can be asked for in a query: `&T`, `&mut T`, `Option<&T>`,
+/// `Option<&mut T>`, the `With<T>`/`Without<T>`/`Added<T>`/`Changed<T>`
+/// filters, or a tuple of any of those. `&mut T` is handed out as a `Mut<T>`,
+/// so the component is only marked as changed if it's actually mutated.
+///
+/// Borrowing is still checked at runtime by the component storage's
+/// `AtomicRefCell`s, so asking for `(&T, &mut T)` in the same query (or
+/// holding a `get_component_vec_mut::<T>()` while querying for `&T`) will
+/// panic instead of aliasing.
+pub trait QueryParam {
+    /// The borrowed component storage(s) this parameter reads from, held for
+    /// as long as the query is alive.
+    type Fetch<'w>;
+    /// What this parameter produces for each matching entity.
+    type Item<'q>;
+
+    /// Borrows the storage this parameter needs. Returns `None` if the query
+    /// can't possibly match anything (a required component type that no
+    /// entity has ever had). `since` is the tick the change detection filters
+    /// compare against.
+    fn fetch(entities: &EntitySystem, since: Tick) -> Option<Self::Fetch<'_>>;
+
+    /// The entities this parameter is *guaranteed* to be limited to, if any,
+    /// so the query can drive iteration off of the smallest required set
+    /// instead of every entity in the world.
+    fn candidates<'a>(fetch: &'a Self::Fetch<'_>) -> Option<&'a [EntityID]>;
+
+    fn matches(fetch: &Self::Fetch<'_>, eid: EntityID) -> bool;
+
+    /// # Safety
+    ///
+    /// `matches` must have returned true for `eid`, and the caller must not
+    /// hand out two items for the same entity at the same time, since items
+    /// for mutable parameters are derived from a raw pointer into the storage.
+    unsafe fn get<'q>(fetch: &'q Self::Fetch<'_>, eid: EntityID) -> Self::Item<'q>;
+}
+
+/// Only matches entities that have a `T`, without borrowing it.
+pub struct With<T>(PhantomData<T>);
+/// Only matches entities that do *not* have a `T`.
+pub struct Without<T>(PhantomData<T>);
+/// Only matches entities whose `T` was added since the query's tick.
+pub struct Added<T>(PhantomData<T>);
+/// Only matches entities whose `T` was added or mutated since the query's
+/// tick.
+pub struct Changed<T>(PhantomData<T>);
+
+/// A mutably borrowed sparse set. We keep the guard around so nobody else can
+/// borrow the storage, but hand out items through a raw pointer to the dense
+/// array, since an iterator can't lend out `&mut`s derived from itself.
+pub struct FetchMut<'w, T> {
+    set: AtomicRefMut<'w, SparseSet<T>>,
+    dense: NonNull<T>,
+    ticks: NonNull<ComponentTicks>,
+    tick: Tick,
+}
+
+impl<'w, T> FetchMut<'w, T> {
+    pub(crate) fn new(mut set: AtomicRefMut<'w, SparseSet<T>>) -> Self {
+        let tick = set.current_tick();
+        let (dense, ticks) = set.dense_mut_ptrs();
+        Self {
+            set,
+            dense: NonNull::new(dense).unwrap(),
+            ticks: NonNull::new(ticks).unwrap(),
+            tick,
+        }
+    }
+
+    /// # Safety
+    ///
+    /// No other reference to `eid`'s component may be alive.
+    pub(crate) unsafe fn get_mut(&self, eid: EntityID) -> Option<Mut<'_, T>> {
+        self.set.dense_index(eid).map(|i| {
+            Mut::new(
+                &mut *self.dense.as_ptr().add(i),
+                &mut *self.ticks.as_ptr().add(i),
+                self.tick,
+            )
+        })
+    }
+}
+
+impl<T: Component + 'static> QueryParam for &T {
+    type Fetch<'w> = AtomicRef<'w, SparseSet<T>>;
+    type Item<'q> = &'q T;
+
+    fn fetch(entities: &EntitySystem, _since: Tick) -> Option<Self::Fetch<'_>> {
+        entities.get_component_vec::<T>()
+    }
+    fn candidates<'b>(fetch: &'b Self::Fetch<'_>) -> Option<&'b [EntityID]> {
+        Some(fetch.entities())
+    }
+    fn matches(fetch: &Self::Fetch<'_>, eid: EntityID) -> bool {
+        fetch.contains(eid)
+    }
+    unsafe fn get<'q>(fetch: &'q Self::Fetch<'_>, eid: EntityID) -> Self::Item<'q> {
+        fetch.get(eid).unwrap()
+    }
+}
+
+impl<T: Component + 'static> QueryParam for &mut T {
+    type Fetch<'w> = FetchMut<'w, T>;
+    type Item<'q> = Mut<'q, T>;
+
+    fn fetch(entities: &EntitySystem, _since: Tick) -> Option<Self::Fetch<'_>> {
+        entities.get_component_vec_mut::<T>().map(FetchMut::new)
+    }
+    fn candidates<'b>(fetch: &'b Self::Fetch<'_>) -> Option<&'b [EntityID]> {
+        Some(fetch.set.entities())
+    }
+    fn matches(fetch: &Self::Fetch<'_>, eid: EntityID) -> bool {
+        fetch.set.contains(eid)
+    }
+    unsafe fn get<'q>(fetch: &'q Self::Fetch<'_>, eid: EntityID) -> Self::Item<'q> {
+        fetch.get_mut(eid).unwrap()
+    }
+}
+
+impl<T: Component + 'static> QueryParam for Option<&T> {
+    type Fetch<'w> = Option<AtomicRef<'w, SparseSet<T>>>;
+    type Item<'q> = Option<&'q T>;
+
+    fn fetch(entities: &EntitySystem, _since: Tick) -> Option<Self::Fetch<'_>> {
+        Some(entities.get_component_vec::<T>())
+    }
+    fn candidates<'b>(_fetch: &'b Self::Fetch<'_>) -> Option<&'b [EntityID]> {
+        None
+    }
+    fn matches(_fetch: &Self::Fetch<'_>, _eid: EntityID) -> bool {
+        true
+    }
+    unsafe fn get<'q>(fetch: &'q Self::Fetch<'_>, eid: EntityID) -> Self::Item<'q> {
+        fetch.as_ref().and_then(|set| set.get(eid))
+    }
+}
+
+impl<T: Component + 'static> QueryParam for Option<&mut T> {
+    type Fetch<'w> = Option<FetchMut<'w, T>>;
+    type Item<'q> = Option<Mut<'q, T>>;
+
+    fn fetch(entities: &EntitySystem, _since: Tick) -> Option<Self::Fetch<'_>> {
+        Some(entities.get_component_vec_mut::<T>().map(FetchMut::new))
+    }
+    fn candidates<'b>(_fetch: &'b Self::Fetch<'_>) -> Option<&'b [EntityID]> {
+        None
+    }
+    fn matches(_fetch: &Self::Fetch<'_>, _eid: EntityID) -> bool {
+        true
+    }
+    unsafe fn get<'q>(fetch: &'q Self::Fetch<'_>, eid: EntityID) -> Self::Item<'q> {
+        fetch.as_ref().and_then(|set| set.get_mut(eid))
+    }
+}
+
+impl<T: Component + 'static> QueryParam for With<T> {
+    type Fetch<'w> = AtomicRef<'w, SparseSet<T>>;
+    type Item<'q> = ();
+
+    fn fetch(entities: &EntitySystem, _since: Tick) -> Option<Self::Fetch<'_>> {
+        entities.get_component_vec::<T>()
+    }
+    fn candidates<'b>(fetch: &'b Self::Fetch<'_>) -> Option<&'b [EntityID]> {
+        Some(fetch.entities())
+    }
+    fn matches(fetch: &Self::Fetch<'_>, eid: EntityID) -> bool {
+        fetch.contains(eid)
+    }
+    unsafe fn get<'q>(_fetch: &'q Self::Fetch<'_>, _eid: EntityID) -> Self::Item<'q> {}
+}
+
+impl<T: Component + 'static> QueryParam for Without<T> {
+    type Fetch<'w> = Option<AtomicRef<'w, SparseSet<T>>>;
+    type Item<'q> = ();
+
+    fn fetch(entities: &EntitySystem, _since: Tick) -> Option<Self::Fetch<'_>> {
+        Some(entities.get_component_vec::<T>())
+    }
+    fn candidates<'b>(_fetch: &'b Self::Fetch<'_>) -> Option<&'b [EntityID]> {
+        None
+    }
+    fn matches(fetch: &Self::Fetch<'_>, eid: EntityID) -> bool {
+        !fetch.as_ref().is_some_and(|set| set.contains(eid))
+    }
+    unsafe fn get<'q>(_fetch: &'q Self::Fetch<'_>, _eid: EntityID) -> Self::Item<'q> {}
+}
+
+impl<T: Component + 'static> QueryParam for Added<T> {
+    type Fetch<'w> = (AtomicRef<'w, SparseSet<T>>, Tick);
+    type Item<'q> = ();
+
+    fn fetch(entities: &EntitySystem, since: Tick) -> Option<Self::Fetch<'_>> {
+        Some((entities.get_component_vec::<T>()?, since))
+    }
+    fn candidates<'b>((set, _): &'b Self::Fetch<'_>) -> Option<&'b [EntityID]> {
+        Some(set.entities())
+    }
+    fn matches((set, since): &Self::Fetch<'_>, eid: EntityID) -> bool {
+        set.ticks(eid)
+            .is_some_and(|ticks| ticks.is_added_since(*since))
+    }
+    unsafe fn get<'q>(_fetch: &'q Self::Fetch<'_>, _eid: EntityID) -> Self::Item<'q> {}
+}
+
+impl<T: Component + 'static> QueryParam for Changed<T> {
+    type Fetch<'w> = (AtomicRef<'w, SparseSet<T>>, Tick);
+    type Item<'q> = ();
+
+    fn fetch(entities: &EntitySystem, since: Tick) -> Option<Self::Fetch<'_>> {
+        Some((entities.get_component_vec::<T>()?, since))
+    }
+    fn candidates<'b>((set, _): &'b Self::Fetch<'_>) -> Option<&'b [EntityID]> {
+        Some(set.entities())
+    }
+    fn matches((set, since): &Self::Fetch<'_>, eid: EntityID) -> bool {
+        set.ticks(eid)
+            .is_some_and(|ticks| ticks.is_changed_since(*since))
+    }
+    unsafe fn get<'q>(_fetch: &'q Self::Fetch<'_>, _eid: EntityID) -> Self::Item<'q> {}
+}
+
+impl QueryParam for () {
+    type Fetch<'w> = ();
+    type Item<'q> = ();
+
+    fn fetch(_entities: &EntitySystem, _since: Tick) -> Option<Self::Fetch<'_>> {
+        Some(())
+    }
+    fn candidates<'b>(_fetch: &'b Self::Fetch<'_>) -> Option<&'b [EntityID]> {
+        None
+    }
+    fn matches(_fetch: &Self::Fetch<'_>, _eid: EntityID) -> bool {
+        true
+    }
+    unsafe fn get<'q>(_fetch: &'q Self::Fetch<'_>, _eid: EntityID) -> Self::Item<'q> {}
+}
+
+macro_rules! impl_query_param_tuple {
+    ($($name: ident),+) => {
+        #[allow(non_snake_case)]
+        impl<$($name: QueryParam),+> QueryParam for ($($name,)+) {
+            type Fetch<'w> = ($($name::Fetch<'w>,)+);
+            type Item<'q> = ($($name::Item<'q>,)+);
+
+            fn fetch(entities: &EntitySystem, since: Tick) -> Option<Self::Fetch<'_>> {
+                Some(($($name::fetch(entities, since)?,)+))
+            }
+            fn candidates<'b>(fetch: &'b Self::Fetch<'_>) -> Option<&'b [EntityID]> {
+                let ($($name,)+) = fetch;
+                [$($name::candidates($name)),+]
+                    .into_iter()
+                    .flatten()
+                    .min_by_key(|c| c.len())
+            }
+            fn matches(fetch: &Self::Fetch<'_>, eid: EntityID) -> bool {
+                let ($($name,)+) = fetch;
+                $($name::matches($name, eid))&&+
+            }
+            unsafe fn get<'q>(fetch: &'q Self::Fetch<'_>, eid: EntityID) -> Self::Item<'q> {
+                let ($($name,)+) = fetch;
+                ($($name::get($name, eid),)+)
+            }
+        }
+    };
+}
+
+impl_query_param_tuple!(A);
+impl_query_param_tuple!(A, B);
+impl_query_param_tuple!(A, B, C);
+impl_query_param_tuple!(A, B, C, D);
+impl_query_param_tuple!(A, B, C, D, E);
+impl_query_param_tuple!(A, B, C, D, E, F);
+impl_query_param_tuple!(A, B, C, D, E, F, G);
+impl_query_param_tuple!(A, B, C, D, E, F, G, H);
+
+/// A live query over the entity system. Holds the borrows of every component
+/// storage it touches until it's dropped, so keep it short-lived.
+///
+/// `Q` is what gets yielded for each entity, and `F` is an extra set of
+/// filters (usually `With`/`Without`) that have to match but aren't yielded.
+pub struct Query<'w, Q: QueryParam, F: QueryParam = ()> {
+    entities: &'w EntitySystem,
+    fetch: Option<(Q::Fetch<'w>, F::Fetch<'w>)>,
+}
+
+impl<'w, Q: QueryParam, F: QueryParam> Query<'w, Q, F> {
+    /// `since` is the tick `Added`/`Changed` filters compare against.
+    pub fn new(entities: &'w EntitySystem, since: Tick) -> Self {
+        let fetch = Q::fetch(entities, since).and_then(|q| Some((q, F::fetch(entities, since)?)));
+        Self { entities, fetch }
+    }
+
+    /// Iterate over every entity matching the query. Takes `&mut self` so
+    /// that two iterators can't hand out overlapping mutable components.
+    pub fn iter(&mut self) -> QueryIter<'_, 'w, Q, F> {
+        let driver = match self.fetch.as_ref() {
+            None => Driver::Slice([].iter()),
+            Some(fetch) => match <(Q, F)>::candidates(fetch) {
+                Some(candidates) => Driver::Slice(candidates.iter()),
+                // Nothing we need is required, so we have to check everyone
+                None => Driver::All(0..self.entities.entity_count),
+            },
+        };
+        QueryIter {
+            entities: self.entities,
+            fetch: self.fetch.as_ref(),
+            driver,
+        }
+    }
+
+    /// Fetch the query's item for a single entity, if it's alive and matches.
+    pub fn get(&mut self, entity: Entity) -> Option<Q::Item<'_>> {
+        let fetch = self.fetch.as_ref()?;
+        if self.entities.get_current_entity_from_id(entity.id) != Some(entity)
+            || !<(Q, F)>::matches(fetch, entity.id)
+        {
+            return None;
+        }
+        // SAFETY: we have &mut self, so no other items can be alive.
+        Some(unsafe { Q::get(&fetch.0, entity.id) })
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.fetch.as_ref().is_some_and(|fetch| {
+            self.entities.get_current_entity_from_id(entity.id) == Some(entity)
+                && <(Q, F)>::matches(fetch, entity.id)
+        })
+    }
+}
+
+enum Driver<'q> {
+    Slice(std::slice::Iter<'q, EntityID>),
+    All(std::ops::Range<EntityID>),
+}
+
+impl<'q> Iterator for Driver<'q> {
+    type Item = EntityID;
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            Driver::Slice(s) => s.next().copied(),
+            Driver::All(r) => r.next(),
+        }
+    }
+}
+
+pub struct QueryIter<'q, 'w, Q: QueryParam, F: QueryParam> {
+    entities: &'w EntitySystem,
+    fetch: Option<&'q (Q::Fetch<'w>, F::Fetch<'w>)>,
+    driver: Driver<'q>,
+}
+
+impl<'q, 'w, Q: QueryParam, F: QueryParam> Iterator for QueryIter<'q, 'w, Q, F> {
+    type Item = (Entity, Q::Item<'q>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let fetch = self.fetch?;
+        for eid in self.driver.by_ref() {
+            if !<(Q, F)>::matches(fetch, eid) {
+                continue;
+            }
+            if let Some(entity) = self.entities.get_current_entity_from_id(eid) {
+                // SAFETY: the driver yields every entity ID at most once, so
+                // we never hand out two items for the same entity.
+                return Some((entity, unsafe { Q::get(&fetch.0, eid) }));
+            }
+        }
+        None
+    }
+}
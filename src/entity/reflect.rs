@@ -0,0 +1,541 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Runtime reflection, so that anything that wants to poke at components
+//! generically (the debug console, scripting, an inspector) can read and
+//! write their fields by name, using paths like `"transform.trans.x"`,
+//! without needing code written for every component type.
+//!
+//! Derive `Reflect` (from `render_gl_derive`) to get it for your own types;
+//! the plain values fields bottom out in (numbers, bools, strings, glam
+//! vectors and quaternions) implement it here. Values can be read and
+//! written either as their actual Rust type or as TOML values, for things
+//! that only have text to go on.
+
+use std::{any::Any, fmt::Display};
+
+/// The derive lives alongside the trait, so importing one gets both
+pub use render_gl_derive::Reflect;
+
+use crate::update_thread::GameState;
+
+use super::{Entity, EntityError};
+
+/// The name and (source-level) type of one of a reflected value's fields.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FieldInfo {
+    pub name: &'static str,
+    pub type_name: &'static str,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReflectError {
+    /// The path tried to go into a field the value doesn't have
+    NoField {
+        path: String,
+        type_name: String,
+    },
+    /// The value at the path isn't of the type it was read or written as
+    WrongType {
+        path: String,
+        type_name: String,
+    },
+    /// A TOML value that doesn't fit the field it was written to
+    InvalidValue {
+        path: String,
+        message: String,
+    },
+    /// A component ID that was never registered for reflection
+    UnknownComponent(String),
+    Entity(EntityError),
+}
+
+impl Display for ReflectError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReflectError::NoField { path, type_name } => {
+                write!(f, "{} has no field at \"{}\"", type_name, path)
+            }
+            ReflectError::WrongType { path, type_name } => {
+                write!(f, "\"{}\" is a {}", path, type_name)
+            }
+            ReflectError::InvalidValue { path, message } => {
+                write!(f, "invalid value for \"{}\": {}", path, message)
+            }
+            ReflectError::UnknownComponent(c) => {
+                write!(f, "{} isn't a reflected component type", c)
+            }
+            ReflectError::Entity(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+pub trait Reflect: Any {
+    fn type_name(&self) -> &'static str;
+
+    /// The fields that can be reached through `field` and `field_mut`. Plain
+    /// values don't have any.
+    fn fields(&self) -> Vec<FieldInfo>;
+    fn field(&self, name: &str) -> Option<&dyn Reflect>;
+    fn field_mut(&mut self, name: &str) -> Option<&mut dyn Reflect>;
+
+    fn as_any(&self) -> &dyn Any;
+    fn as_any_mut(&mut self) -> &mut dyn Any;
+
+    /// The value as TOML: a table of all the fields, for anything that has
+    /// them.
+    fn to_toml(&self) -> toml::Value {
+        toml::Value::Table(
+            self.fields()
+                .into_iter()
+                .filter_map(|f| Some((f.name.to_string(), self.field(f.name)?.to_toml())))
+                .collect(),
+        )
+    }
+
+    /// Checks that `set_toml` would take the value, without changing
+    /// anything.
+    fn check_toml(&self, value: &toml::Value) -> Result<(), ReflectError> {
+        let toml::Value::Table(table) = value else {
+            return Err(ReflectError::InvalidValue {
+                path: String::new(),
+                message: format!("expected a table for {}", self.type_name()),
+            });
+        };
+        for (name, value) in table {
+            let field = self.field(name).ok_or_else(|| ReflectError::NoField {
+                path: name.clone(),
+                type_name: self.type_name().to_string(),
+            })?;
+            field.check_toml(value).map_err(|e| e.prefixed_with(name))?;
+        }
+        Ok(())
+    }
+
+    /// Overwrites the value from TOML. Tables only need to mention the
+    /// fields they're changing. The whole value is checked before any of it
+    /// is written, so if some of it doesn't fit, nothing changes.
+    fn set_toml(&mut self, value: toml::Value) -> Result<(), ReflectError> {
+        self.check_toml(&value)?;
+        let toml::Value::Table(table) = value else {
+            return Err(ReflectError::InvalidValue {
+                path: String::new(),
+                message: format!("expected a table for {}", self.type_name()),
+            });
+        };
+        for (name, value) in table {
+            let type_name = self.type_name().to_string();
+            let field = self.field_mut(&name).ok_or_else(|| ReflectError::NoField {
+                path: name.clone(),
+                type_name,
+            })?;
+            field.set_toml(value).map_err(|e| e.prefixed_with(&name))?;
+        }
+        Ok(())
+    }
+}
+
+impl ReflectError {
+    /// Puts the name of the field the error happened inside of in front of
+    /// its path.
+    fn prefixed_with(self, field: &str) -> Self {
+        let prefix = |path: String| {
+            if path.is_empty() {
+                field.to_string()
+            } else {
+                format!("{}.{}", field, path)
+            }
+        };
+        match self {
+            ReflectError::NoField { path, type_name } => ReflectError::NoField {
+                path: prefix(path),
+                type_name,
+            },
+            ReflectError::WrongType { path, type_name } => ReflectError::WrongType {
+                path: prefix(path),
+                type_name,
+            },
+            ReflectError::InvalidValue { path, message } => ReflectError::InvalidValue {
+                path: prefix(path),
+                message,
+            },
+            e => e,
+        }
+    }
+}
+
+impl dyn Reflect {
+    /// Follows a dot-separated path of field names down from this value. The
+    /// empty path is the value itself.
+    pub fn path(&self, path: &str) -> Result<&dyn Reflect, ReflectError> {
+        let mut value = self;
+        for (i, name) in path.split('.').filter(|n| !n.is_empty()).enumerate() {
+            value = value.field(name).ok_or_else(|| ReflectError::NoField {
+                path: path_prefix(path, i),
+                type_name: value.type_name().to_string(),
+            })?;
+        }
+        Ok(value)
+    }
+
+    pub fn path_mut(&mut self, path: &str) -> Result<&mut dyn Reflect, ReflectError> {
+        let mut value = self;
+        for (i, name) in path.split('.').filter(|n| !n.is_empty()).enumerate() {
+            let type_name = value.type_name();
+            value = value.field_mut(name).ok_or_else(|| ReflectError::NoField {
+                path: path_prefix(path, i),
+                type_name: type_name.to_string(),
+            })?;
+        }
+        Ok(value)
+    }
+
+    pub fn get_path<T: Reflect>(&self, path: &str) -> Result<&T, ReflectError> {
+        let value = self.path(path)?;
+        value
+            .as_any()
+            .downcast_ref()
+            .ok_or_else(|| ReflectError::WrongType {
+                path: path.to_string(),
+                type_name: value.type_name().to_string(),
+            })
+    }
+
+    pub fn set_path<T: Reflect>(&mut self, path: &str, new_value: T) -> Result<(), ReflectError> {
+        let value = self.path_mut(path)?;
+        let type_name = value.type_name();
+        let value = value
+            .as_any_mut()
+            .downcast_mut()
+            .ok_or_else(|| ReflectError::WrongType {
+                path: path.to_string(),
+                type_name: type_name.to_string(),
+            })?;
+        *value = new_value;
+        Ok(())
+    }
+
+    pub fn get_path_toml(&self, path: &str) -> Result<toml::Value, ReflectError> {
+        Ok(self.path(path)?.to_toml())
+    }
+
+    pub fn set_path_toml(&mut self, path: &str, value: toml::Value) -> Result<(), ReflectError> {
+        self.path_mut(path)?.set_toml(value).map_err(|e| {
+            // Errors come back relative to the field we started at
+            path.split('.')
+                .filter(|n| !n.is_empty())
+                .rev()
+                .fold(e, |e, name| e.prefixed_with(name))
+        })
+    }
+}
+
+impl From<EntityError> for ReflectError {
+    fn from(e: EntityError) -> Self {
+        ReflectError::Entity(e)
+    }
+}
+
+impl GameState {
+    /// Reads a field of one of the entity's components, given the
+    /// component's ID (it has to be registered with
+    /// `ComponentRegistry::register_reflect`).
+    pub fn get_component_field(
+        &self,
+        e: Entity,
+        component: &str,
+        path: &str,
+    ) -> Result<toml::Value, ReflectError> {
+        let mut result = None;
+        self.registry
+            .reflect(&self.entities, e, component, &mut |c| {
+                result = Some(c.get_path_toml(path))
+            })?;
+        result.expect("reflect calls back when it succeeds")
+    }
+
+    /// Writes a field of one of the entity's components. This marks the
+    /// component as changed, but doesn't run any of its hooks, so changing
+    /// things hooks care about (like a model's path) won't take effect:
+    /// replace the whole component for that.
+    pub fn set_component_field(
+        &mut self,
+        e: Entity,
+        component: &str,
+        path: &str,
+        value: toml::Value,
+    ) -> Result<(), ReflectError> {
+        let mut value = Some(value);
+        let mut result = None;
+        self.registry
+            .reflect_mut(&self.entities, e, component, &mut |c| {
+                result = value.take().map(|value| c.set_path_toml(path, value))
+            })?;
+        result.expect("reflect_mut calls back when it succeeds")
+    }
+}
+
+/// The first `n + 1` names of a path, for errors about the `n`th one.
+fn path_prefix(path: &str, n: usize) -> String {
+    path.split('.')
+        .filter(|n| !n.is_empty())
+        .take(n + 1)
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Plain values have no fields, and convert to and from TOML through serde.
+macro_rules! impl_reflect_value {
+    ($($t:ty),*) => {
+        $(
+            impl Reflect for $t {
+                fn type_name(&self) -> &'static str {
+                    stringify!($t)
+                }
+                fn fields(&self) -> Vec<FieldInfo> {
+                    vec![]
+                }
+                fn field(&self, _name: &str) -> Option<&dyn Reflect> {
+                    None
+                }
+                fn field_mut(&mut self, _name: &str) -> Option<&mut dyn Reflect> {
+                    None
+                }
+                fn as_any(&self) -> &dyn Any {
+                    self
+                }
+                fn as_any_mut(&mut self) -> &mut dyn Any {
+                    self
+                }
+                fn to_toml(&self) -> toml::Value {
+                    toml::Value::try_from(self).expect("Plain values can always be turned into TOML")
+                }
+                fn check_toml(&self, value: &toml::Value) -> Result<(), ReflectError> {
+                    value.clone().try_into::<$t>().map(|_| ())
+                        .map_err(|e| ReflectError::InvalidValue {
+                            path: String::new(),
+                            message: e.message().to_string(),
+                        })
+                }
+                fn set_toml(&mut self, value: toml::Value) -> Result<(), ReflectError> {
+                    *self = value.try_into().map_err(|e: toml::de::Error| ReflectError::InvalidValue {
+                        path: String::new(),
+                        message: e.message().to_string(),
+                    })?;
+                    Ok(())
+                }
+            }
+        )*
+    };
+}
+
+impl_reflect_value!(bool, f32, f64, i32, i64, u8, u32, usize, String);
+
+/// Foreign types whose fields we can get at directly, like glam's vectors.
+macro_rules! impl_reflect_fields {
+    ($($t:ty { $($field:ident: $field_ty:ty),* }),*) => {
+        $(
+            impl Reflect for $t {
+                fn type_name(&self) -> &'static str {
+                    stringify!($t)
+                }
+                fn fields(&self) -> Vec<FieldInfo> {
+                    vec![$(FieldInfo {
+                        name: stringify!($field),
+                        type_name: stringify!($field_ty),
+                    }),*]
+                }
+                fn field(&self, name: &str) -> Option<&dyn Reflect> {
+                    match name {
+                        $(stringify!($field) => Some(&self.$field),)*
+                        _ => None,
+                    }
+                }
+                fn field_mut(&mut self, name: &str) -> Option<&mut dyn Reflect> {
+                    match name {
+                        $(stringify!($field) => Some(&mut self.$field),)*
+                        _ => None,
+                    }
+                }
+                fn as_any(&self) -> &dyn Any {
+                    self
+                }
+                fn as_any_mut(&mut self) -> &mut dyn Any {
+                    self
+                }
+            }
+        )*
+    };
+}
+
+impl_reflect_fields!(
+    glam::Vec2 { x: f32, y: f32 },
+    glam::Vec3 {
+        x: f32,
+        y: f32,
+        z: f32
+    },
+    glam::Vec4 {
+        x: f32,
+        y: f32,
+        z: f32,
+        w: f32
+    },
+    glam::Quat {
+        x: f32,
+        y: f32,
+        z: f32,
+        w: f32
+    }
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, PartialEq, Reflect)]
+    struct Thing {
+        name: String,
+        pos: glam::Vec3,
+        shape: Shape,
+        #[reflect(skip)]
+        cache: u32,
+    }
+
+    #[derive(Debug, PartialEq, Reflect)]
+    enum Shape {
+        Sphere { radius: f32 },
+        Box { size: glam::Vec3, solid: bool },
+        Nothing,
+    }
+
+    fn thing() -> Thing {
+        Thing {
+            name: "thing".to_string(),
+            pos: glam::vec3(1.0, 2.0, 3.0),
+            shape: Shape::Sphere { radius: 0.5 },
+            cache: 7,
+        }
+    }
+
+    #[test]
+    fn follows_paths() {
+        let mut thing = thing();
+        let value: &mut dyn Reflect = &mut thing;
+        assert_eq!(value.path("").unwrap().type_name(), "Thing");
+        assert_eq!(value.path("pos.y").unwrap().type_name(), "f32");
+        assert_eq!(*value.get_path::<f32>("pos.y").unwrap(), 2.0);
+        assert_eq!(*value.get_path::<String>("name").unwrap(), "thing");
+        assert_eq!(
+            value.get_path::<bool>("pos.y"),
+            Err(ReflectError::WrongType {
+                path: "pos.y".to_string(),
+                type_name: "f32".to_string(),
+            })
+        );
+        assert_eq!(
+            value.path("pos.w").err(),
+            Some(ReflectError::NoField {
+                path: "pos.w".to_string(),
+                type_name: "glam::Vec3".to_string(),
+            })
+        );
+
+        value
+            .set_path_toml("pos.z", toml::Value::Float(-1.0))
+            .unwrap();
+        value.set_path("name", "other".to_string()).unwrap();
+        assert_eq!(thing.pos, glam::vec3(1.0, 2.0, -1.0));
+        assert_eq!(thing.name, "other");
+    }
+
+    #[test]
+    fn enums_have_the_fields_of_their_variant() {
+        let mut thing = thing();
+        let value: &mut dyn Reflect = &mut thing;
+        assert_eq!(*value.get_path::<f32>("shape.radius").unwrap(), 0.5);
+        assert!(value.path("shape.size").is_err());
+
+        value
+            .set_path_toml("shape.radius", toml::Value::Float(2.0))
+            .unwrap();
+        assert_eq!(thing.shape, Shape::Sphere { radius: 2.0 });
+
+        thing.shape = Shape::Box {
+            size: glam::Vec3::ONE,
+            solid: false,
+        };
+        let value: &mut dyn Reflect = &mut thing;
+        assert!(value.path("shape.radius").is_err());
+        value
+            .set_path_toml("shape.solid", toml::Value::Boolean(true))
+            .unwrap();
+        assert!(*value.get_path::<bool>("shape.solid").unwrap());
+
+        thing.shape = Shape::Nothing;
+        assert!(thing.shape.fields().is_empty());
+        assert_eq!(
+            thing.shape.to_toml(),
+            toml::Value::Table(Default::default())
+        );
+    }
+
+    #[test]
+    fn skipped_fields_are_hidden() {
+        let mut thing = thing();
+        let value: &mut dyn Reflect = &mut thing;
+        assert!(!value.fields().iter().any(|f| f.name == "cache"));
+        assert!(value.path("cache").is_err());
+        assert!(value.get_path_toml("").unwrap().get("cache").is_none());
+        assert!(value
+            .set_path_toml("", toml::toml! { cache = 1 }.into())
+            .is_err());
+        assert_eq!(thing.cache, 7);
+    }
+
+    #[test]
+    fn bad_writes_change_nothing() {
+        let mut thing = thing();
+        let value: &mut dyn Reflect = &mut thing;
+        // `name` and `pos.x` are fine, but `pos.y` isn't, and neither is
+        // `shape.sides`
+        let error = value
+            .set_path_toml(
+                "",
+                toml::toml! {
+                    name = "changed"
+                    pos = { x = 5.0, y = "up" }
+                }
+                .into(),
+            )
+            .unwrap_err();
+        assert!(
+            matches!(error, ReflectError::InvalidValue { ref path, .. } if path == "pos.y"),
+            "{:?}",
+            error
+        );
+        let error = value
+            .set_path_toml(
+                "",
+                toml::toml! {
+                    name = "changed"
+                    shape = { sides = 4 }
+                }
+                .into(),
+            )
+            .unwrap_err();
+        assert_eq!(
+            error,
+            ReflectError::NoField {
+                path: "shape.sides".to_string(),
+                type_name: "Shape".to_string(),
+            }
+        );
+        assert_eq!(thing, self::thing());
+    }
+}
@@ -0,0 +1,196 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use super::{
+    change_detection::{ComponentTicks, Mut, Tick, TickCounter},
+    EntityID,
+};
+
+/// Storage for a single component type. Components are packed tightly into
+/// the `dense` array (so iterating over them only ever touches entities that
+/// actually have the component), and the `sparse` array maps entity IDs to
+/// their component's index in the dense array.
+///
+/// Removal swaps the last component into the removed slot, so the dense
+/// array never has holes, but it also means the order of iteration is *not*
+/// the order of insertion, and it can change whenever something is removed.
+///
+/// Every component also has a set of change ticks, stamped from the world's
+/// `TickCounter` whenever it's inserted or mutated (through `Mut`), and
+/// removals are logged with the tick they happened at, so anyone can ask what
+/// was added, changed or removed since they last looked.
+pub struct SparseSet<T> {
+    /// Indexed by entity ID, points into `dense` and `entities`.
+    sparse: Vec<Option<usize>>,
+    /// The actual components, packed.
+    dense: Vec<T>,
+    /// The entity that owns each component in `dense`, at the same index.
+    entities: Vec<EntityID>,
+    /// When each component in `dense` was added and last changed, at the
+    /// same index.
+    ticks: Vec<ComponentTicks>,
+    /// Entities that lost this component, and when.
+    removed: Vec<(EntityID, Tick)>,
+    clock: TickCounter,
+}
+
+impl<T> Default for SparseSet<T> {
+    fn default() -> Self {
+        Self::new(TickCounter::default())
+    }
+}
+
+impl<T> SparseSet<T> {
+    pub fn new(clock: TickCounter) -> Self {
+        Self {
+            sparse: vec![],
+            dense: vec![],
+            entities: vec![],
+            ticks: vec![],
+            removed: vec![],
+            clock,
+        }
+    }
+
+    /// Number of components actually stored (not the number of entities in
+    /// the world!)
+    pub fn len(&self) -> usize {
+        self.dense.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.dense.is_empty()
+    }
+
+    pub fn contains(&self, eid: EntityID) -> bool {
+        self.dense_index(eid).is_some()
+    }
+
+    pub(crate) fn dense_index(&self, eid: EntityID) -> Option<usize> {
+        self.sparse.get(eid).copied().flatten()
+    }
+
+    /// Raw pointers to the packed components and their ticks, for queries
+    /// that need to lend out mutable references to several of them at once.
+    pub(crate) fn dense_mut_ptrs(&mut self) -> (*mut T, *mut ComponentTicks) {
+        (self.dense.as_mut_ptr(), self.ticks.as_mut_ptr())
+    }
+
+    pub(crate) fn current_tick(&self) -> Tick {
+        self.clock.current()
+    }
+
+    pub fn get(&self, eid: EntityID) -> Option<&T> {
+        self.dense_index(eid).map(|i| &self.dense[i])
+    }
+
+    pub fn get_mut(&mut self, eid: EntityID) -> Option<Mut<'_, T>> {
+        let i = self.dense_index(eid)?;
+        Some(self.get_mut_by_index(i))
+    }
+
+    pub(crate) fn get_by_index(&self, i: usize) -> &T {
+        &self.dense[i]
+    }
+
+    pub(crate) fn get_mut_by_index(&mut self, i: usize) -> Mut<'_, T> {
+        let tick = self.clock.current();
+        Mut::new(&mut self.dense[i], &mut self.ticks[i], tick)
+    }
+
+    pub fn ticks(&self, eid: EntityID) -> Option<ComponentTicks> {
+        self.dense_index(eid).map(|i| self.ticks[i])
+    }
+
+    pub(crate) fn ticks_by_index(&self, i: usize) -> ComponentTicks {
+        self.ticks[i]
+    }
+
+    /// Inserts (or replaces) the component for the given entity, returning
+    /// the old component if there was one. Replacing counts as a change, not
+    /// an addition.
+    pub fn insert(&mut self, eid: EntityID, value: T) -> Option<T> {
+        let tick = self.clock.current();
+        if let Some(i) = self.dense_index(eid) {
+            self.ticks[i].changed = tick;
+            return Some(std::mem::replace(&mut self.dense[i], value));
+        }
+
+        if eid >= self.sparse.len() {
+            self.sparse.resize(eid + 1, None);
+        }
+        self.sparse[eid] = Some(self.dense.len());
+        self.dense.push(value);
+        self.entities.push(eid);
+        self.ticks.push(ComponentTicks::new(tick));
+        None
+    }
+
+    /// Removes the component for the given entity, if it has one, by swapping
+    /// the last component in the dense array into its place.
+    pub fn remove(&mut self, eid: EntityID) -> Option<T> {
+        let i = self.dense_index(eid)?;
+        self.sparse[eid] = None;
+
+        let last = self.dense.len() - 1;
+        if i != last {
+            // The last entity's component is about to move to i
+            self.sparse[self.entities[last]] = Some(i);
+        }
+        self.entities.swap_remove(i);
+        self.ticks.swap_remove(i);
+        self.removed.push((eid, self.clock.current()));
+        Some(self.dense.swap_remove(i))
+    }
+
+    /// Entities that lost this component (or were deleted) after `since`.
+    /// The entity might be alive again by now with a new generation, or even
+    /// have the component again.
+    pub fn removed_since(&self, since: Tick) -> impl Iterator<Item = EntityID> + '_ {
+        self.removed
+            .iter()
+            .filter(move |(_, tick)| *tick > since)
+            .map(|(eid, _)| *eid)
+    }
+
+    /// Forgets removals from `tick` or earlier, once nobody can still be
+    /// interested in them.
+    pub fn clear_removed_through(&mut self, tick: Tick) {
+        self.removed.retain(|(_, t)| *t > tick);
+    }
+
+    /// The entities that have this component, in the same order as `iter`.
+    pub fn entities(&self) -> &[EntityID] {
+        &self.entities
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (EntityID, &T)> {
+        self.entities.iter().copied().zip(self.dense.iter())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (EntityID, Mut<'_, T>)> {
+        let tick = self.clock.current();
+        self.entities.iter().copied().zip(
+            self.dense
+                .iter_mut()
+                .zip(self.ticks.iter_mut())
+                .map(move |(value, ticks)| Mut::new(value, ticks, tick)),
+        )
+    }
+
+    /// Iterates over every component added or changed after `since`.
+    pub fn iter_changed_since(&self, since: Tick) -> impl Iterator<Item = (EntityID, &T)> {
+        self.entities
+            .iter()
+            .copied()
+            .zip(self.dense.iter())
+            .zip(self.ticks.iter())
+            .filter(move |(_, ticks)| ticks.is_changed_since(since))
+            .map(|(item, _)| item)
+    }
+}
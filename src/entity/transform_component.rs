@@ -6,28 +6,42 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
-use std::collections::HashSet;
-
-use crate::render_gl::{data, objects};
-
 use super::*;
-use crate::render_gl::objects::Buffer;
-use glam::Vec4Swizzles;
 use render_gl_derive::ComponentId;
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Copy, Clone, PartialEq)]
+use super::reflect::Reflect;
+
+#[derive(Debug, Copy, Clone, PartialEq, Serialize, Deserialize, Reflect)]
+#[serde(default)]
 pub struct Transform {
     pub trans: glam::Vec3,
     pub rot: glam::Quat,
+    pub scale: glam::Vec3,
+}
+
+impl Default for Transform {
+    fn default() -> Self {
+        Self {
+            trans: glam::Vec3::ZERO,
+            rot: glam::Quat::IDENTITY,
+            scale: glam::Vec3::ONE,
+        }
+    }
 }
 
 impl Transform {
+    /// Scale, then rotate, then translate.
     pub fn to_matrix(&self) -> glam::Mat4 {
-        glam::Mat4::from_rotation_translation(self.rot, self.trans)
+        glam::Mat4::from_scale_rotation_translation(self.scale, self.rot, self.trans)
     }
 }
 
-#[derive(ComponentId)]
+/// An entity's transform, relative to its parent if it has one (see
+/// `HierarchyComponent`), or to the world if it doesn't. Anything left out
+/// when it's loaded is the identity.
+#[derive(Default, Serialize, Deserialize, Reflect)]
+#[serde(default)]
 pub struct TransformComponent {
     pub transform: Transform,
     /// Whether the rotating object behaves as if it is attached to the "ground"
@@ -35,25 +49,35 @@ pub struct TransformComponent {
     /// original XZ plane while vertical rotations are relative, or if all
     /// rotations are relative. Useful for cameras.
     pub grounded: bool,
-    pub dirty_flag: bool,
 }
 
 impl TransformComponent {
     pub fn new_from_rot_trans(rot: glam::Vec3, trans: glam::Vec3, grounded: bool) -> Self {
         let rot = glam::Quat::from_euler(glam::EulerRot::XYZ, rot.x, rot.y, rot.z).normalize();
-        let transform = Transform { trans, rot };
+        let transform = Transform {
+            trans,
+            rot,
+            scale: glam::Vec3::ONE,
+        };
         Self {
             transform,
             grounded,
-            dirty_flag: true,
         }
     }
 
+    pub fn with_scale(mut self, scale: glam::Vec3) -> Self {
+        self.transform.scale = scale;
+        self
+    }
+
+    pub fn scale_by(&mut self, factor: glam::Vec3) {
+        self.transform.scale *= factor;
+    }
+
     /// Displaces object by the given relative vector *rotated by the direction
     /// the object is pointing*
     pub fn displace_by(&mut self, rel_vec: glam::Vec3) {
         self.transform.trans += self.transform.rot * rel_vec;
-        self.dirty_flag = true;
     }
 
     pub fn rotate(&mut self, pyr: glam::Vec3) {
@@ -73,13 +97,50 @@ impl TransformComponent {
             let rot = glam::Quat::from_euler(glam::EulerRot::XYZ, pyr.x, pyr.y, pyr.z).normalize();
             self.transform.rot = self.transform.rot * rot;
         }
-
-        self.dirty_flag = true;
     }
 
     pub fn point_of_view(&self) -> glam::Mat4 {
-        let Transform { trans: pos, rot } = self.transform;
+        let Transform {
+            trans: pos, rot, ..
+        } = self.transform;
         let direction = rot * glam::Vec3::Z;
         glam::Mat4::look_to_rh(pos, direction, rot * glam::Vec3::Y)
     }
 }
+
+impl Component for TransformComponent {
+    fn get_id() -> ComponentID {
+        "TransformComponent"
+    }
+    fn add_hook(&mut self, current_entity: Entity, game_state: &mut GameState) {
+        // Everything with a local transform gets a world transform for the
+        // propagation pass to fill in.
+        game_state
+            .entities
+            .add_component(current_entity, GlobalTransformComponent::default())
+            .expect("add_hook is only called for live entities");
+    }
+    fn remove_hook(&mut self, current_entity: Entity, game_state: &mut GameState) {
+        // Nothing left for the world transform to be computed from
+        let _ = game_state
+            .entities
+            .remove_component::<GlobalTransformComponent>(current_entity);
+    }
+}
+
+/// The entity's final transform in world space: its own `TransformComponent`
+/// composed with those of all of its ancestors. Added automatically alongside
+/// `TransformComponent`, and only ever written by
+/// `systems::propagate_transforms`, so don't bother modifying it yourself.
+#[derive(ComponentId, Debug, Copy, Clone, PartialEq)]
+pub struct GlobalTransformComponent {
+    pub matrix: glam::Mat4,
+}
+
+impl Default for GlobalTransformComponent {
+    fn default() -> Self {
+        Self {
+            matrix: glam::Mat4::IDENTITY,
+        }
+    }
+}
@@ -0,0 +1,244 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! A typed event bus, so that different parts of the game can react to things
+//! happening without having to know about each other.
+//!
+//! Events can be any `Send + 'static` type. They're queued up as they're
+//! emitted (through `GameState::emit`, or `SystemContext::events` from inside
+//! a system), and handed to listeners at fixed points in each update, its
+//! phases:
+//!
+//! 1. `PreUpdate`: after input has been handled, before systems run
+//! 2. `PostUpdate`: after systems have run
+//! 3. `PreRender`: after transforms have been propagated, just before the
+//!    render state is sent off
+//!
+//! Each listener listens for one type of event in one phase, and sees every
+//! event of that type emitted since the start of the update (so a
+//! `PreRender` listener still sees events that were emitted before
+//! `PreUpdate`). Once the last phase is done, the queue is cleared.
+//!
+//! Everything happens in a deterministic order: events are delivered in the
+//! order they were emitted (events from systems go in the order the systems
+//! were registered), and to listeners in order of priority, then the order
+//! they were added in. Listeners can emit follow-up events, which are
+//! delivered after everything already queued, in the same phase.
+
+use std::{
+    any::{Any, TypeId},
+    sync::Mutex,
+};
+
+use crate::update_thread::GameState;
+
+/// How many follow-up events deep a chain of events can get before we assume
+/// listeners are just bouncing events between each other forever.
+const MAX_FOLLOW_UP_DEPTH: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum EventPhase {
+    PreUpdate,
+    PostUpdate,
+    PreRender,
+}
+
+impl EventPhase {
+    pub const LAST: EventPhase = EventPhase::PreRender;
+}
+
+struct QueuedEvent {
+    type_id: TypeId,
+    payload: Box<dyn Any + Send>,
+    /// How many events led up to this one (0 if it wasn't a follow-up)
+    depth: usize,
+}
+
+impl QueuedEvent {
+    fn new<E: Send + 'static>(event: E) -> Self {
+        Self {
+            type_id: TypeId::of::<E>(),
+            payload: Box::new(event),
+            depth: 0,
+        }
+    }
+}
+
+pub type ListenerFn = dyn FnMut(&mut GameState, &dyn Any) + Send;
+
+/// Something that wants to be told about every event of one type, in one
+/// phase.
+pub struct Listener {
+    pub name: &'static str,
+    pub phase: EventPhase,
+    priority: i32,
+    type_id: TypeId,
+    run: Box<ListenerFn>,
+}
+
+impl Listener {
+    pub fn new<E: Send + 'static>(
+        name: &'static str,
+        phase: EventPhase,
+        mut run: impl FnMut(&mut GameState, &E) + Send + 'static,
+    ) -> Self {
+        Self {
+            name,
+            phase,
+            priority: 0,
+            type_id: TypeId::of::<E>(),
+            run: Box::new(move |game_state, event| {
+                run(
+                    game_state,
+                    event
+                        .downcast_ref()
+                        .expect("Events are only delivered to listeners of their type"),
+                )
+            }),
+        }
+    }
+
+    /// Listeners with a lower priority go first. The default is 0.
+    pub fn priority(mut self, priority: i32) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// Collects events emitted from places that only have shared access, like
+/// systems, which may be running on several threads at once.
+#[derive(Default)]
+pub struct EventWriter {
+    events: Mutex<Vec<QueuedEvent>>,
+}
+
+impl EventWriter {
+    pub fn send<E: Send + 'static>(&self, event: E) {
+        self.events.lock().unwrap().push(QueuedEvent::new(event));
+    }
+
+    /// Moves everything written to `other` to the end of this one.
+    pub fn append(&self, other: EventWriter) {
+        let other = other.events.into_inner().unwrap();
+        self.events.lock().unwrap().extend(other);
+    }
+
+    /// Takes out every event of type `E`, in order, for tests to look at.
+    #[cfg(test)]
+    pub(crate) fn take<E: 'static>(&self) -> Vec<E> {
+        let (taken, rest) = std::mem::take(&mut *self.events.lock().unwrap())
+            .into_iter()
+            .partition::<Vec<_>, _>(|e| e.type_id == TypeId::of::<E>());
+        *self.events.lock().unwrap() = rest;
+        taken
+            .into_iter()
+            .map(|e| *e.payload.downcast().unwrap())
+            .collect()
+    }
+}
+
+#[derive(Default)]
+pub struct EventBus {
+    /// Kept sorted by phase and then priority
+    listeners: Vec<Listener>,
+    queue: Vec<QueuedEvent>,
+}
+
+impl EventBus {
+    pub fn add_listener(&mut self, listener: Listener) {
+        // Insert after everything that goes before or alongside it, so ties
+        // are broken by the order listeners were added in
+        let index = self
+            .listeners
+            .partition_point(|l| (l.phase, l.priority) <= (listener.phase, listener.priority));
+        self.listeners.insert(index, listener);
+    }
+
+    /// Removes every listener with this name. This doesn't work from inside
+    /// a listener, since the listeners are taken out of the bus while they
+    /// run.
+    pub fn remove_listener(&mut self, name: &str) {
+        self.listeners.retain(|l| l.name != name);
+    }
+
+    pub fn emit<E: Send + 'static>(&mut self, event: E) {
+        self.queue.push(QueuedEvent::new(event));
+    }
+
+    /// Queues everything that was written to `writer`, in order.
+    pub fn extend(&mut self, writer: EventWriter) {
+        self.queue.extend(writer.events.into_inner().unwrap());
+    }
+
+    /// How many events are waiting to be delivered.
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+}
+
+impl GameState {
+    /// Queues an event for the listeners in this phase (if we're in the
+    /// middle of dispatching one) and all the later ones.
+    pub fn emit<E: Send + 'static>(&mut self, event: E) {
+        self.events.emit(event);
+    }
+
+    /// Listeners added by other listeners start listening from the next
+    /// phase.
+    pub fn add_listener(&mut self, listener: Listener) {
+        self.events.add_listener(listener);
+    }
+
+    /// Hands every queued event to this phase's listeners for its type,
+    /// along with any follow-up events they emit. Clears the queue once the
+    /// last phase is done.
+    pub fn dispatch_events(&mut self, phase: EventPhase) {
+        let mut listeners = std::mem::take(&mut self.events.listeners);
+        let mut queue = std::mem::take(&mut self.events.queue);
+
+        let start = listeners.partition_point(|l| l.phase < phase);
+        let end = listeners.partition_point(|l| l.phase <= phase);
+        let mut i = 0;
+        while i < queue.len() {
+            let event = &queue[i];
+            for listener in listeners[start..end]
+                .iter_mut()
+                .filter(|l| l.type_id == event.type_id)
+            {
+                (listener.run)(self, event.payload.as_ref());
+            }
+
+            // Anything the listeners emitted follows on from this event
+            let depth = event.depth + 1;
+            let follow_ups = std::mem::take(&mut self.events.queue);
+            if depth > MAX_FOLLOW_UP_DEPTH && !follow_ups.is_empty() {
+                error!(
+                    "Dropping {} follow-up events nested more than {} deep, listeners are probably emitting events in a loop",
+                    follow_ups.len(),
+                    MAX_FOLLOW_UP_DEPTH
+                );
+            } else {
+                queue.extend(follow_ups.into_iter().map(|e| QueuedEvent { depth, ..e }));
+            }
+            i += 1;
+        }
+
+        // Put back the listeners, along with any that were added while they
+        // were running
+        for listener in std::mem::replace(&mut self.events.listeners, listeners) {
+            self.events.add_listener(listener);
+        }
+        if phase != EventPhase::LAST {
+            self.events.queue = queue;
+        }
+    }
+}
@@ -7,47 +7,34 @@
  */
 
 use crate::update_thread::{GameState, GameStateEvent};
-use sdl2::keyboard::Scancode;
-use sdl2::mouse::RelativeMouseState;
 
-pub fn handle_keyboard(
-    game_state: &mut GameState,
-    scancodes: Vec<(sdl2::keyboard::Scancode, bool)>,
-    dt: f32,
-) {
-    let camera_movement = scancodes
-        .iter()
-        .fold(glam::Vec3::ZERO, |cm, (scancode, pressed)| {
-            if *pressed {
-                match scancode {
-                    Scancode::W => cm + glam::Vec3::Z,
-                    Scancode::S => cm - glam::Vec3::Z,
-                    Scancode::A => cm + glam::Vec3::X,
-                    Scancode::D => cm - glam::Vec3::X,
-                    Scancode::E => cm + glam::Vec3::Y,
-                    Scancode::F => cm - glam::Vec3::Y,
-                    _ => cm,
-                }
-            } else {
-                cm
-            }
-        });
+/// Flies the camera around with the movement and look axes.
+pub fn handle_camera_controls(game_state: &mut GameState, dt: f32) {
+    let input = &game_state.input;
+    let camera_movement = glam::vec3(
+        -input.axis("MoveRight"),
+        input.axis("MoveUp"),
+        input.axis("MoveForward"),
+    );
+    let look = glam::vec3(input.axis("LookY"), -input.axis("LookX"), 0.0);
 
-    game_state.move_camera_by_vector(camera_movement, dt);
-}
-
-pub fn handle_mouse(game_state: &mut GameState, mouse_state: &RelativeMouseState, dt: f32) {
-    let yo = mouse_state.y() as f32;
-    let xo = mouse_state.x() as f32;
-    game_state.rotate_camera(glam::vec3(yo, -xo, 0.0), dt);
+    // Don't mark the camera as changed (and send a whole new render state)
+    // when nobody's touching the controls
+    if camera_movement != glam::Vec3::ZERO {
+        game_state.move_camera_by_vector(camera_movement, dt);
+    }
+    if look != glam::Vec3::ZERO {
+        game_state.rotate_camera(look, dt);
+    }
 }
 
-pub fn handle_event(game_state: &mut GameState, event: GameStateEvent, dt: f32) {
+pub fn handle_event(game_state: &mut GameState, event: GameStateEvent) {
     match event {
-        GameStateEvent::FrameEvent(scancodes, mouse_state) => {
-            handle_keyboard(game_state, scancodes, dt);
-            handle_mouse(game_state, &mouse_state, dt);
+        GameStateEvent::FrameEvent(frame) => game_state.input.handle_frame(&frame),
+        // Anything else is up to whoever's listening for it
+        GameStateEvent::SDLEvent(event) => {
+            game_state.input.handle_sdl_event(&event);
+            game_state.emit(event);
         }
-        _ => {}
     }
 }
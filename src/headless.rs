@@ -0,0 +1,111 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Running the game without a window, a GL context, or even SDL: just the
+//! update thread and the resource manager, with a renderer that takes
+//! whatever it's sent and doesn't draw any of it. This is for running the
+//! simulation somewhere without a GPU, like CI (usually along with
+//! `--replay`, which stops the game once the recording runs out) or a
+//! server.
+
+use std::{
+    collections::HashMap,
+    sync::{atomic::AtomicBool, Arc},
+    time::Duration,
+};
+
+use crossbeam_channel::unbounded;
+
+use crate::{
+    dead_drop::DeadDrop,
+    entity::mesh_component::Model,
+    render_thread::RenderWorldState,
+    resource_manager::{LoadedTexture, ResourceManager},
+    update_thread::{spawn_update_thread, GameState},
+    CONFIG,
+};
+
+/// Stands in for `RendererState`. It keeps the latest render state and the
+/// loaded models around, same as the real renderer, so that the update
+/// thread and resource manager work exactly like they normally do, but
+/// never touches the GPU.
+pub struct NullRenderer {
+    pub render_world_state: Option<RenderWorldState>,
+    pub models: HashMap<String, Model>,
+    pub textures: HashMap<String, LoadedTexture>,
+    resource_manager: ResourceManager,
+}
+
+impl NullRenderer {
+    pub fn new(resource_manager: ResourceManager) -> Self {
+        Self {
+            render_world_state: None,
+            models: HashMap::new(),
+            textures: HashMap::new(),
+            resource_manager,
+        }
+    }
+
+    /// Picks up anything new from the update thread and resource manager.
+    pub fn frame(&mut self, rws_receiver: &DeadDrop<RenderWorldState>) {
+        if let Some(new_render_state) = rws_receiver.recv() {
+            match self.render_world_state.as_mut() {
+                Some(rws) => rws.merge(new_render_state),
+                None => self.render_world_state = Some(new_render_state),
+            }
+        }
+        while self
+            .resource_manager
+            .try_integrate_loaded_models_with(&mut self.models, |_| {})
+        {}
+        while self
+            .resource_manager
+            .try_integrate_loaded_textures_with(&mut self.textures, |texture| texture)
+        {}
+    }
+
+    pub fn render_loop(
+        &mut self,
+        rws_receiver: DeadDrop<RenderWorldState>,
+        running: Arc<AtomicBool>,
+    ) {
+        let interval = Duration::from_millis(CONFIG.load().performance.update_interval as u64);
+        while running.load(std::sync::atomic::Ordering::SeqCst) {
+            self.frame(&rws_receiver);
+            std::thread::sleep(interval);
+        }
+    }
+}
+
+/// Runs the game headless until the update loop stops, which only happens
+/// on its own at the end of a replay, or until `running` is cleared.
+pub fn run_headless(setup: impl FnOnce(&mut GameState) + Send + 'static, running: Arc<AtomicBool>) {
+    info!("Running headless");
+    let resource_manager = ResourceManager::new();
+    let render_world_state = DeadDrop::default();
+    // Nothing sends input, and nothing listens for controller requests
+    let (_event_sender, event_receiver) = unbounded();
+    let (controller_sender, _) = unbounded();
+
+    let update_thread = spawn_update_thread(
+        resource_manager.clone(),
+        render_world_state.clone(),
+        event_receiver,
+        controller_sender,
+        (
+            CONFIG.load().graphics.window_width as u32,
+            CONFIG.load().graphics.window_height as u32,
+        ),
+        running.clone(),
+        setup,
+    )
+    .expect("Couldn't start update thread");
+
+    NullRenderer::new(resource_manager).render_loop(render_world_state, running);
+    let _ = update_thread.join();
+}
@@ -0,0 +1,407 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Named actions and axes, so that game code asks "is the player pressing
+//! Use?" or "how far forward are they moving?" instead of looking at raw
+//! scancodes, and players can rebind everything in the `[controls]` section
+//! of config.toml.
+//!
+//! Actions are buttons: each one is bound to any number of keys, mouse
+//! buttons, and gamepad buttons, and is held if any of them are. Axes are
+//! values built up from pairs of buttons or analog inputs (mouse movement,
+//! gamepad sticks and triggers), added together.
+//!
+//! The update loop feeds everything it hears from the event loop into the
+//! `InputState` as it comes in, and each fixed step sees everything that
+//! happened since the last one. Systems see it through
+//! `SystemContext::input`.
+//!
+//! Any number of gamepads can be plugged in (see `controllers`), and they
+//! all count: a button is held if it's held on any of them, and an axis is
+//! whichever of them is pushed furthest.
+
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Mutex,
+};
+
+use sdl2::{controller, event::Event, keyboard::Scancode, mouse::MouseButton};
+
+use crate::{
+    controllers::ControllerRequest,
+    utils::config::{AxisBindingConfig, ControlConfig},
+};
+
+/// Anything that can be pressed and released.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum InputButton {
+    Key(Scancode),
+    Mouse(MouseButton),
+    Pad(controller::Button),
+}
+
+impl InputButton {
+    /// Parses bindings like `"Key:W"`, `"Key:Left Ctrl"` (SDL's scancode
+    /// names), `"Mouse:Left"`, or `"Pad:leftshoulder"` (SDL's game
+    /// controller button names).
+    pub fn parse(binding: &str) -> Option<Self> {
+        let (device, name) = binding.split_once(':')?;
+        match device {
+            "Key" => Scancode::from_name(name).map(InputButton::Key),
+            "Mouse" => match name {
+                "Left" => Some(MouseButton::Left),
+                "Middle" => Some(MouseButton::Middle),
+                "Right" => Some(MouseButton::Right),
+                "X1" => Some(MouseButton::X1),
+                "X2" => Some(MouseButton::X2),
+                _ => None,
+            }
+            .map(InputButton::Mouse),
+            "Pad" => controller::Button::from_string(name).map(InputButton::Pad),
+            _ => None,
+        }
+    }
+}
+
+/// Anything that has a value instead of just being up or down.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum InputAxis {
+    /// Relative mouse motion since the last step, in pixels
+    MouseX,
+    MouseY,
+    /// Gamepad axes, from -1.0 to 1.0 (0.0 to 1.0 for triggers), after
+    /// deadzones
+    Pad(controller::Axis),
+}
+
+impl InputAxis {
+    /// Parses `"Mouse:X"`, `"Mouse:Y"`, or `"Pad:leftx"` and so on (SDL's
+    /// game controller axis names).
+    pub fn parse(binding: &str) -> Option<Self> {
+        match binding.split_once(':')? {
+            ("Mouse", "X") => Some(InputAxis::MouseX),
+            ("Mouse", "Y") => Some(InputAxis::MouseY),
+            ("Pad", name) => controller::Axis::from_string(name).map(InputAxis::Pad),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum AxisBinding {
+    Buttons {
+        positive: InputButton,
+        negative: InputButton,
+    },
+    Analog {
+        axis: InputAxis,
+        scale: f32,
+    },
+}
+
+impl AxisBinding {
+    pub fn parse(config: &AxisBindingConfig) -> Option<Self> {
+        match config {
+            AxisBindingConfig::Buttons { positive, negative } => Some(AxisBinding::Buttons {
+                positive: InputButton::parse(positive)?,
+                negative: InputButton::parse(negative)?,
+            }),
+            AxisBindingConfig::Analog {
+                axis,
+                invert,
+                scale,
+            } => Some(AxisBinding::Analog {
+                axis: InputAxis::parse(axis)?,
+                scale: if *invert { -scale } else { *scale },
+            }),
+        }
+    }
+}
+
+/// The keyboard and mouse as of one frame of the event loop, sent while the
+/// game has the mouse.
+#[derive(Clone, Debug, Default)]
+pub struct FrameInput {
+    pub keys: Vec<Scancode>,
+    pub mouse_buttons: Vec<MouseButton>,
+    /// Relative motion since the last frame
+    pub mouse_motion: (i32, i32),
+}
+
+/// One connected gamepad.
+#[derive(Default)]
+struct PadState {
+    buttons: HashSet<controller::Button>,
+    /// Raw values, from -1.0 to 1.0
+    axes: HashMap<controller::Axis, f32>,
+}
+
+impl PadState {
+    fn raw_axis(&self, axis: controller::Axis) -> f32 {
+        self.axes.get(&axis).copied().unwrap_or(0.0)
+    }
+}
+
+/// What every input device is doing, as of this step, and
+/// what the player has bound to what.
+#[derive(Default)]
+pub struct InputState {
+    actions: HashMap<String, Vec<InputButton>>,
+    axes: HashMap<String, Vec<AxisBinding>>,
+    /// Keyboard keys and mouse buttons
+    held: HashSet<InputButton>,
+    /// Keyed by joystick instance ID
+    pads: HashMap<u32, PadState>,
+    /// Everything that was held as of the last update, to tell what's just
+    /// been pressed or released
+    previously_held: HashSet<InputButton>,
+    mouse_motion: glam::Vec2,
+    stick_deadzone: f32,
+    trigger_deadzone: f32,
+    /// Waiting for the update loop to send them to the event loop
+    controller_requests: Mutex<Vec<ControllerRequest>>,
+}
+
+impl InputState {
+    /// Sets up the bindings in the `[controls]` section of the config.
+    /// Bindings that don't make sense are logged and left out.
+    pub fn from_config(controls: &ControlConfig) -> Self {
+        let mut input = Self::default();
+        input.configure(controls);
+        input
+    }
+
+    /// Replaces every binding and deadzone with the ones in `controls`,
+    /// including anything that was bound in code. Whatever's held stays
+    /// held.
+    pub fn configure(&mut self, controls: &ControlConfig) {
+        self.stick_deadzone = controls.stick_deadzone;
+        self.trigger_deadzone = controls.trigger_deadzone;
+        self.actions.clear();
+        self.axes.clear();
+        for (action, bindings) in controls.actions.iter() {
+            let buttons = bindings
+                .iter()
+                .filter_map(|b| {
+                    let button = InputButton::parse(b);
+                    if button.is_none() {
+                        error!("Unknown button {:?} bound to action {}", b, action);
+                    }
+                    button
+                })
+                .collect();
+            self.actions.insert(action.clone(), buttons);
+        }
+        for (axis, bindings) in controls.axes.iter() {
+            let bindings = bindings
+                .iter()
+                .filter_map(|b| {
+                    let binding = AxisBinding::parse(b);
+                    if binding.is_none() {
+                        error!("Invalid binding for axis {}", axis);
+                    }
+                    binding
+                })
+                .collect();
+            self.axes.insert(axis.clone(), bindings);
+        }
+    }
+
+    /// Binds (or rebinds) an action.
+    pub fn bind_action(&mut self, action: &str, buttons: Vec<InputButton>) {
+        self.actions.insert(action.to_string(), buttons);
+    }
+
+    /// Binds (or rebinds) an axis.
+    pub fn bind_axis(&mut self, axis: &str, bindings: Vec<AxisBinding>) {
+        self.axes.insert(axis.to_string(), bindings);
+    }
+
+    /// Called once a step has seen the input, so the next one starts fresh:
+    /// whatever's held now is what was held last time, and the mouse hasn't
+    /// moved yet.
+    pub fn finish_step(&mut self) {
+        self.previously_held = self.held.clone();
+        self.previously_held.extend(
+            self.pads
+                .values()
+                .flat_map(|pad| pad.buttons.iter().map(|b| InputButton::Pad(*b))),
+        );
+        self.mouse_motion = glam::Vec2::ZERO;
+    }
+
+    /// Takes in the keyboard and mouse state the event loop sends every
+    /// frame while the game has the mouse.
+    pub fn handle_frame(&mut self, frame: &FrameInput) {
+        self.held.clear();
+        self.held
+            .extend(frame.keys.iter().map(|k| InputButton::Key(*k)));
+        self.held
+            .extend(frame.mouse_buttons.iter().map(|b| InputButton::Mouse(*b)));
+        let (x, y) = frame.mouse_motion;
+        self.mouse_motion += glam::vec2(x as f32, y as f32);
+    }
+
+    /// Takes in gamepad input (including them being plugged in and
+    /// unplugged), and releases from the keyboard and mouse.
+    /// Keyboard and mouse presses only count while the game has the mouse,
+    /// so they come from `handle_frame`, but releases always count, so
+    /// nothing gets stuck down when the mouse is let go.
+    pub fn handle_sdl_event(&mut self, event: &Event) {
+        match event {
+            Event::KeyUp {
+                scancode: Some(scancode),
+                ..
+            } => {
+                self.held.remove(&InputButton::Key(*scancode));
+            }
+            Event::MouseButtonUp { mouse_btn, .. } => {
+                self.held.remove(&InputButton::Mouse(*mouse_btn));
+            }
+            Event::ControllerDeviceAdded { .. } => {
+                // Added events carry a device index rather than the instance
+                // ID everything else uses, so the pad gets tracked once it
+                // sends something
+            }
+            Event::ControllerDeviceRemoved { which, .. } => {
+                self.pads.remove(which);
+            }
+            Event::ControllerButtonDown { which, button, .. } => {
+                self.pads.entry(*which).or_default().buttons.insert(*button);
+            }
+            Event::ControllerButtonUp { which, button, .. } => {
+                self.pads.entry(*which).or_default().buttons.remove(button);
+            }
+            Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => {
+                self.pads
+                    .entry(*which)
+                    .or_default()
+                    .axes
+                    .insert(*axis, (*value as f32 / i16::MAX as f32).max(-1.0));
+            }
+            _ => {}
+        }
+    }
+
+    pub fn button_held(&self, button: InputButton) -> bool {
+        match button {
+            InputButton::Pad(button) => self.pads.values().any(|pad| pad.buttons.contains(&button)),
+            _ => self.held.contains(&button),
+        }
+    }
+
+    pub fn axis_value(&self, axis: InputAxis) -> f32 {
+        match axis {
+            InputAxis::MouseX => self.mouse_motion.x,
+            InputAxis::MouseY => self.mouse_motion.y,
+            InputAxis::Pad(axis) => self
+                .pads
+                .values()
+                .map(|pad| self.pad_axis(pad, axis))
+                .max_by(|a, b| a.abs().total_cmp(&b.abs()))
+                .unwrap_or(0.0),
+        }
+    }
+
+    /// Applies deadzones to one of the pad's axes. Sticks get a radial
+    /// deadzone, looking at both of their axes together, so pushing a stick
+    /// mostly along one axis doesn't snap the other one to 0. Whatever's
+    /// outside the deadzone gets stretched back out to the full range.
+    fn pad_axis(&self, pad: &PadState, axis: controller::Axis) -> f32 {
+        use controller::Axis::*;
+        let stick = match axis {
+            LeftX | LeftY => (LeftX, LeftY),
+            RightX | RightY => (RightX, RightY),
+            TriggerLeft | TriggerRight => {
+                return rescale_past_deadzone(pad.raw_axis(axis), self.trigger_deadzone);
+            }
+        };
+        let stick = glam::vec2(pad.raw_axis(stick.0), pad.raw_axis(stick.1));
+        let length = stick.length();
+        if length == 0.0 {
+            return 0.0;
+        }
+        let scaled = stick * rescale_past_deadzone(length, self.stick_deadzone) / length;
+        match axis {
+            LeftX | RightX => scaled.x,
+            _ => scaled.y,
+        }
+    }
+
+    fn any_held(&self, action: &str, held: impl Fn(InputButton) -> bool) -> bool {
+        self.actions
+            .get(action)
+            .is_some_and(|buttons| buttons.iter().any(|b| held(*b)))
+    }
+
+    /// Whether any of the action's buttons are down.
+    pub fn held(&self, action: &str) -> bool {
+        self.any_held(action, |b| self.button_held(b))
+    }
+
+    /// Whether any of the action's buttons were down as of the last step.
+    fn was_held(&self, action: &str) -> bool {
+        self.any_held(action, |b| self.previously_held.contains(&b))
+    }
+
+    /// Whether the action went from not held to held since the last step.
+    pub fn pressed(&self, action: &str) -> bool {
+        self.held(action) && !self.was_held(action)
+    }
+
+    /// Whether the action went from held to not held since the last step.
+    pub fn released(&self, action: &str) -> bool {
+        !self.held(action) && self.was_held(action)
+    }
+
+    /// Rumbles every connected gamepad (that can rumble) for a while.
+    /// Strengths are from 0.0 to 1.0. Can be called from systems.
+    pub fn rumble(&self, low_frequency: f32, high_frequency: f32, duration_ms: u32) {
+        self.controller_requests
+            .lock()
+            .unwrap()
+            .push(ControllerRequest::Rumble {
+                low_frequency,
+                high_frequency,
+                duration_ms,
+            });
+    }
+
+    pub fn take_controller_requests(&self) -> Vec<ControllerRequest> {
+        std::mem::take(&mut *self.controller_requests.lock().unwrap())
+    }
+
+    /// The sum of everything bound to the axis. Unbound axes are always 0.
+    pub fn axis(&self, axis: &str) -> f32 {
+        let Some(bindings) = self.axes.get(axis) else {
+            return 0.0;
+        };
+        bindings
+            .iter()
+            .map(|binding| match *binding {
+                AxisBinding::Buttons { positive, negative } => {
+                    self.button_held(positive) as i32 as f32
+                        - self.button_held(negative) as i32 as f32
+                }
+                AxisBinding::Analog { axis, scale } => self.axis_value(axis) * scale,
+            })
+            .sum()
+    }
+}
+
+/// Zeroes out anything within the deadzone, and stretches the rest so it
+/// still goes all the way from 0.0 to 1.0.
+fn rescale_past_deadzone(value: f32, deadzone: f32) -> f32 {
+    if value.abs() <= deadzone {
+        0.0
+    } else {
+        value.signum() * ((value.abs() - deadzone) / (1.0 - deadzone)).min(1.0)
+    }
+}
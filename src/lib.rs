@@ -0,0 +1,84 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Embryo is a library: the ECS, `GameState`, the resource manager, the
+//! renderer and the config all live here, and `App` puts them together into
+//! a running game. The `embryo` binary is just `App` pointed at `./data`; see
+//! `examples/demo.rs` for adding behavior of your own on top.
+
+// So that `::embryo` paths in derived code work in here too
+extern crate self as embryo;
+
+extern crate bytes;
+extern crate gl;
+extern crate glam;
+extern crate gltf;
+extern crate rayon;
+extern crate rmp;
+extern crate sdl2;
+#[macro_use]
+extern crate log;
+#[macro_use]
+extern crate project_gilgamesh_render_gl_derive as render_gl_derive;
+extern crate crossbeam_channel;
+extern crate freetype;
+
+use gl::Gl;
+use lazy_static::lazy_static;
+use std::ops::Deref;
+
+pub use app::App;
+pub use args::Args;
+pub use entity::EntitySystem;
+pub use resource_manager::ResourceManager;
+pub use scheduler::{System, SystemContext};
+pub use update_thread::GameState;
+pub use utils::config::GameConfig;
+
+pub mod app;
+pub mod args;
+pub mod commands;
+pub mod controllers;
+pub mod dead_drop;
+pub mod entity;
+pub mod event_bus;
+pub mod events;
+pub mod headless;
+pub mod input;
+pub mod prefab;
+pub mod render_gl;
+pub mod render_thread;
+pub mod replay;
+pub mod resource_manager;
+pub mod resources;
+pub mod scene;
+pub mod scheduler;
+pub mod serialization;
+pub mod systems;
+pub mod text;
+pub mod update_thread;
+pub mod utils;
+
+lazy_static! {
+    pub static ref CONFIG: utils::config::ConfigHandle =
+        utils::config::ConfigHandle::new(utils::config::read_config());
+}
+
+pub(crate) struct ShareablePtr<T>(*mut T);
+unsafe impl<T> Sync for ShareablePtr<T> {}
+unsafe impl<T> Send for ShareablePtr<T> {}
+
+pub struct SendableGl(Gl);
+unsafe impl Send for SendableGl {}
+
+impl Deref for SendableGl {
+    type Target = Gl;
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
@@ -0,0 +1,208 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Prefabs: named bundles of components with default values, so that things
+//! the world has lots of (lamps, crates, guards...) only have to be described
+//! once. Every `.toml` file in the prefab directory is a prefab named after
+//! the file, and looks like:
+//!
+//! ```toml
+//! # Start from everything in lamp.toml, then change some of it
+//! inherits = "lamp"
+//!
+//! [LightComponent]
+//! color = [20.0, 0.1, 0.1]
+//! ```
+//!
+//! Components are written the same way `save_scene` writes them, keyed by
+//! component ID, and only registered components can be used (see
+//! `ComponentRegistry`). Inheriting merges tables field by field, so a
+//! prefab only has to mention what it changes, and the same goes for scene
+//! entities that start from a prefab (see `scene.rs`) and the overrides
+//! passed to `GameState::spawn_prefab`.
+//!
+//! Prefabs can't refer to other entities (so no `HierarchyComponent`
+//! parents): parent the spawned entity yourself.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
+
+use serde::Deserialize;
+
+use crate::{
+    entity::Entity,
+    serialization::{SaveError, SavedEntity, SavedScene},
+    update_thread::GameState,
+    utils::merge,
+};
+
+#[derive(Debug)]
+pub enum PrefabError {
+    Io(String, std::io::Error),
+    Parse(String, String),
+    NotFound(String),
+    /// The chain of prefabs that ended up inheriting from itself
+    InheritanceCycle(Vec<String>),
+    Spawn(String, SaveError),
+}
+
+impl Display for PrefabError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PrefabError::Io(path, e) => write!(f, "{}: {}", path, e),
+            PrefabError::Parse(path, e) => write!(f, "{}: {}", path, e),
+            PrefabError::NotFound(name) => write!(f, "no prefab named \"{}\"", name),
+            PrefabError::InheritanceCycle(chain) => {
+                write!(f, "prefabs inherit in a circle: {}", chain.join(" -> "))
+            }
+            PrefabError::Spawn(name, e) => write!(f, "couldn't spawn prefab \"{}\": {}", name, e),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct Prefab {
+    pub inherits: Option<String>,
+    /// Component IDs to their (possibly partial) saved form
+    #[serde(flatten)]
+    pub components: toml::Table,
+}
+
+#[derive(Clone, Default)]
+pub struct PrefabLibrary {
+    prefabs: HashMap<String, Prefab>,
+}
+
+impl PrefabLibrary {
+    /// Loads every `.toml` file in `dir` as a prefab. Checks that everything
+    /// they inherit from exists and doesn't go in circles. A game doesn't
+    /// have to have any prefabs, so if `dir` doesn't exist, that's just an
+    /// empty library.
+    pub fn load_dir(dir: &str) -> Result<Self, PrefabError> {
+        let mut library = Self::default();
+        let entries = match std::fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                debug!("No prefab directory at {}", dir);
+                return Ok(library);
+            }
+            Err(e) => return Err(PrefabError::Io(dir.to_string(), e)),
+        };
+        for entry in entries {
+            let path = entry
+                .map_err(|e| PrefabError::Io(dir.to_string(), e))?
+                .path();
+            if path.extension() != Some("toml".as_ref()) {
+                continue;
+            }
+            let path_str = path.to_string_lossy().to_string();
+            let source =
+                std::fs::read_to_string(&path).map_err(|e| PrefabError::Io(path_str.clone(), e))?;
+            let prefab: Prefab = toml::from_str(&source)
+                .map_err(|e| PrefabError::Parse(path_str.clone(), e.to_string()))?;
+            let name = path
+                .file_stem()
+                .expect("Files with extensions have stems")
+                .to_string_lossy()
+                .to_string();
+            library.insert(name, prefab);
+        }
+
+        for name in library.prefabs.keys() {
+            library.resolve(name)?;
+        }
+        info!("Loaded {} prefabs from {}", library.prefabs.len(), dir);
+        Ok(library)
+    }
+
+    pub fn insert(&mut self, name: String, prefab: Prefab) {
+        self.prefabs.insert(name, prefab);
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Prefab> {
+        self.prefabs.get(name)
+    }
+
+    /// All of the prefab's components, with everything it inherits filled
+    /// in.
+    pub fn resolve(&self, name: &str) -> Result<toml::Table, PrefabError> {
+        // Walk up to the root of the inheritance chain, then apply everything
+        // back down from there so the most specific prefab wins
+        let mut chain: Vec<String> = vec![];
+        let mut next = Some(name);
+        while let Some(name) = next {
+            let cycle = chain.iter().any(|n| n == name);
+            chain.push(name.to_string());
+            if cycle {
+                return Err(PrefabError::InheritanceCycle(chain));
+            }
+            let prefab = self
+                .get(name)
+                .ok_or_else(|| PrefabError::NotFound(name.to_string()))?;
+            next = prefab.inherits.as_deref();
+        }
+
+        let mut components = toml::Table::new();
+        for name in chain.iter().rev() {
+            merge(&mut components, self.prefabs[name].components.clone());
+        }
+        Ok(components)
+    }
+}
+
+impl GameState {
+    /// Spawns a new entity from a prefab, with `overrides` (in the same
+    /// format as the prefab file) merged over the prefab's components.
+    pub fn spawn_prefab(
+        &mut self,
+        name: &str,
+        overrides: toml::Table,
+    ) -> Result<Entity, PrefabError> {
+        let mut components = self.prefabs.resolve(name)?;
+        merge(&mut components, overrides);
+
+        let scene = SavedScene {
+            camera: None,
+            entities: vec![SavedEntity {
+                id: 0,
+                components: components.into_iter().collect::<BTreeMap<_, _>>(),
+            }],
+        };
+        let entities = scene
+            .spawn(self)
+            .map_err(|e| PrefabError::Spawn(name.to_string(), e))?;
+        Ok(entities[0])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_directory_is_an_empty_library() {
+        let library = PrefabLibrary::load_dir("./data/no_prefabs_here").unwrap();
+        assert!(library.prefabs.is_empty());
+    }
+
+    #[test]
+    fn inherits_field_by_field() {
+        let library = PrefabLibrary::load_dir("./data/prefabs").unwrap();
+        let red_lamp = library.resolve("red_lamp").unwrap();
+        let light = red_lamp["LightComponent"].as_table().unwrap();
+        // Its own color, but everything else from lamp
+        assert_eq!(
+            light["color"],
+            toml::Value::try_from([20.2, 0.1, 0.1]).unwrap()
+        );
+        assert_eq!(light["kind"].as_str(), Some("Point"));
+        assert!(red_lamp.contains_key("TransformComponent"));
+    }
+}
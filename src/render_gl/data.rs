@@ -11,6 +11,11 @@ use gl::Gl;
 pub trait VertexAttribute {
     /// Initialize a vertex attribute containing this type at this location,
     /// with this stride and offset.
+    ///
+    /// # Safety
+    ///
+    /// A vertex array object has to be bound, with the buffer the attribute
+    /// reads from bound to `ARRAY_BUFFER`.
     unsafe fn vertex_attrib_pointer(gl: &Gl, stride: usize, location: usize, offset: usize);
 }
 
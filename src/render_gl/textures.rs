@@ -9,7 +9,6 @@
 use gl::Gl;
 use half::f16;
 
-use crate::utils;
 use std::{any::Any, marker::PhantomData};
 
 use super::objects::FramebufferAttachment;
@@ -76,8 +75,24 @@ impl ColorDepth for RGB8 {
     }
 }
 
+/// Straight 8-bit RGBA, which is what standalone textures are decoded to
 #[repr(transparent)]
-pub struct R16F(f16);
+#[derive(Copy, Clone)]
+pub struct RGBA8(pub u8);
+impl ColorDepth for RGBA8 {
+    fn get_gl_type() -> gl::types::GLenum {
+        gl::UNSIGNED_BYTE
+    }
+    fn get_pixel_format() -> gl::types::GLenum {
+        gl::RGBA
+    }
+    fn get_sized_internal_format() -> gl::types::GLenum {
+        gl::RGBA8
+    }
+}
+
+#[repr(transparent)]
+pub struct R16F(pub f16);
 impl ColorDepth for R16F {
     fn get_gl_type() -> gl::types::GLenum {
         gl::UNSIGNED_SHORT
@@ -90,7 +105,7 @@ impl ColorDepth for R16F {
     }
 }
 
-pub struct RGBA32F(f32);
+pub struct RGBA32F(pub f32);
 impl ColorDepth for RGBA32F {
     fn get_gl_type() -> gl::types::GLenum {
         gl::FLOAT
@@ -103,7 +118,7 @@ impl ColorDepth for RGBA32F {
     }
 }
 
-pub struct RGBA16F(f16);
+pub struct RGBA16F(pub f16);
 impl ColorDepth for RGBA16F {
     fn get_gl_type() -> gl::types::GLenum {
         gl::HALF_FLOAT
@@ -115,7 +130,7 @@ impl ColorDepth for RGBA16F {
         gl::RGBA16F
     }
 }
-pub struct DepthComponent24(u32);
+pub struct DepthComponent24(pub u32);
 impl ColorDepth for DepthComponent24 {
     fn get_gl_type() -> gl::types::GLenum {
         gl::UNSIGNED_INT
@@ -129,7 +144,7 @@ impl ColorDepth for DepthComponent24 {
 }
 
 #[derive(Clone)]
-pub struct Depth24Stencil8(u32);
+pub struct Depth24Stencil8(pub u32);
 impl ColorDepth for Depth24Stencil8 {
     fn get_gl_type() -> gl::types::GLenum {
         gl::UNSIGNED_INT_24_8
@@ -231,6 +246,7 @@ impl<T: ColorDepth> Texture<T> {
         }
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn update_texture(
         &self,
         bytes: &Vec<T>,
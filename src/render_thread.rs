@@ -1,57 +1,104 @@
 use crate::{
     dead_drop::DeadDrop,
     entity::{
-        light_component::LightComponent,
-        mesh_component::Model,
-        transform_component::{Transform, TransformComponent},
-        Entity, EntityID,
+        light_component::LightComponent, mesh_component::Model,
+        transform_component::TransformComponent, Entity, EntityID,
     },
     render_gl::{
-        data::{Cvec3, InstanceTransformVertex, VertexPos, VertexTex},
-        objects::{
-            Buffer, BufferObject, FramebufferObject, Renderbuffer, VertexArray, VertexArrayObject,
-        },
-        shaders::{self, Program},
-        textures::{
-            AbstractTexture, Depth24Stencil8, DepthComponent24, Texture, TextureParameters, R16F,
-            RGBA16F,
-        },
+        data::{Cvec3, InstanceTransformVertex, VertexPos},
+        objects::{Buffer, BufferObject, FramebufferObject, VertexArray, VertexArrayObject},
+        shaders::Program,
+        textures::{Depth24Stencil8, Texture, TextureParameters, R16F, RGBA16F, RGBA8},
     },
     resource_manager::ResourceManager,
-    systems,
     text::FontRenderer,
-    update_thread::GameStateEvent,
-    utils, CONFIG,
+    utils::{self, config::GameConfig},
+    CONFIG,
 };
-use crossbeam_channel::{unbounded, Receiver, Sender};
 use gl::Gl;
-use rayon::{iter::IntoParallelRefIterator, slice::ParallelSliceMut};
 use std::{
-    any::Any,
     collections::HashMap,
-    ffi::{CStr, CString},
-    sync::{atomic::AtomicBool, Arc, Mutex, RwLock},
-    time::Duration,
+    ffi::CString,
+    sync::{atomic::AtomicBool, Arc},
 };
 
 use crate::SendableGl;
-use bytes::{BufMut, Bytes, BytesMut};
 use glam::Vec4Swizzles;
-use sdl2::{event::Event, video::GLContext};
 
 pub struct RenderWorldState {
     pub active_camera: Option<RenderCameraState>,
-    pub entity_generations: HashMap<EntityID, usize>,
+    pub entity_generations: Vec<usize>,
     pub lights: Vec<ShaderLight>,
+    /// When sent from the update thread, only the world transforms that
+    /// changed since the last state was sent. See `merge`.
     pub entity_transforms: HashMap<EntityID, glam::Mat4>,
+    /// Where everything that moved since the last render state was as of
+    /// that state. Everything else is just drawn where it is.
+    pub previous_transforms: HashMap<EntityID, glam::Mat4>,
+    /// How far between the last update step and the next one we were when
+    /// this was sent, from 0.0 to 1.0. Things get drawn that far between
+    /// their previous and current transforms, so movement looks smooth no
+    /// matter how the render rate lines up with the update rate.
+    pub alpha: f32,
+    /// How long an update step is, in milliseconds
+    pub step: f32,
+}
+
+impl RenderWorldState {
+    /// Applies a newer state on top of this one: everything is replaced,
+    /// except for transforms, which only the changed ones get sent for.
+    pub fn merge(&mut self, newer: RenderWorldState) {
+        self.active_camera = newer.active_camera;
+        self.entity_generations = newer.entity_generations;
+        self.lights = newer.lights;
+        self.entity_transforms.extend(newer.entity_transforms);
+        self.previous_transforms = newer.previous_transforms;
+        self.alpha = newer.alpha;
+        self.step = newer.step;
+    }
+
+    /// Like `merge`, but for folding a newer state into one the renderer
+    /// hasn't picked up yet. The renderer never saw this one, so where things
+    /// were before it is still where they should be interpolated from.
+    pub fn merge_unsent(&mut self, newer: RenderWorldState) {
+        let mut previous_transforms = std::mem::take(&mut self.previous_transforms);
+        for (id, previous) in newer.previous_transforms.iter() {
+            previous_transforms.entry(*id).or_insert(*previous);
+        }
+        let previous_view = self.active_camera.as_ref().map(|c| c.previous_view);
+        self.merge(newer);
+        self.previous_transforms = previous_transforms;
+        if let (Some(camera), Some(previous_view)) = (self.active_camera.as_mut(), previous_view) {
+            camera.previous_view = previous_view;
+        }
+    }
+
+    /// Where the entity should be drawn, `alpha` of the way from where it
+    /// was before the last step to where it is now.
+    pub fn interpolated_transform(&self, e: Entity, alpha: f32) -> Option<glam::Mat4> {
+        let current =
+            utils::get_entity_transform(&self.entity_generations, &self.entity_transforms, e)?;
+        Some(match self.previous_transforms.get(&e.id) {
+            Some(previous) => utils::interpolate_transform(previous, current, alpha),
+            None => *current,
+        })
+    }
 }
 
 #[derive(Clone)]
 pub struct RenderCameraState {
     pub view: glam::Mat4,
+    /// The view as of the last render state, to interpolate from
+    pub previous_view: glam::Mat4,
     pub proj: glam::Mat4,
 }
 
+impl RenderCameraState {
+    pub fn interpolated_view(&self, alpha: f32) -> glam::Mat4 {
+        utils::interpolate_transform(&self.previous_view, &self.view, alpha)
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub enum Shaders {
     Default,
@@ -72,11 +119,18 @@ pub struct RendererState {
     gl: Gl,
 
     pub render_world_state: RenderWorldState,
+    /// How far between the last two update steps to draw this frame
+    pub alpha: f32,
     pub resource_manager: ResourceManager,
+    /// The config as of this frame. The render loop keeps it up to date
+    /// when the config is reloaded.
+    pub config: Arc<GameConfig>,
 
     pub viewport_size: (u32, u32),
 
     pub models: HashMap<String, Model>,
+    /// Standalone textures, by path
+    pub textures: HashMap<String, Texture<RGBA8>>,
 
     pub shader_programs: HashMap<Shaders, Program>,
 
@@ -110,18 +164,25 @@ impl RendererState {
             1,
         );
         let lib = freetype::Library::init().unwrap();
+        let config = CONFIG.load();
         RendererState {
             gl: gl.clone(),
             resource_manager,
             render_world_state: RenderWorldState {
                 active_camera: None,
-                entity_generations: HashMap::new(),
+                entity_generations: vec![],
                 lights: Vec::new(),
                 entity_transforms: HashMap::new(),
+                previous_transforms: HashMap::new(),
+                alpha: 1.0,
+                step: config.performance.update_interval as f32,
             },
+            config,
+            alpha: 1.0,
             viewport_size: (width, height),
             shader_programs: HashMap::new(),
             models: HashMap::new(),
+            textures: HashMap::new(),
             light_ubo: BufferObject::new(&gl, gl::UNIFORM_BUFFER, gl::STREAM_DRAW, 1),
             g_buffer: {
                 let mut fbo = FramebufferObject::new(&gl);
@@ -291,7 +352,6 @@ impl RendererState {
         &mut self,
 
         rws_receiver: DeadDrop<RenderWorldState>,
-        event_sender: Sender<GameStateEvent>,
         swap_buffers: impl Fn(),
 
         running: Arc<AtomicBool>,
@@ -301,8 +361,16 @@ impl RendererState {
         let mut dt;
         let mut avg_dt = 0.0;
         let mut avg_fps;
+        let mut state_received = std::time::Instant::now();
+        // Subscribe before looking at the config, so no reload gets missed
+        let config_changes = CONFIG.subscribe();
+        self.config = CONFIG.load();
 
         while running.load(std::sync::atomic::Ordering::SeqCst) {
+            if let Some(config) = config_changes.try_iter().last() {
+                self.config = config;
+            }
+
             // Track time
             let time = start_time.elapsed().as_millis();
 
@@ -313,11 +381,20 @@ impl RendererState {
             avg_fps = 1000.0 / avg_dt;
 
             if let Some(new_render_state) = rws_receiver.recv() {
-                self.render_world_state = new_render_state;
+                self.render_world_state.merge(new_render_state);
+                state_received = std::time::Instant::now();
             }
+            // Keep moving things along between states, up until where they
+            // were as of the latest one
+            let since_received = state_received.elapsed().as_secs_f32() * 1000.0;
+            self.alpha = (self.render_world_state.alpha
+                + since_received / self.render_world_state.step)
+                .min(1.0);
 
             self.resource_manager
                 .try_integrate_loaded_models(&mut self.models, &self.gl);
+            self.resource_manager
+                .try_integrate_loaded_textures(&mut self.textures, &self.gl);
 
             // Render world to gbuffer
             self.render_to_g();
@@ -326,7 +403,7 @@ impl RendererState {
             self.render_g_to_hdr();
 
             // Render HDR buffer to screen with tone mapping, gamma correction, and auto exposure
-            self.render_hdr_to_sdr(avg_dt, dt as f32);
+            self.render_hdr_to_sdr(avg_dt);
 
             self.ui_font.render_lines(
                 format!(
@@ -370,20 +447,20 @@ impl RendererState {
             program.set_used();
 
             // Prepare the shader's constant uniforms based on the camera and the lights.
-            camera_prepare_shader(program, camera);
+            camera_prepare_shader(program, camera, self.alpha);
 
             // Loop through each model and render all instances of it, in batches.
             let models = &mut self.models;
-            let egen = &self.render_world_state.entity_generations;
-            let etrans = &self.render_world_state.entity_transforms;
-            for (path, model) in models.iter_mut() {
+            let rws = &self.render_world_state;
+            let alpha = self.alpha;
+            for model in models.values_mut() {
                 // Create the list of transforms of all the instances of this model. We
                 // will pull from this for all batches
                 let new_transforms = model
                 .entities
                 .iter()
                 .map(|entity| {
-                    utils::get_entity_transform(egen, etrans, *entity)
+                    rws.interpolated_transform(*entity, alpha)
                         .expect("Tried to render model for an entity that either doesn't have a transform component, or has been recycled.")
                 })
                 .map(|mat| InstanceTransformVertex::new(mat.to_cols_array()))
@@ -392,8 +469,8 @@ impl RendererState {
                 // See how many batches we're gonna have to do
                 let batches = new_transforms
                     .len()
-                    .div_ceil(CONFIG.performance.max_batch_size);
-                let mbs = CONFIG.performance.max_batch_size as usize;
+                    .div_ceil(self.config.performance.max_batch_size);
+                let mbs = self.config.performance.max_batch_size;
 
                 for batch in 0..batches {
                     // Batch starts after the last batch (or at zero for the first)
@@ -489,7 +566,7 @@ impl RendererState {
 
                 let program = &self.shader_programs[&Shaders::SimpleProject];
                 program.set_used();
-                camera_prepare_shader(&program, camera);
+                camera_prepare_shader(&program, camera, self.alpha);
 
                 program.set_uniform_matrix_4fv(
                     &CString::new("model_matrix").unwrap(),
@@ -563,7 +640,7 @@ impl RendererState {
 
                 let program = &self.shader_programs[&Shaders::Light];
                 program.set_used();
-                camera_prepare_shader(&program, camera);
+                camera_prepare_shader(&program, camera, self.alpha);
 
                 program.set_uniform_3f(
                     &CString::new("cameraDirection").unwrap(),
@@ -653,7 +730,7 @@ impl RendererState {
 
     /// Renders the HDR buffer to the standard definition window framebuffer
     /// using tonemapping supplied by the tone mapping shader
-    pub fn render_hdr_to_sdr(&mut self, avg_dt: f32, lag: f32) {
+    pub fn render_hdr_to_sdr(&mut self, avg_dt: f32) {
         setup_viewport(&self.gl, self.viewport_size);
         clear_screen(&self.gl);
 
@@ -663,9 +740,9 @@ impl RendererState {
             .hdr_framebuffer
             .get_attachment_mut::<Texture<RGBA16F>>(0);
 
-        let min_log_luminance = -8.0f32;
-        let max_log_luminance = 3.5f32;
-        let tau = 1.1f32;
+        let min_log_luminance = self.config.graphics.min_log_luminence;
+        let max_log_luminance = self.config.graphics.max_log_luminence;
+        let tau = self.config.graphics.auto_exposure_speed_factor;
         let time_coefficient = (1.0 - (-(1000.0 / avg_dt) * tau).exp()).clamp(0.0, 1.0);
 
         // First, we need to get the average luminance of the HDR buffer.
@@ -776,7 +853,7 @@ impl ShaderLight {
                 - 4.0
                     * self.quadratic_attenuation
                     * (self.constant_attenuation
-                        - brightest_color * CONFIG.graphics.attenuation_cutoff))
+                        - brightest_color * CONFIG.load().graphics.attenuation_cutoff))
                 .sqrt())
             / (2.0 * self.quadratic_attenuation);
         glam::Mat4::from_scale_rotation_translation(
@@ -893,10 +970,10 @@ pub fn setup_viewport(gl: &Gl, (w, h): (u32, u32)) {
     }
 }
 
-pub fn camera_prepare_shader(program: &Program, camera: &RenderCameraState) {
+pub fn camera_prepare_shader(program: &Program, camera: &RenderCameraState, alpha: f32) {
     program.set_uniform_matrix_4fv(
         &CString::new("view_matrix").unwrap(),
-        &camera.view.to_cols_array(),
+        &camera.interpolated_view(alpha).to_cols_array(),
     );
     program.set_uniform_matrix_4fv(
         &CString::new("projection_matrix").unwrap(),
@@ -0,0 +1,561 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Recording input and playing it back, so that bugs can be reproduced
+//! exactly, and so that what the world looks like after a replay can be
+//! compared against what it looked like after the recording.
+//!
+//! Everything the update loop hears from the event loop comes through
+//! `GameStateEvent`s, so with `--record <file>`, every update's events get
+//! written down along with the update's number (its tick) and how long it
+//! was. With `--replay <file>`, the update loop ignores the event loop and
+//! reads them back instead, taking exactly the same steps as when they were
+//! recorded, as fast as it can. The RNG is seeded from the recording too.
+//!
+//! When a recording finishes, the world is saved next to it, as
+//! `<file>.recorded.toml`, and when a replay finishes, as
+//! `<file>.replayed.toml`, ready to be diffed.
+//!
+//! Only input gets recorded: window events and the like aren't, since
+//! they'd be meaningless played back anyway.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+use rand::{rngs::StdRng, SeedableRng};
+use sdl2::{
+    controller,
+    event::Event,
+    keyboard::{Keycode, Mod, Scancode},
+    mouse::MouseButton,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    input::FrameInput,
+    update_thread::{GameState, GameStateEvent},
+    utils::config::GameConfig,
+};
+
+/// Bumped whenever what gets recorded changes, so old recordings fail
+/// loudly instead of replaying differently.
+const REPLAY_VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum ReplayError {
+    Io(std::io::Error),
+    Encode(rmp_serde::encode::Error),
+    Decode(rmp_serde::decode::Error),
+    WrongVersion(u32),
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplayError::Io(e) => write!(f, "{}", e),
+            ReplayError::Encode(e) => write!(f, "couldn't encode recording: {}", e),
+            ReplayError::Decode(e) => write!(f, "couldn't decode recording: {}", e),
+            ReplayError::WrongVersion(v) => write!(
+                f,
+                "recording is version {}, but this build can only replay version {}",
+                v, REPLAY_VERSION
+            ),
+        }
+    }
+}
+
+impl From<std::io::Error> for ReplayError {
+    fn from(e: std::io::Error) -> Self {
+        ReplayError::Io(e)
+    }
+}
+
+impl From<rmp_serde::encode::Error> for ReplayError {
+    fn from(e: rmp_serde::encode::Error) -> Self {
+        ReplayError::Encode(e)
+    }
+}
+
+impl From<rmp_serde::decode::Error> for ReplayError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        ReplayError::Decode(e)
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct ReplayHeader {
+    version: u32,
+    seed: u64,
+    timing: ReplayTiming,
+}
+
+/// The settings that decide how updates get split into fixed steps. A replay
+/// that used different ones would step differently and turn out differently,
+/// so these are saved with the recording, and held where they are (whatever
+/// the config says) for as long as it's recording or replaying.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplayTiming {
+    pub update_interval: usize,
+    pub max_catch_up_steps: usize,
+}
+
+impl ReplayTiming {
+    pub fn from_config(config: &GameConfig) -> Self {
+        Self {
+            update_interval: config.performance.update_interval,
+            max_catch_up_steps: config.performance.max_catch_up_steps,
+        }
+    }
+}
+
+/// A `GameStateEvent` in a form that can be written to a file. SDL's
+/// timestamps and window IDs are dropped.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub enum RecordedEvent {
+    Frame {
+        keys: Vec<i32>,
+        mouse_buttons: Vec<u8>,
+        mouse_motion: (i32, i32),
+    },
+    KeyDown {
+        scancode: i32,
+        keymod: u16,
+        repeat: bool,
+    },
+    KeyUp {
+        scancode: i32,
+        keymod: u16,
+    },
+    MouseButtonDown {
+        button: u8,
+        clicks: u8,
+        x: i32,
+        y: i32,
+    },
+    MouseButtonUp {
+        button: u8,
+        clicks: u8,
+        x: i32,
+        y: i32,
+    },
+    ControllerButtonDown {
+        which: u32,
+        button: String,
+    },
+    ControllerButtonUp {
+        which: u32,
+        button: String,
+    },
+    ControllerAxisMotion {
+        which: u32,
+        axis: String,
+        value: i16,
+    },
+    ControllerDeviceRemoved {
+        which: u32,
+    },
+}
+
+impl RecordedEvent {
+    /// Whatever's worth recording about the event, if anything.
+    pub fn from_event(event: &GameStateEvent) -> Option<Self> {
+        let event = match event {
+            GameStateEvent::FrameEvent(frame) => {
+                return Some(RecordedEvent::Frame {
+                    keys: frame.keys.iter().map(|k| *k as i32).collect(),
+                    mouse_buttons: frame.mouse_buttons.iter().map(|b| *b as u8).collect(),
+                    mouse_motion: frame.mouse_motion,
+                })
+            }
+            GameStateEvent::SDLEvent(event) => event,
+        };
+        Some(match *event {
+            Event::KeyDown {
+                scancode: Some(scancode),
+                keymod,
+                repeat,
+                ..
+            } => RecordedEvent::KeyDown {
+                scancode: scancode as i32,
+                keymod: keymod.bits(),
+                repeat,
+            },
+            Event::KeyUp {
+                scancode: Some(scancode),
+                keymod,
+                ..
+            } => RecordedEvent::KeyUp {
+                scancode: scancode as i32,
+                keymod: keymod.bits(),
+            },
+            Event::MouseButtonDown {
+                mouse_btn,
+                clicks,
+                x,
+                y,
+                ..
+            } => RecordedEvent::MouseButtonDown {
+                button: mouse_btn as u8,
+                clicks,
+                x,
+                y,
+            },
+            Event::MouseButtonUp {
+                mouse_btn,
+                clicks,
+                x,
+                y,
+                ..
+            } => RecordedEvent::MouseButtonUp {
+                button: mouse_btn as u8,
+                clicks,
+                x,
+                y,
+            },
+            Event::ControllerButtonDown { which, button, .. } => {
+                RecordedEvent::ControllerButtonDown {
+                    which,
+                    button: button.string(),
+                }
+            }
+            Event::ControllerButtonUp { which, button, .. } => RecordedEvent::ControllerButtonUp {
+                which,
+                button: button.string(),
+            },
+            Event::ControllerAxisMotion {
+                which, axis, value, ..
+            } => RecordedEvent::ControllerAxisMotion {
+                which,
+                axis: axis.string(),
+                value,
+            },
+            Event::ControllerDeviceRemoved { which, .. } => {
+                RecordedEvent::ControllerDeviceRemoved { which }
+            }
+            _ => return None,
+        })
+    }
+
+    /// Turns it back into the event it was recorded from, or as close as we
+    /// can get. `None` if it names a key or button this build doesn't know.
+    pub fn to_event(&self) -> Option<GameStateEvent> {
+        let scancode = |s: i32| Scancode::from_i32(s);
+        let event = match self {
+            RecordedEvent::Frame {
+                keys,
+                mouse_buttons,
+                mouse_motion,
+            } => {
+                return Some(GameStateEvent::FrameEvent(FrameInput {
+                    keys: keys.iter().filter_map(|k| scancode(*k)).collect(),
+                    mouse_buttons: mouse_buttons
+                        .iter()
+                        .map(|b| MouseButton::from_ll(*b))
+                        .collect(),
+                    mouse_motion: *mouse_motion,
+                }))
+            }
+            RecordedEvent::KeyDown {
+                scancode: s,
+                keymod,
+                repeat,
+            } => {
+                let scancode = scancode(*s)?;
+                Event::KeyDown {
+                    timestamp: 0,
+                    window_id: 0,
+                    keycode: Keycode::from_scancode(scancode),
+                    scancode: Some(scancode),
+                    keymod: Mod::from_bits_truncate(*keymod),
+                    repeat: *repeat,
+                }
+            }
+            RecordedEvent::KeyUp {
+                scancode: s,
+                keymod,
+            } => {
+                let scancode = scancode(*s)?;
+                Event::KeyUp {
+                    timestamp: 0,
+                    window_id: 0,
+                    keycode: Keycode::from_scancode(scancode),
+                    scancode: Some(scancode),
+                    keymod: Mod::from_bits_truncate(*keymod),
+                    repeat: false,
+                }
+            }
+            RecordedEvent::MouseButtonDown {
+                button,
+                clicks,
+                x,
+                y,
+            } => Event::MouseButtonDown {
+                timestamp: 0,
+                window_id: 0,
+                which: 0,
+                mouse_btn: MouseButton::from_ll(*button),
+                clicks: *clicks,
+                x: *x,
+                y: *y,
+            },
+            RecordedEvent::MouseButtonUp {
+                button,
+                clicks,
+                x,
+                y,
+            } => Event::MouseButtonUp {
+                timestamp: 0,
+                window_id: 0,
+                which: 0,
+                mouse_btn: MouseButton::from_ll(*button),
+                clicks: *clicks,
+                x: *x,
+                y: *y,
+            },
+            RecordedEvent::ControllerButtonDown { which, button } => Event::ControllerButtonDown {
+                timestamp: 0,
+                which: *which,
+                button: controller::Button::from_string(button)?,
+            },
+            RecordedEvent::ControllerButtonUp { which, button } => Event::ControllerButtonUp {
+                timestamp: 0,
+                which: *which,
+                button: controller::Button::from_string(button)?,
+            },
+            RecordedEvent::ControllerAxisMotion { which, axis, value } => {
+                Event::ControllerAxisMotion {
+                    timestamp: 0,
+                    which: *which,
+                    axis: controller::Axis::from_string(axis)?,
+                    value: *value,
+                }
+            }
+            RecordedEvent::ControllerDeviceRemoved { which } => Event::ControllerDeviceRemoved {
+                timestamp: 0,
+                which: *which,
+            },
+        };
+        Some(GameStateEvent::SDLEvent(event))
+    }
+}
+
+/// One update's worth of input.
+#[derive(Serialize, Deserialize)]
+pub struct RecordedTick {
+    pub tick: u64,
+    /// Milliseconds since the last update
+    pub dt: f32,
+    pub events: Vec<RecordedEvent>,
+}
+
+pub struct Recorder {
+    path: PathBuf,
+    timing: ReplayTiming,
+    writer: BufWriter<File>,
+}
+
+impl Recorder {
+    pub fn create(
+        path: impl AsRef<Path>,
+        seed: u64,
+        timing: ReplayTiming,
+    ) -> Result<Self, ReplayError> {
+        let mut writer = BufWriter::new(File::create(path.as_ref())?);
+        rmp_serde::encode::write(
+            &mut writer,
+            &ReplayHeader {
+                version: REPLAY_VERSION,
+                seed,
+                timing,
+            },
+        )?;
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            timing,
+            writer,
+        })
+    }
+
+    pub fn record(&mut self, tick: &RecordedTick) -> Result<(), ReplayError> {
+        rmp_serde::encode::write(&mut self.writer, tick)?;
+        // The process can go away as soon as the window's closed, so don't
+        // leave anything sitting in the buffer
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+pub struct Player {
+    path: PathBuf,
+    seed: u64,
+    timing: ReplayTiming,
+    /// In reverse, so the next one can just be popped off
+    ticks: Vec<RecordedTick>,
+}
+
+impl Player {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, ReplayError> {
+        let bytes = std::fs::read(path.as_ref())?;
+        let mut rest = bytes.as_slice();
+        let header: ReplayHeader = rmp_serde::from_read(&mut rest)?;
+        if header.version != REPLAY_VERSION {
+            return Err(ReplayError::WrongVersion(header.version));
+        }
+        let mut ticks = vec![];
+        while !rest.is_empty() {
+            ticks.push(rmp_serde::from_read::<_, RecordedTick>(&mut rest)?);
+        }
+        ticks.reverse();
+        Ok(Self {
+            path: path.as_ref().to_path_buf(),
+            seed: header.seed,
+            timing: header.timing,
+            ticks,
+        })
+    }
+
+    pub fn next_tick(&mut self) -> Option<RecordedTick> {
+        self.ticks.pop()
+    }
+}
+
+/// Where the update loop gets its input from.
+#[derive(Default)]
+pub enum ReplayMode {
+    /// From the event loop, like normal
+    #[default]
+    Live,
+    /// From the event loop, writing it all down as it goes
+    Recording(Recorder),
+    /// From a recording, ignoring the event loop
+    Replaying(Player),
+}
+
+impl ReplayMode {
+    pub fn is_replaying(&self) -> bool {
+        matches!(self, ReplayMode::Replaying(_))
+    }
+
+    /// The step timing being held in place, if we're recording or replaying.
+    pub fn timing(&self) -> Option<ReplayTiming> {
+        match self {
+            ReplayMode::Live => None,
+            ReplayMode::Recording(Recorder { timing, .. }) => Some(*timing),
+            ReplayMode::Replaying(Player { timing, .. }) => Some(*timing),
+        }
+    }
+
+    /// Where to save the world once we're done.
+    fn final_state_path(&self) -> Option<PathBuf> {
+        match self {
+            ReplayMode::Live => None,
+            ReplayMode::Recording(Recorder { path, .. }) => {
+                Some(path.with_extension("recorded.toml"))
+            }
+            ReplayMode::Replaying(Player { path, .. }) => {
+                Some(path.with_extension("replayed.toml"))
+            }
+        }
+    }
+}
+
+impl GameState {
+    /// Starts writing everything the update loop hears to a file, reseeding
+    /// the RNG so the recording knows what it was.
+    pub fn start_recording(&mut self, path: impl AsRef<Path>) -> Result<(), ReplayError> {
+        let seed = rand::random();
+        let timing = ReplayTiming::from_config(&self.config);
+        self.replay = ReplayMode::Recording(Recorder::create(path.as_ref(), seed, timing)?);
+        self.insert_resource(StdRng::seed_from_u64(seed));
+        info!("Recording input to {}", path.as_ref().display());
+        Ok(())
+    }
+
+    /// Makes the update loop take its input from a recording instead of the
+    /// event loop. Needs to happen before anything's been updated, so the
+    /// replay starts from the same world the recording did.
+    pub fn start_replay(&mut self, path: impl AsRef<Path>) -> Result<(), ReplayError> {
+        let player = Player::open(path.as_ref())?;
+        self.insert_resource(StdRng::seed_from_u64(player.seed));
+        info!(
+            "Replaying {} updates from {}",
+            player.ticks.len(),
+            path.as_ref().display()
+        );
+        self.replay = ReplayMode::Replaying(player);
+        Ok(())
+    }
+
+    /// The fixed step length and how many steps to catch up on at most: the
+    /// recording's while recording or replaying, and the config's otherwise.
+    pub fn step_timing(&self) -> ReplayTiming {
+        self.replay
+            .timing()
+            .unwrap_or_else(|| ReplayTiming::from_config(&self.config))
+    }
+
+    /// Records this update's events if we're recording. If we're replaying,
+    /// swaps them out for the recorded ones (and `dt` for the recorded
+    /// length of the update). `None` once the replay has run out.
+    pub fn replay_tick(
+        &mut self,
+        tick: u64,
+        dt: f32,
+        events: Vec<GameStateEvent>,
+    ) -> Option<(f32, Vec<GameStateEvent>)> {
+        match &mut self.replay {
+            ReplayMode::Live => Some((dt, events)),
+            ReplayMode::Recording(recorder) => {
+                let recorded = RecordedTick {
+                    tick,
+                    dt,
+                    events: events
+                        .iter()
+                        .filter_map(RecordedEvent::from_event)
+                        .collect(),
+                };
+                if let Err(e) = recorder.record(&recorded) {
+                    error!("Couldn't record input, stopping recording: {}", e);
+                    self.replay = ReplayMode::Live;
+                }
+                Some((dt, events))
+            }
+            ReplayMode::Replaying(player) => {
+                let recorded = player.next_tick()?;
+                if recorded.tick != tick {
+                    warn!(
+                        "Replay is out of step: expected tick {}, got {}",
+                        tick, recorded.tick
+                    );
+                }
+                let events = recorded
+                    .events
+                    .iter()
+                    .filter_map(RecordedEvent::to_event)
+                    .collect();
+                Some((recorded.dt, events))
+            }
+        }
+    }
+
+    /// Saves the world next to the recording, if there is one, so the
+    /// results of a recording and its replays can be compared.
+    pub fn finish_replay(&mut self) {
+        if let Some(path) = self.replay.final_state_path() {
+            let path = path.to_string_lossy().into_owned();
+            if let Err(e) = self.save_scene(&path) {
+                error!("Couldn't save final state to {}: {}", path, e);
+            }
+        }
+        self.replay = ReplayMode::Live;
+    }
+}
@@ -9,17 +9,17 @@
 use std::{
     collections::{HashMap, HashSet},
     sync::{Arc, RwLock},
-    thread::{self, JoinHandle},
+    thread,
 };
 
 use crossbeam_channel::{unbounded, Receiver, Sender};
 use gl::Gl;
 
-use rayon::prelude::*;
+use sdl2::{image::LoadSurface, pixels::PixelFormatEnum, surface::Surface};
 
-use crate::entity::{
-    mesh_component::{Model, ModelComponent},
-    Entity, EntitySystem,
+use crate::{
+    entity::{mesh_component::Model, Entity},
+    render_gl::textures::{Texture, TextureParameters, RGBA8},
 };
 
 #[derive(Debug)]
@@ -27,6 +27,7 @@ pub enum ResourceRequest {
     Models(Vec<(String, Entity)>),
     UnloadModels(Vec<(String, Entity)>),
     Textures(Vec<String>),
+    UnloadTextures(Vec<String>),
     WorldChunks(Vec<(u32, u32)>),
 }
 
@@ -34,11 +35,75 @@ pub enum ResourceRequest {
 pub struct ResourceManager {
     pub request_sender: Sender<ResourceRequest>,
     pub model_response: Receiver<(String, Model)>,
-    pub texture_response: Receiver<(u32, u32, Vec<u8>)>,
+    pub texture_response: Receiver<TextureResponse>,
     pub chunk_response: Receiver<()>,
     state: Arc<ResourceManagerState>,
 }
 
+/// A decoded image, not on the GPU yet: tightly packed RGBA, top row first.
+#[derive(Debug, Clone)]
+pub struct LoadedTexture {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<u8>,
+}
+
+impl LoadedTexture {
+    /// Decodes anything SDL_image can read.
+    pub fn from_file(path: &str) -> Result<Self, String> {
+        let surface = Surface::from_file(path)?.convert_format(PixelFormatEnum::RGBA32)?;
+        let (width, height) = surface.size();
+        // Rows can be padded out past the end of the pixels in them
+        let pitch = surface.pitch() as usize;
+        let row = width as usize * 4;
+        let pixels = surface.with_lock(|bytes| {
+            bytes
+                .chunks(pitch)
+                .take(height as usize)
+                .flat_map(|r| &r[..row])
+                .copied()
+                .collect()
+        });
+        Ok(Self {
+            width,
+            height,
+            pixels,
+        })
+    }
+
+    /// How many mip levels the texture gets: the usual number, unless it's
+    /// too small to halve that many times, since asking GL for more levels
+    /// than the size allows leaves the texture with no storage at all.
+    pub fn mip_levels(&self) -> gl::types::GLint {
+        let longest_side = self.width.max(self.height).max(1);
+        // floor(log2(longest_side)) + 1
+        let most = (u32::BITS - longest_side.leading_zeros()) as gl::types::GLint;
+        TextureParameters::default().mips.min(most)
+    }
+
+    pub fn upload(&self, gl: &Gl) -> Texture<RGBA8> {
+        Texture::new_with_bytes(
+            gl,
+            TextureParameters {
+                mips: self.mip_levels(),
+                ..Default::default()
+            },
+            &self.pixels.iter().map(|b| RGBA8(*b)).collect(),
+            self.width as usize,
+            self.height as usize,
+            1,
+        )
+    }
+}
+
+pub enum TextureResponse {
+    Loaded(String, LoadedTexture),
+    /// The texture couldn't be loaded, so it's been given up on
+    Failed(String, String),
+    /// Nothing's using this texture anymore, so the renderer can drop it
+    Unloaded(String),
+}
+
 #[derive(Debug, PartialEq, Eq)]
 enum LoadingState {
     Loading,
@@ -47,8 +112,18 @@ enum LoadingState {
 
 struct ResourceManagerState {
     loaded_loading_models: RwLock<HashMap<String, (LoadingState, HashSet<Entity>)>>,
+    // For world chunks, which can't be requested yet
+    #[allow(dead_code)]
     loaded_loading_chunks: RwLock<HashSet<(LoadingState, (u32, u32))>>,
-    loaded_loading_texs: RwLock<HashSet<(LoadingState, String)>>,
+    /// Along with how many requests for each texture haven't been unloaded
+    /// yet
+    loaded_loading_texs: RwLock<HashMap<String, (LoadingState, usize)>>,
+}
+
+impl Default for ResourceManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ResourceManager {
@@ -56,12 +131,12 @@ impl ResourceManager {
         let (reqs, request_receiver) = unbounded();
         let (model_response_sender, model_response) = unbounded();
         let (tex_response_sender, texture_response) = unbounded();
-        let (chunk_response_sender, chunk_response) = unbounded();
+        let (_chunk_response_sender, chunk_response) = unbounded();
 
         let state = Arc::new(ResourceManagerState {
             loaded_loading_models: RwLock::new(HashMap::new()),
             loaded_loading_chunks: RwLock::new(HashSet::new()),
-            loaded_loading_texs: RwLock::new(HashSet::new()),
+            loaded_loading_texs: RwLock::new(HashMap::new()),
         });
         {
             let state = state.clone();
@@ -120,8 +195,42 @@ impl ResourceManager {
                                     }
                                 }
                             }
-                            ResourceRequest::Textures(texture_reqs) => unimplemented!(),
-                            ResourceRequest::WorldChunks(chunk_reqs) => unimplemented!(),
+                            ResourceRequest::Textures(texture_reqs) => {
+                                let mut loaded_loading_texs =
+                                    state.loaded_loading_texs.write().unwrap();
+                                for path in texture_reqs {
+                                    // Unlike models, the renderer doesn't
+                                    // need to know who's using a texture, so
+                                    // if we already have it, just count the
+                                    // new user
+                                    if let Some((_, users)) = loaded_loading_texs.get_mut(&path) {
+                                        *users += 1;
+                                    } else {
+                                        loaded_loading_texs
+                                            .insert(path.clone(), (LoadingState::Loading, 1));
+                                        Self::spawn_texture_loader(
+                                            tex_response_sender.clone(),
+                                            path,
+                                        );
+                                    }
+                                }
+                            }
+                            ResourceRequest::UnloadTextures(texture_unload_reqs) => {
+                                let mut loaded_loading_texs =
+                                    state.loaded_loading_texs.write().unwrap();
+                                for path in texture_unload_reqs {
+                                    if let Some((_, users)) = loaded_loading_texs.get_mut(&path) {
+                                        *users -= 1;
+                                        if *users == 0 {
+                                            loaded_loading_texs.remove(&path);
+                                            tex_response_sender
+                                                .send(TextureResponse::Unloaded(path))
+                                                .unwrap();
+                                        }
+                                    }
+                                }
+                            }
+                            ResourceRequest::WorldChunks(_) => unimplemented!(),
                         }
                     }
                 })
@@ -148,6 +257,20 @@ impl ResourceManager {
             .unwrap()
     }
 
+    /// Asks for textures to be loaded. Every request has to be matched by an
+    /// unload request eventually, since textures stay loaded until nothing's
+    /// using them.
+    pub fn request_textures(&self, requests: Vec<String>) {
+        self.request_sender
+            .send(ResourceRequest::Textures(requests))
+            .unwrap()
+    }
+    pub fn request_unload_textures(&self, requests: Vec<String>) {
+        self.request_sender
+            .send(ResourceRequest::UnloadTextures(requests))
+            .unwrap()
+    }
+
     /// Checks to see if there's a new batch of models done loading. If there
     /// is, then block and integrate it. Else return. Returns true if there was
     /// new stuff and false otherwise.
@@ -158,6 +281,17 @@ impl ResourceManager {
         &self,
         models: &mut HashMap<String, Model>,
         gl: &Gl,
+    ) -> bool {
+        self.try_integrate_loaded_models_with(models, |model| model.setup_model_gl(gl))
+    }
+
+    /// Like `try_integrate_loaded_models`, but with whatever setup new
+    /// models need done to them instead of uploading them to the GPU, for
+    /// when there isn't one.
+    pub fn try_integrate_loaded_models_with(
+        &self,
+        models: &mut HashMap<String, Model>,
+        setup: impl FnOnce(&mut Model),
     ) -> bool {
         if let Ok((path, mut model)) = self.model_response.try_recv() {
             let mut loaded_loading_models = self.state.loaded_loading_models.write().unwrap();
@@ -172,7 +306,7 @@ impl ResourceManager {
 
                 *state = LoadingState::Loaded;
 
-                model.setup_model_gl(gl);
+                setup(&mut model);
                 model.entities.extend(entities.iter());
 
                 models.insert(path, model);
@@ -183,6 +317,78 @@ impl ResourceManager {
         }
     }
 
+    /// Checks to see if a texture is done loading, has failed to load, or has
+    /// been unloaded, and if so, uploads it or drops it. Returns true if
+    /// there was anything new and false otherwise.
+    pub fn try_integrate_loaded_textures(
+        &self,
+        textures: &mut HashMap<String, Texture<RGBA8>>,
+        gl: &Gl,
+    ) -> bool {
+        self.try_integrate_loaded_textures_with(textures, |texture| texture.upload(gl))
+    }
+
+    /// Like `try_integrate_loaded_textures`, but turning loaded textures into
+    /// whatever `setup` makes of them instead of uploading them to the GPU.
+    pub fn try_integrate_loaded_textures_with<T>(
+        &self,
+        textures: &mut HashMap<String, T>,
+        setup: impl FnOnce(LoadedTexture) -> T,
+    ) -> bool {
+        let Ok(response) = self.texture_response.try_recv() else {
+            return false;
+        };
+        let mut loaded_loading_texs = self.state.loaded_loading_texs.write().unwrap();
+        match response {
+            TextureResponse::Loaded(path, texture) => {
+                // If it's not in the registry, it was unloaded before it
+                // finished loading. If we have it already, it was requested
+                // again right after being unloaded, and loaded twice.
+                if let Some((state, _)) = loaded_loading_texs.get_mut(&path) {
+                    *state = LoadingState::Loaded;
+                    textures.entry(path).or_insert_with(|| setup(texture));
+                }
+            }
+            TextureResponse::Failed(path, _) => {
+                // Forget about it, so asking for it again tries again instead
+                // of waiting on a load that's never going to finish
+                if matches!(
+                    loaded_loading_texs.get(&path),
+                    Some((LoadingState::Loading, _))
+                ) {
+                    loaded_loading_texs.remove(&path);
+                }
+            }
+            TextureResponse::Unloaded(path) => {
+                // Unless it's been requested again since
+                if !loaded_loading_texs.contains_key(&path) {
+                    textures.remove(&path);
+                }
+            }
+        }
+        true
+    }
+
+    fn spawn_texture_loader(tex_response_sender: Sender<TextureResponse>, path: String) {
+        rayon::spawn(move || {
+            let time = std::time::Instant::now();
+            let texture = match LoadedTexture::from_file(&path) {
+                Ok(texture) => texture,
+                Err(e) => {
+                    error!("Unable to load texture {}: {}", path, e);
+                    let _ = tex_response_sender.send(TextureResponse::Failed(path, e));
+                    return;
+                }
+            };
+            debug!(
+                "Texture loaded for {} in time {}ms",
+                path,
+                time.elapsed().as_millis()
+            );
+            let _ = tex_response_sender.send(TextureResponse::Loaded(path, texture));
+        });
+    }
+
     fn spawn_model_loader(model_response_sender: Sender<(String, Model)>, path: String) {
         rayon::spawn(move || {
             let time = std::time::Instant::now();
@@ -199,7 +405,7 @@ impl ResourceManager {
             );
 
             let start_process_time = time.elapsed().as_millis();
-            let mut model = Model::from_gltf(gltf).expect("Unable to load model");
+            let model = Model::from_gltf(gltf).expect("Unable to load model");
             let end_process_time = time.elapsed().as_millis();
             println!(
                 "GLTF processed to native formats for {} in time {}ms",
@@ -207,9 +413,62 @@ impl ResourceManager {
                 end_process_time - start_process_time
             );
 
-            let _ = model_response_sender
-                .send((path.to_string(), model))
-                .unwrap();
+            // Nobody's waiting for it anymore if the game's already shut down
+            let _ = model_response_sender.send((path.to_string(), model));
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Integrates texture responses until one turns up, or gives up after a
+    /// few seconds.
+    fn integrate(resources: &ResourceManager, textures: &mut HashMap<String, LoadedTexture>) {
+        let start = std::time::Instant::now();
+        while !resources.try_integrate_loaded_textures_with(textures, |texture| texture) {
+            assert!(
+                start.elapsed().as_secs() < 5,
+                "Never heard back about the texture"
+            );
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn small_textures_get_fewer_mips() {
+        let texture = |width, height| LoadedTexture {
+            width,
+            height,
+            pixels: vec![],
+        };
+        assert_eq!(texture(1, 1).mip_levels(), 1);
+        assert_eq!(texture(2, 1).mip_levels(), 2);
+        assert_eq!(texture(1, 7).mip_levels(), 3);
+        assert_eq!(texture(8, 2).mip_levels(), 4);
+        assert_eq!(texture(1024, 512).mip_levels(), 4);
+    }
+
+    #[test]
+    fn missing_textures_are_given_up_on() {
+        let resources = ResourceManager::new();
+        let mut textures = HashMap::new();
+        let path = "./data/textures/nothing_here.png".to_string();
+
+        resources.request_textures(vec![path.clone()]);
+        integrate(&resources, &mut textures);
+        assert!(textures.is_empty());
+        assert!(!resources
+            .state
+            .loaded_loading_texs
+            .read()
+            .unwrap()
+            .contains_key(&path));
+
+        // And it's tried again from scratch the next time it's asked for
+        resources.request_textures(vec![path.clone()]);
+        integrate(&resources, &mut textures);
+        assert!(textures.is_empty());
+    }
+}
@@ -0,0 +1,129 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Engine-wide singletons (the time, RNG, input state, quest state...)
+//! stored by type, so game code can keep whatever global state it needs in
+//! the game state without having to add a field to `GameState` for it.
+//!
+//! Each resource is kept in an `Accessor`, so just like the camera and
+//! lights, anyone who needs to know whether a resource has been touched can
+//! check its dirty flag.
+
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+};
+
+use atomic_refcell::{AtomicRef, AtomicRefCell, AtomicRefMut};
+
+use crate::update_thread::{Accessor, GameState};
+
+/// Resources are shared with systems, which can be running on several
+/// threads at once, so they have to be `Sync` as well as `Send`.
+pub trait Resource: Send + Sync + 'static {}
+impl<T: Send + Sync + 'static> Resource for T {}
+
+#[derive(Default)]
+pub struct Resources {
+    /// Each value is an `AtomicRefCell<Accessor<T>>`, keyed by `T`'s type
+    /// ID. The cell is so systems can borrow resources with only shared
+    /// access to the game state, like they do component storage.
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Resources {
+    /// Adds the resource, handing back the old one if there already was one
+    /// of this type.
+    pub fn insert<T: Resource>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(
+                TypeId::of::<T>(),
+                Box::new(AtomicRefCell::new(Accessor::new(value))),
+            )
+            .map(|old| Self::unbox::<T>(old).into_inner())
+    }
+
+    pub fn remove<T: Resource>(&mut self) -> Option<T> {
+        self.map
+            .remove(&TypeId::of::<T>())
+            .map(|old| Self::unbox::<T>(old).into_inner())
+    }
+
+    pub fn contains<T: Resource>(&self) -> bool {
+        self.map.contains_key(&TypeId::of::<T>())
+    }
+
+    /// Panics if the resource is already mutably borrowed.
+    pub fn get<T: Resource>(&self) -> Option<AtomicRef<'_, Accessor<T>>> {
+        self.cell::<T>().map(|cell| cell.borrow())
+    }
+
+    /// Mutating the resource through the `Accessor` marks it dirty. Panics if
+    /// the resource is already borrowed.
+    pub fn get_mut<T: Resource>(&self) -> Option<AtomicRefMut<'_, Accessor<T>>> {
+        self.cell::<T>().map(|cell| cell.borrow_mut())
+    }
+
+    /// Like `get_mut`, but with exclusive access there's no need to check
+    /// for anyone else borrowing it.
+    pub fn get_exclusive<T: Resource>(&mut self) -> Option<&mut Accessor<T>> {
+        self.map.get_mut(&TypeId::of::<T>()).map(|r| {
+            r.downcast_mut::<AtomicRefCell<Accessor<T>>>()
+                .expect("Resources are keyed by their own type")
+                .get_mut()
+        })
+    }
+
+    fn cell<T: Resource>(&self) -> Option<&AtomicRefCell<Accessor<T>>> {
+        self.map.get(&TypeId::of::<T>()).map(|r| {
+            r.downcast_ref()
+                .expect("Resources are keyed by their own type")
+        })
+    }
+
+    fn unbox<T: Resource>(resource: Box<dyn Any + Send + Sync>) -> Accessor<T> {
+        resource
+            .downcast::<AtomicRefCell<Accessor<T>>>()
+            .expect("Resources are keyed by their own type")
+            .into_inner()
+    }
+}
+
+impl GameState {
+    /// Adds an engine-wide resource, replacing (and returning) any resource
+    /// of the same type that was already there.
+    pub fn insert_resource<T: Resource>(&mut self, value: T) -> Option<T> {
+        self.resources.insert(value)
+    }
+
+    pub fn remove_resource<T: Resource>(&mut self) -> Option<T> {
+        self.resources.remove()
+    }
+
+    pub fn resource<T: Resource>(&self) -> Option<AtomicRef<'_, T>> {
+        self.resources.get().map(|r| AtomicRef::map(r, |r| &**r))
+    }
+
+    /// The resource wrapped in its `Accessor`, which marks it dirty as soon
+    /// as it's mutated.
+    pub fn resource_mut<T: Resource>(&mut self) -> Option<&mut Accessor<T>> {
+        self.resources.get_exclusive()
+    }
+
+    /// Whether the resource has been mutated since it was added or last
+    /// marked clean (`false` if there isn't one).
+    pub fn resource_is_dirty<T: Resource>(&self) -> bool {
+        self.resources.get::<T>().is_some_and(|r| r.is_dirty())
+    }
+
+    pub fn mark_resource_clean<T: Resource>(&mut self) {
+        if let Some(r) = self.resources.get_exclusive::<T>() {
+            r.mark_clean();
+        }
+    }
+}
@@ -0,0 +1,623 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Loading scenes (sets of entities and their components) from TOML files,
+//! so the world doesn't have to be hard-coded. A scene file looks like:
+//!
+//! ```toml
+//! # Which entity to render from, by name or by index in the entity list
+//! camera = "player"
+//!
+//! [[entity]]
+//! name = "player"
+//! [entity.TransformComponent]
+//! grounded = true
+//! transform = { trans = [0.0, 0.0, -3.0], rot = [0.0, 0.479, 0.0, 0.878] }
+//! [entity.CameraComponent]
+//! fov = 90.0
+//!
+//! [[entity]]
+//! [entity.TransformComponent]
+//! transform = { trans = [4.0, 2.0, 0.0] }
+//! [entity.LightComponent]
+//! kind = "Point" # or Ambient, Directional, Spot
+//! color = [20.2, 0.1, 0.1]
+//! ambient = [0.0, 0.0, 0.0]
+//! attenuation = { constant = 1.5, linear = 9.0, quadratic = 1.9 }
+//!
+//! [[entity]]
+//! [entity.ModelComponent]
+//! path = "./data/models/heroine.glb"
+//! [entity.HierarchyComponent]
+//! parent = "player" # or an index into the entity list
+//!
+//! [[entity]]
+//! # Everything in data/prefabs/red_lamp.toml, with this entity's own
+//! # components merged over it field by field
+//! prefab = "red_lamp"
+//! TransformComponent = { transform = { trans = [1.0, 4.0, 3.0] } }
+//!
+//! [[entity]]
+//! # 200 copies of this entity, 10 along X, 20 along Y and 1 along Z, each
+//! # moved over by `spacing` from the last, starting at its own transform
+//! prefab = "heroine"
+//! grid = { count = [10, 20, 1], spacing = [1.0, 2.0, 0.0] }
+//! ```
+//!
+//! Components are written the same way `save_scene` writes them (and
+//! prefabs are), keyed by component ID, and only registered components can
+//! be used (see `ComponentRegistry`). Anything a component leaves out gets
+//! its default, so transforms only need the parts that aren't the identity.
+//! The only differences from a saved scene are that entities can be named,
+//! start from a prefab (see `prefab.rs`) and be repeated in a grid, and the
+//! camera and
+//! `HierarchyComponent` parents refer to entities by name or by index,
+//! instead of by saved ID. Since a grid entity is really a lot of entities,
+//! it can't be the camera or a parent, and it needs a `TransformComponent`
+//! to lay its copies out from.
+//!
+//! The whole scene is checked before anything gets spawned, so a broken
+//! scene file doesn't leave half of itself behind in the world.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+    ops::Range,
+};
+
+use serde::Deserialize;
+use toml::Spanned;
+
+use crate::{
+    entity::{
+        camera_component::CameraComponent, hierarchy_component::HierarchyComponent,
+        mesh_component::ModelComponent, transform_component::TransformComponent, Component, Entity,
+        EntityID,
+    },
+    serialization::{SaveError, SavedEntity, SavedScene},
+    update_thread::GameState,
+    utils::merge,
+};
+
+/// Something wrong with a scene file, pointing at where in the file it is if
+/// we know.
+#[derive(Debug, Clone)]
+pub struct SceneError {
+    pub path: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl Display for SceneError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.line {
+            Some(line) => write!(f, "{}:{}: {}", self.path, line, self.message),
+            None => write!(f, "{}: {}", self.path, self.message),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct SceneDesc {
+    camera: Option<Spanned<toml::Value>>,
+    #[serde(default, rename = "entity")]
+    entities: Vec<EntityDesc>,
+}
+
+/// The entity's components keyed by component ID, plus maybe a `name`,
+/// `prefab` and `grid`.
+type EntityDesc = BTreeMap<String, Spanned<toml::Value>>;
+
+/// Lays out copies of an entity `count` deep along each axis, `spacing`
+/// apart, starting from where its own transform puts it.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Grid {
+    count: [usize; 3],
+    spacing: glam::Vec3,
+}
+
+pub fn load_scene(game_state: &mut GameState, path: &str) -> Result<Vec<Entity>, SceneError> {
+    info!("Loading scene file at {}", path);
+    let source = std::fs::read_to_string(path).map_err(|e| SceneError {
+        path: path.to_string(),
+        line: None,
+        message: format!("couldn't read scene file: {}", e),
+    })?;
+    load_scene_from_str(game_state, &source, path)
+}
+
+/// Spawns everything in the scene described by `source`, returning the new
+/// entities in the order they're listed. `path` is only used for errors.
+pub fn load_scene_from_str(
+    game_state: &mut GameState,
+    source: &str,
+    path: &str,
+) -> Result<Vec<Entity>, SceneError> {
+    let error_at = |span: Option<Range<usize>>, message: String| SceneError {
+        path: path.to_string(),
+        line: span.map(|span| line_of(source, span.start)),
+        message,
+    };
+
+    let mut scene: SceneDesc =
+        toml::from_str(source).map_err(|e| error_at(e.span(), e.message().to_string()))?;
+
+    let mut names = HashMap::new();
+    for (i, desc) in scene.entities.iter_mut().enumerate() {
+        let Some(name) = desc.remove("name") else {
+            continue;
+        };
+        let toml::Value::String(s) = name.get_ref() else {
+            return Err(error_at(
+                Some(name.span()),
+                "entity names have to be strings".to_string(),
+            ));
+        };
+        if names.insert(s.clone(), i).is_some() {
+            return Err(error_at(
+                Some(name.span()),
+                format!("there's already an entity named \"{}\"", s),
+            ));
+        }
+    }
+
+    // Lay each entity's own components over those of its prefab, if it has
+    // one. Components that only come from the prefab point at the `prefab`
+    // line for errors.
+    for desc in scene.entities.iter_mut() {
+        let Some(prefab) = desc.remove("prefab") else {
+            continue;
+        };
+        let toml::Value::String(name) = prefab.get_ref() else {
+            return Err(error_at(
+                Some(prefab.span()),
+                "prefab names have to be strings".to_string(),
+            ));
+        };
+        let mut components = game_state
+            .prefabs
+            .resolve(name)
+            .map_err(|e| error_at(Some(prefab.span()), e.to_string()))?;
+        merge(
+            &mut components,
+            desc.iter()
+                .map(|(id, value)| (id.clone(), value.get_ref().clone()))
+                .collect(),
+        );
+        *desc = components
+            .into_iter()
+            .map(|(id, value)| {
+                let span = desc.get(&id).map_or(prefab.span(), |own| own.span());
+                (id, Spanned::new(span, value))
+            })
+            .collect();
+    }
+
+    // Entities with a grid turn into a copy for every cell of it, which
+    // happens once everything else is checked
+    let grids = scene
+        .entities
+        .iter_mut()
+        .map(|desc| {
+            let Some(grid) = desc.remove("grid") else {
+                return Ok(None);
+            };
+            let span = grid.span();
+            let grid: Grid = grid.into_inner().try_into().map_err(|e: toml::de::Error| {
+                error_at(Some(span.clone()), format!("bad grid: {}", e.message()))
+            })?;
+            if !desc.contains_key(TransformComponent::get_id()) {
+                return Err(error_at(
+                    Some(span),
+                    "entities laid out in a grid need a TransformComponent".to_string(),
+                ));
+            }
+            Ok(Some(grid))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let resolve = |r: &Spanned<toml::Value>| -> Result<usize, SceneError> {
+        let index = match r.get_ref() {
+            toml::Value::String(name) => names
+                .get(name.as_str())
+                .copied()
+                .ok_or_else(|| error_at(Some(r.span()), format!("no entity named \"{}\"", name))),
+            toml::Value::Integer(i) if (0..scene.entities.len() as i64).contains(i) => {
+                Ok(*i as usize)
+            }
+            toml::Value::Integer(i) => Err(error_at(
+                Some(r.span()),
+                format!(
+                    "entity index {} is out of range, there are only {} entities",
+                    i,
+                    scene.entities.len()
+                ),
+            )),
+            _ => Err(error_at(
+                Some(r.span()),
+                "expected an entity name or index".to_string(),
+            )),
+        }?;
+        if grids[index].is_some() {
+            return Err(error_at(
+                Some(r.span()),
+                "entities laid out in a grid are more than one entity, so they can't be referred to"
+                    .to_string(),
+            ));
+        }
+        Ok(index)
+    };
+
+    // Values inside a component don't keep spans of their own, so errors
+    // about a parent point at its HierarchyComponent
+    let hierarchy_id = HierarchyComponent::get_id();
+    let parents = scene
+        .entities
+        .iter()
+        .map(|desc| {
+            let Some(hierarchy) = desc.get(hierarchy_id) else {
+                return Ok(None);
+            };
+            let Some(parent) = hierarchy.get_ref().get("parent") else {
+                return Ok(None);
+            };
+            resolve(&Spanned::new(hierarchy.span(), parent.clone())).map(Some)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    for (i, desc) in scene.entities.iter().enumerate() {
+        // If we can't get to a root in as many steps as there are entities,
+        // we're going around in circles
+        let mut ancestor = parents[i];
+        for _ in 0..scene.entities.len() {
+            ancestor = ancestor.and_then(|a| parents[a]);
+        }
+        if ancestor.is_some() {
+            return Err(error_at(
+                desc.get(hierarchy_id).map(|h| h.span()),
+                "this parent leads into a cycle in the hierarchy".to_string(),
+            ));
+        }
+    }
+
+    for desc in scene.entities.iter() {
+        let Some(model) = desc.get(ModelComponent::get_id()) else {
+            continue;
+        };
+        if let Some(toml::Value::String(model_path)) = model.get_ref().get("path") {
+            if !std::path::Path::new(model_path).exists() {
+                return Err(error_at(
+                    Some(model.span()),
+                    format!("model file \"{}\" doesn't exist", model_path),
+                ));
+            }
+        }
+    }
+
+    let camera = scene.camera.as_ref().map(&resolve).transpose()?;
+    if let (Some(camera), Some(span)) = (camera, scene.camera.as_ref().map(|c| c.span())) {
+        let desc = &scene.entities[camera];
+        if !desc.contains_key(CameraComponent::get_id())
+            || !desc.contains_key(TransformComponent::get_id())
+        {
+            return Err(error_at(
+                Some(span),
+                "the camera entity needs both a CameraComponent and a TransformComponent"
+                    .to_string(),
+            ));
+        }
+    }
+
+    // Everything that's particular to scene files checks out, so hand it to
+    // the registry. Entities are saved under their index in the expanded
+    // list, with grids taking up as many indices as they have cells.
+    let first_ids: Vec<EntityID> = grids
+        .iter()
+        .scan(0, |next, grid| {
+            let id = *next;
+            *next += grid.as_ref().map_or(1, |g| g.count.iter().product());
+            Some(id)
+        })
+        .collect();
+    let spans: Vec<HashMap<String, Range<usize>>> = scene
+        .entities
+        .iter()
+        .map(|desc| {
+            desc.iter()
+                .map(|(id, value)| (id.clone(), value.span()))
+                .collect()
+        })
+        .collect();
+    let mut saved = SavedScene {
+        camera: camera.map(|c| first_ids[c]),
+        entities: vec![],
+    };
+    // Which entry in the file each saved entity came from, for errors
+    let mut desc_of = vec![];
+    let transform_id = TransformComponent::get_id();
+    for (i, ((desc, parent), grid)) in scene
+        .entities
+        .into_iter()
+        .zip(parents)
+        .zip(grids)
+        .enumerate()
+    {
+        let mut components: BTreeMap<String, toml::Value> = desc
+            .into_iter()
+            .map(|(id, value)| (id, value.into_inner()))
+            .collect();
+        // Parents are given by name or index, but the registry wants them
+        // the way they're saved, as the saved entity
+        if let (Some(parent), Some(toml::Value::Table(hierarchy))) =
+            (parent, components.get_mut(hierarchy_id))
+        {
+            let parent = Entity {
+                id: first_ids[parent],
+                generation: 0,
+            };
+            hierarchy.insert(
+                "parent".to_string(),
+                toml::Value::try_from(parent).expect("Entities can always be written as TOML"),
+            );
+        }
+
+        let Some(grid) = grid else {
+            desc_of.push(i);
+            saved.entities.push(SavedEntity {
+                id: first_ids[i],
+                components,
+            });
+            continue;
+        };
+        let mut transform: TransformComponent = components[transform_id]
+            .clone()
+            .try_into()
+            .map_err(|e: toml::de::Error| {
+                error_at(
+                    spans[i].get(transform_id).cloned(),
+                    format!("bad {}: {}", transform_id, e.message()),
+                )
+            })?;
+        let origin = transform.transform.trans;
+        let [width, height, depth] = grid.count;
+        for z in 0..depth {
+            for y in 0..height {
+                for x in 0..width {
+                    transform.transform.trans =
+                        origin + grid.spacing * glam::vec3(x as f32, y as f32, z as f32);
+                    let mut components = components.clone();
+                    components.insert(
+                        transform_id.to_string(),
+                        toml::Value::try_from(&transform)
+                            .expect("Transforms can always be written as TOML"),
+                    );
+                    saved.entities.push(SavedEntity {
+                        id: desc_of.len(),
+                        components,
+                    });
+                    desc_of.push(i);
+                }
+            }
+        }
+    }
+    let entities = saved.spawn(game_state).map_err(|e| match e {
+        SaveError::UnknownComponent(component) => error_at(
+            spans
+                .iter()
+                .find_map(|components| components.get(&component).cloned()),
+            format!("{} isn't a registered component type", component),
+        ),
+        SaveError::Component {
+            component,
+            entity,
+            message,
+        } => error_at(
+            spans[desc_of[entity]].get(&component).cloned(),
+            format!("bad {}: {}", component, message),
+        ),
+        e => error_at(None, e.to_string()),
+    })?;
+
+    info!("Loaded {} entities from {}", entities.len(), path);
+    Ok(entities)
+}
+
+/// The (1-based) line a byte offset into `source` is on.
+fn line_of(source: &str, offset: usize) -> usize {
+    source[..offset.min(source.len())].matches('\n').count() + 1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        entity::light_component::LightComponent, prefab::PrefabLibrary,
+        resource_manager::ResourceManager,
+    };
+
+    fn game_state() -> GameState {
+        let mut game_state = GameState::new(ResourceManager::new());
+        game_state.prefabs = PrefabLibrary::load_dir("./data/prefabs").unwrap();
+        game_state
+    }
+
+    #[test]
+    fn loads_the_default_scene() {
+        let mut game_state = game_state();
+        let entities = load_scene(&mut game_state, "./data/scenes/default.toml").unwrap();
+
+        let player = entities[0];
+        assert_eq!(*game_state.camera, Some(player));
+        let transform = game_state
+            .entities
+            .get_component::<TransformComponent>(player)
+            .unwrap();
+        assert!(transform.grounded);
+        assert_eq!(transform.transform.trans, glam::vec3(0.0, 0.0, -3.0));
+        assert!(transform
+            .transform
+            .rot
+            .abs_diff_eq(glam::Quat::from_rotation_y(1.0), 1e-6));
+        assert_eq!(transform.transform.scale, glam::Vec3::ONE);
+
+        // The player, 30 lamps, and 10,000 models hanging off of one entity
+        assert_eq!(entities.len(), 1 + 30 + 1 + 10000);
+        let model = *entities.last().unwrap();
+        assert!(game_state
+            .entities
+            .get_component::<ModelComponent>(model)
+            .is_ok());
+        assert_eq!(
+            game_state
+                .entities
+                .get_component::<TransformComponent>(model)
+                .unwrap()
+                .transform
+                .trans,
+            glam::vec3(24.0, 48.0, 15.0)
+        );
+        let heroines = game_state.entities.parent_of(model).unwrap();
+        assert_eq!(game_state.entities.children_of(heroines).len(), 10000);
+    }
+
+    #[test]
+    fn prefabs_can_be_overridden() {
+        let source = r#"
+[[entity]]
+prefab = "red_lamp"
+TransformComponent = { transform = { trans = [1.0, 2.0, 3.0] } }
+LightComponent = { attenuation = { constant = 4.0 } }
+"#;
+        let mut game_state = game_state();
+        let entities = load_scene_from_str(&mut game_state, source, "lamp.toml").unwrap();
+        let lamp = entities[0];
+        assert_eq!(
+            game_state
+                .entities
+                .get_component::<TransformComponent>(lamp)
+                .unwrap()
+                .transform
+                .trans,
+            glam::vec3(1.0, 2.0, 3.0)
+        );
+        match &*game_state
+            .entities
+            .get_component::<LightComponent>(lamp)
+            .unwrap()
+        {
+            LightComponent::Point {
+                color, attenuation, ..
+            } => {
+                // From red_lamp, lamp, and the scene, in that order
+                assert_eq!(*color, glam::vec3(20.2, 0.1, 0.1));
+                assert_eq!(attenuation.linear, 9.0);
+                assert_eq!(attenuation.constant, 4.0);
+            }
+            _ => panic!("Lamp isn't a point light"),
+        }
+
+        let source = r#"
+
+[[entity]]
+prefab = "no_such_thing"
+"#;
+        let error = load_scene_from_str(&mut game_state, source, "lamp.toml").unwrap_err();
+        assert_eq!(error.line, Some(4));
+    }
+
+    #[test]
+    fn grids_repeat_entities() {
+        let source = r#"
+[[entity]]
+name = "root"
+
+[[entity]]
+prefab = "lamp"
+grid = { count = [2, 3, 1], spacing = [1.0, 2.0, 3.0] }
+TransformComponent = { transform = { trans = [10.0, 0.0, 0.0] } }
+HierarchyComponent = { parent = "root" }
+
+[[entity]]
+name = "after"
+"#;
+        let mut game_state = game_state();
+        let entities = load_scene_from_str(&mut game_state, source, "grid.toml").unwrap();
+        assert_eq!(entities.len(), 1 + 6 + 1);
+        let trans: Vec<_> = entities[1..7]
+            .iter()
+            .map(|&e| {
+                assert_eq!(game_state.entities.parent_of(e), Some(entities[0]));
+                game_state
+                    .entities
+                    .get_component::<TransformComponent>(e)
+                    .unwrap()
+                    .transform
+                    .trans
+            })
+            .collect();
+        assert_eq!(
+            trans,
+            [
+                glam::vec3(10.0, 0.0, 0.0),
+                glam::vec3(11.0, 0.0, 0.0),
+                glam::vec3(10.0, 2.0, 0.0),
+                glam::vec3(11.0, 2.0, 0.0),
+                glam::vec3(10.0, 4.0, 0.0),
+                glam::vec3(11.0, 4.0, 0.0),
+            ]
+        );
+
+        let source = r#"
+[[entity]]
+name = "lamps"
+prefab = "lamp"
+grid = { count = [2, 2, 2], spacing = [1.0, 1.0, 1.0] }
+
+[[entity]]
+HierarchyComponent = { parent = "lamps" }
+"#;
+        let error = load_scene_from_str(&mut game_state, source, "grid.toml").unwrap_err();
+        assert_eq!(error.line, Some(8));
+
+        let source = r#"
+[[entity]]
+grid = { count = [2, 2, 2], spacing = [1.0, 1.0, 1.0] }
+"#;
+        let error = load_scene_from_str(&mut game_state, source, "grid.toml").unwrap_err();
+        assert_eq!(error.line, Some(3));
+    }
+
+    #[test]
+    fn points_at_the_broken_line() {
+        let source = r#"
+[[entity]]
+name = "a"
+[entity.TransformComponent]
+transform = { trans = [0.0, 0.0, 0.0] }
+
+[[entity]]
+[entity.TransformComponent]
+transform = { trans = "nowhere" }
+"#;
+        let mut game_state = game_state();
+        let error = load_scene_from_str(&mut game_state, source, "broken.toml").unwrap_err();
+        assert_eq!(error.line, Some(8));
+        // Nothing got spawned
+        assert_eq!(game_state.entities.entity_count, 0);
+
+        let source = r#"
+[[entity]]
+name = "a"
+[entity.HierarchyComponent]
+parent = "b"
+"#;
+        let error = load_scene_from_str(&mut game_state, source, "broken.toml").unwrap_err();
+        assert_eq!(error.line, Some(4));
+    }
+}
@@ -0,0 +1,356 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+use std::{any::TypeId, collections::HashSet};
+
+use atomic_refcell::{AtomicRef, AtomicRefMut};
+
+use rayon::prelude::*;
+
+use crate::{
+    commands::CommandBuffer,
+    entity::{
+        change_detection::Tick,
+        query::{Query, QueryParam},
+        Component, ComponentID, EntitySystem,
+    },
+    event_bus::EventWriter,
+    input::InputState,
+    resources::{Resource, Resources},
+    update_thread::Accessor,
+};
+
+/// Everything a system gets to look at while it runs. Systems only get shared
+/// access to the entity system, and borrow the component storage they need
+/// through it, so that several of them can run at once.
+pub struct SystemContext<'a> {
+    pub entities: &'a EntitySystem,
+    /// Milliseconds this step covers
+    pub dt: f32,
+    /// Milliseconds since the update loop started
+    pub time: u128,
+    /// The change tick as of the last time this system ran (0 if it never
+    /// has), so it can only look at what's changed since. Filled in by the
+    /// scheduler.
+    pub last_run: Tick,
+    /// Where to send events to be dispatched on the event bus. The
+    /// scheduler gives each system its own, so that events from different
+    /// systems always end up in the same order.
+    pub events: &'a EventWriter,
+    /// Where to queue structural changes (spawning, despawning, adding and
+    /// removing components) that can't happen while systems are running.
+    /// Per-system, like `events`.
+    pub commands: &'a CommandBuffer,
+    /// What the player's pressing, in terms of the actions and axes bound
+    /// in the config
+    pub input: &'a InputState,
+    /// Engine-wide resources (see `GameState::insert_resource`). Use
+    /// `resource` and `resource_mut` rather than borrowing these directly,
+    /// and declare which ones the system uses like components.
+    pub resources: &'a Resources,
+}
+
+impl<'a> SystemContext<'a> {
+    pub fn query<Q: QueryParam>(&self) -> Query<'a, Q> {
+        self.entities.query_since(self.last_run)
+    }
+
+    /// Like `EntitySystem::query_filtered`, but `Added<T>` and `Changed<T>`
+    /// are relative to the last time this system ran.
+    pub fn query_filtered<Q: QueryParam, F: QueryParam>(&self) -> Query<'a, Q, F> {
+        self.entities.query_since(self.last_run)
+    }
+
+    /// The resource, if there is one. The system needs to have declared it
+    /// with `reads_resource` or `writes_resource`.
+    pub fn resource<T: Resource>(&self) -> Option<AtomicRef<'a, T>> {
+        self.resources.get().map(|r| AtomicRef::map(r, |r| &**r))
+    }
+
+    /// The resource, wrapped in its `Accessor` so mutating it marks it
+    /// dirty. The system needs to have declared it with `writes_resource`.
+    pub fn resource_mut<T: Resource>(&self) -> Option<AtomicRefMut<'a, Accessor<T>>> {
+        self.resources.get_mut()
+    }
+}
+
+pub type SystemFn = dyn Fn(&SystemContext) + Send + Sync;
+
+/// A system, along with the component and resource types it declares it
+/// reads and writes. The scheduler trusts these declarations to decide what
+/// can run in parallel, so if a system borrows something it didn't declare,
+/// it'll panic when the runtime borrow check trips instead of silently
+/// racing.
+pub struct System {
+    pub name: &'static str,
+    reads: HashSet<ComponentID>,
+    writes: HashSet<ComponentID>,
+    resource_reads: HashSet<TypeId>,
+    resource_writes: HashSet<TypeId>,
+    run: Box<SystemFn>,
+    last_run: Tick,
+}
+
+impl System {
+    pub fn new(name: &'static str, run: impl Fn(&SystemContext) + Send + Sync + 'static) -> Self {
+        Self {
+            name,
+            reads: HashSet::new(),
+            writes: HashSet::new(),
+            resource_reads: HashSet::new(),
+            resource_writes: HashSet::new(),
+            run: Box::new(run),
+            last_run: 0,
+        }
+    }
+
+    pub fn reads<T: Component>(mut self) -> Self {
+        self.reads.insert(T::get_id());
+        self
+    }
+
+    pub fn writes<T: Component>(mut self) -> Self {
+        self.writes.insert(T::get_id());
+        self
+    }
+
+    pub fn reads_resource<T: Resource>(mut self) -> Self {
+        self.resource_reads.insert(TypeId::of::<T>());
+        self
+    }
+
+    pub fn writes_resource<T: Resource>(mut self) -> Self {
+        self.resource_writes.insert(TypeId::of::<T>());
+        self
+    }
+
+    /// Two systems conflict if either one writes something the other one
+    /// touches at all.
+    pub fn conflicts_with(&self, other: &System) -> bool {
+        fn overlap<T: Eq + std::hash::Hash>(
+            (reads, writes): (&HashSet<T>, &HashSet<T>),
+            (other_reads, other_writes): (&HashSet<T>, &HashSet<T>),
+        ) -> bool {
+            writes
+                .iter()
+                .any(|c| other_reads.contains(c) || other_writes.contains(c))
+                || other_writes.iter().any(|c| reads.contains(c))
+        }
+        overlap((&self.reads, &self.writes), (&other.reads, &other.writes))
+            || overlap(
+                (&self.resource_reads, &self.resource_writes),
+                (&other.resource_reads, &other.resource_writes),
+            )
+    }
+}
+
+/// Runs registered systems, in parallel wherever their declared component
+/// access allows.
+///
+/// Registration order is the tie-breaker: if two systems conflict, the one
+/// registered first always runs first. From that we get a dependency graph,
+/// which we flatten into stages, where each stage is a set of systems that
+/// don't conflict with each other and only depend on systems in earlier
+/// stages. Stages run one after another, and everything within a stage runs
+/// at once on the rayon pool.
+#[derive(Default)]
+pub struct Scheduler {
+    systems: Vec<System>,
+    /// Indices into `systems`, rebuilt lazily whenever a system is added.
+    stages: Option<Vec<Vec<usize>>>,
+}
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_system(&mut self, system: System) {
+        self.systems.push(system);
+        self.stages = None;
+    }
+
+    /// The names of the systems in each stage, for debugging.
+    pub fn stage_names(&mut self) -> Vec<Vec<&'static str>> {
+        let systems = &self.systems;
+        self.stages
+            .get_or_insert_with(|| Self::build_stages(systems))
+            .iter()
+            .map(|stage| stage.iter().map(|i| systems[*i].name).collect())
+            .collect()
+    }
+
+    fn build_stages(systems: &[System]) -> Vec<Vec<usize>> {
+        // Each system goes in the stage right after the latest stage of any
+        // earlier system it conflicts with (the longest path to it in the
+        // dependency graph), which keeps things as parallel as possible
+        // without reordering conflicting systems.
+        let mut system_stage: Vec<usize> = Vec::with_capacity(systems.len());
+        let mut stages: Vec<Vec<usize>> = vec![];
+        for (i, system) in systems.iter().enumerate() {
+            let stage = systems[..i]
+                .iter()
+                .enumerate()
+                .filter(|(_, earlier)| earlier.conflicts_with(system))
+                .map(|(j, _)| system_stage[j] + 1)
+                .max()
+                .unwrap_or(0);
+            system_stage.push(stage);
+            if stage == stages.len() {
+                stages.push(vec![]);
+            }
+            stages[stage].push(i);
+        }
+        for (i, stage) in stages.iter().enumerate() {
+            trace!(
+                "System stage {}: {:?}",
+                i,
+                stage.iter().map(|s| systems[*s].name).collect::<Vec<_>>()
+            );
+        }
+        stages
+    }
+
+    /// The oldest tick any system is still going to compare against, so
+    /// change tracking info from before it isn't needed anymore.
+    pub fn oldest_last_run(&self) -> Option<Tick> {
+        self.systems.iter().map(|s| s.last_run).min()
+    }
+
+    /// Runs every system once. `ctx.last_run` is ignored, each system gets
+    /// its own.
+    pub fn run(&mut self, ctx: &SystemContext) {
+        let systems = &mut self.systems;
+        let stages = self
+            .stages
+            .get_or_insert_with(|| Self::build_stages(systems));
+        // Stages don't keep systems in registration order (a later system
+        // that doesn't conflict with anything can go in the first stage), so
+        // hold onto everything they write until they've all run, and then
+        // put it in registration order
+        let mut outputs: Vec<(usize, EventWriter, CommandBuffer)> =
+            Vec::with_capacity(systems.len());
+        for stage in stages.iter() {
+            // Each stage's changes get their own tick, so systems in later
+            // stages (and the next run of this one) can see them
+            let tick = ctx.entities.change_tick();
+            let run_system = |i: &usize| {
+                let system = &systems[*i];
+                let events = EventWriter::default();
+                let commands = CommandBuffer::default();
+                (system.run)(&SystemContext {
+                    last_run: system.last_run,
+                    events: &events,
+                    commands: &commands,
+                    ..*ctx
+                });
+                (*i, events, commands)
+            };
+            if let [only] = stage.as_slice() {
+                // Don't bother the thread pool for a single system
+                outputs.push(run_system(only));
+            } else {
+                outputs.par_extend(stage.par_iter().map(run_system));
+            }
+            for i in stage {
+                systems[*i].last_run = tick;
+            }
+            ctx.entities.increment_change_tick();
+        }
+        outputs.sort_by_key(|(i, _, _)| *i);
+        for (_, events, commands) in outputs {
+            ctx.events.append(events);
+            ctx.commands.append(commands);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::transform_component::TransformComponent;
+
+    #[test]
+    fn output_is_in_registration_order() {
+        let mut scheduler = Scheduler::new();
+        let named = |name: &'static str| {
+            System::new(name, move |ctx: &SystemContext| ctx.events.send(name))
+        };
+        scheduler.add_system(named("A").writes::<TransformComponent>());
+        scheduler.add_system(named("B").writes::<TransformComponent>());
+        scheduler.add_system(named("C"));
+        // C doesn't conflict with anything, so it gets to run alongside A
+        assert_eq!(scheduler.stage_names(), vec![vec!["A", "C"], vec!["B"]]);
+
+        let entities = EntitySystem::new();
+        let events = EventWriter::default();
+        let commands = CommandBuffer::default();
+        let input = InputState::default();
+        let resources = Resources::default();
+        scheduler.run(&SystemContext {
+            entities: &entities,
+            dt: 16.0,
+            time: 0,
+            last_run: 0,
+            events: &events,
+            commands: &commands,
+            input: &input,
+            resources: &resources,
+        });
+        assert_eq!(events.take::<&'static str>(), vec!["A", "B", "C"]);
+    }
+
+    #[test]
+    fn systems_share_resources() {
+        struct Counter(u32);
+
+        let mut scheduler = Scheduler::new();
+        scheduler.add_system(
+            System::new("count", |ctx: &SystemContext| {
+                ctx.resource_mut::<Counter>().unwrap().0 += 1;
+            })
+            .writes_resource::<Counter>(),
+        );
+        scheduler.add_system(
+            System::new("look", |ctx: &SystemContext| {
+                ctx.events.send(ctx.resource::<Counter>().unwrap().0);
+            })
+            .reads_resource::<Counter>(),
+        );
+        scheduler.add_system(
+            System::new("look again", |ctx: &SystemContext| {
+                ctx.events.send(ctx.resource::<Counter>().unwrap().0);
+            })
+            .reads_resource::<Counter>(),
+        );
+        // Readers can share, but not with the writer
+        assert_eq!(
+            scheduler.stage_names(),
+            vec![vec!["count"], vec!["look", "look again"]]
+        );
+
+        let entities = EntitySystem::new();
+        let events = EventWriter::default();
+        let commands = CommandBuffer::default();
+        let input = InputState::default();
+        let mut resources = Resources::default();
+        resources.insert(Counter(0));
+        scheduler.run(&SystemContext {
+            entities: &entities,
+            dt: 16.0,
+            time: 0,
+            last_run: 0,
+            events: &events,
+            commands: &commands,
+            input: &input,
+            resources: &resources,
+        });
+        assert_eq!(events.take::<u32>(), vec![1, 1]);
+        assert!(resources.get::<Counter>().unwrap().is_dirty());
+    }
+}
@@ -0,0 +1,564 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Saving the live world (every entity and all of its serializable
+//! components) to a file and loading it back, for save games and for
+//! checking that scenes survive a round trip.
+//!
+//! Only component types registered with the `ComponentRegistry` get saved.
+//! Components are stored as plain values keyed by their component ID, so
+//! the same structure can be written out as TOML or MessagePack.
+//!
+//! Entities are saved along with their ID at save time, and components that
+//! refer to other entities (like `HierarchyComponent::parent`) keep those old
+//! IDs in the file. When the scene is loaded back, every saved entity gets a
+//! fresh entity, and those references are remapped to point at the new ones
+//! (see `MapEntities`).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt::Display,
+};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{
+    entity::{
+        camera_component::CameraComponent,
+        hierarchy_component::HierarchyComponent,
+        light_component::LightComponent,
+        mesh_component::ModelComponent,
+        reflect::{Reflect, ReflectError},
+        transform_component::TransformComponent,
+        Component, ComponentID, Entity, EntityError, EntityID, EntitySystem,
+    },
+    update_thread::GameState,
+};
+
+/// Maps the IDs entities had when they were saved to the entities they were
+/// loaded back in as.
+pub type EntityMap = HashMap<EntityID, Entity>;
+
+/// Components that refer to other entities need to point those references
+/// at the newly loaded entities.
+pub trait MapEntities {
+    fn map_entities(&mut self, map: &EntityMap);
+}
+
+#[derive(Debug)]
+pub enum SaveError {
+    Io(std::io::Error),
+    Encode(String),
+    Decode(String),
+    /// A component in the file that isn't in the registry
+    UnknownComponent(String),
+    /// A component that couldn't be converted to or from its saved form
+    Component {
+        component: String,
+        entity: EntityID,
+        message: String,
+    },
+}
+
+impl Display for SaveError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SaveError::Io(e) => write!(f, "{}", e),
+            SaveError::Encode(e) => write!(f, "couldn't encode scene: {}", e),
+            SaveError::Decode(e) => write!(f, "couldn't decode scene: {}", e),
+            SaveError::UnknownComponent(c) => {
+                write!(f, "{} isn't a registered component type", c)
+            }
+            SaveError::Component {
+                component,
+                entity,
+                message,
+            } => write!(f, "{} of entity {}: {}", component, entity, message),
+        }
+    }
+}
+
+impl From<std::io::Error> for SaveError {
+    fn from(e: std::io::Error) -> Self {
+        SaveError::Io(e)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SaveFormat {
+    Toml,
+    MessagePack,
+}
+
+impl SaveFormat {
+    /// Guesses the format from the file extension: `.toml` is TOML, and
+    /// anything else is MessagePack.
+    pub fn from_path(path: &str) -> Self {
+        if path.ends_with(".toml") {
+            SaveFormat::Toml
+        } else {
+            SaveFormat::MessagePack
+        }
+    }
+}
+
+type SaveFn = fn(&EntitySystem, Entity) -> Option<Result<toml::Value, String>>;
+type DecodeFn = fn(toml::Value) -> Result<PendingInsert, String>;
+/// A decoded component, waiting for the entity (and the entity map) it'll be
+/// added with
+type PendingInsert = Box<dyn FnOnce(&mut GameState, Entity, &EntityMap)>;
+
+type ReflectFn = fn(&EntitySystem, Entity, &mut dyn FnMut(&dyn Reflect)) -> Result<(), EntityError>;
+type ReflectMutFn =
+    fn(&EntitySystem, Entity, &mut dyn FnMut(&mut dyn Reflect)) -> Result<(), EntityError>;
+
+#[derive(Clone, Copy)]
+struct ReflectedComponent {
+    id: ComponentID,
+    reflect: ReflectFn,
+    reflect_mut: ReflectMutFn,
+}
+
+#[derive(Clone, Copy)]
+struct RegisteredComponent {
+    id: ComponentID,
+    save: SaveFn,
+    decode: DecodeFn,
+}
+
+/// The component types that get saved and loaded. Loading adds components
+/// in the order they were registered, so register anything whose hooks
+/// depend on other components (like `HierarchyComponent`, which wants
+/// transforms to already be there) after those.
+///
+/// Also keeps track of which components can be looked at through
+/// reflection by their ID (see `GameState::get_component_field`).
+#[derive(Clone)]
+pub struct ComponentRegistry {
+    components: Vec<RegisteredComponent>,
+    reflected: Vec<ReflectedComponent>,
+}
+
+impl Default for ComponentRegistry {
+    fn default() -> Self {
+        let mut registry = Self::empty();
+        registry.register::<TransformComponent>();
+        registry.register::<CameraComponent>();
+        registry.register::<LightComponent>();
+        registry.register::<ModelComponent>();
+        registry.register_with_entities::<HierarchyComponent>();
+
+        registry.register_reflect::<TransformComponent>();
+        registry.register_reflect::<CameraComponent>();
+        registry.register_reflect::<LightComponent>();
+        registry.register_reflect::<ModelComponent>();
+        registry
+    }
+}
+
+impl ComponentRegistry {
+    /// A registry with nothing registered in it.
+    pub fn empty() -> Self {
+        Self {
+            components: vec![],
+            reflected: vec![],
+        }
+    }
+
+    pub fn register<T: Component + Serialize + DeserializeOwned + 'static>(&mut self) {
+        self.insert(RegisteredComponent {
+            id: T::get_id(),
+            save: save_component::<T>,
+            decode: |value| {
+                let c: T = value.try_into().map_err(|e| e.to_string())?;
+                Ok(Box::new(move |game_state, e, _map| {
+                    game_state
+                        .add_component(e, c)
+                        .expect("Scenes are only spawned into fresh entities")
+                }))
+            },
+        });
+    }
+
+    /// For component types that refer to other entities.
+    pub fn register_with_entities<
+        T: Component + MapEntities + Serialize + DeserializeOwned + 'static,
+    >(
+        &mut self,
+    ) {
+        self.insert(RegisteredComponent {
+            id: T::get_id(),
+            save: save_component::<T>,
+            decode: |value| {
+                let mut c: T = value.try_into().map_err(|e| e.to_string())?;
+                Ok(Box::new(move |game_state, e, map| {
+                    c.map_entities(map);
+                    game_state
+                        .add_component(e, c)
+                        .expect("Scenes are only spawned into fresh entities")
+                }))
+            },
+        });
+    }
+
+    pub fn register_reflect<T: Component + Reflect + 'static>(&mut self) {
+        self.reflected.retain(|c| c.id != T::get_id());
+        self.reflected.push(ReflectedComponent {
+            id: T::get_id(),
+            reflect: |entities, e, f| {
+                f(&*entities.get_component::<T>(e)?);
+                Ok(())
+            },
+            reflect_mut: |entities, e, f| {
+                f(&mut *entities.get_component_mut::<T>(e)?);
+                Ok(())
+            },
+        });
+    }
+
+    /// Calls `f` with the entity's component, looked up by ID.
+    pub fn reflect(
+        &self,
+        entities: &EntitySystem,
+        e: Entity,
+        component: &str,
+        f: &mut dyn FnMut(&dyn Reflect),
+    ) -> Result<(), ReflectError> {
+        let rc = self.reflected(component)?;
+        Ok((rc.reflect)(entities, e, f)?)
+    }
+
+    /// Calls `f` with the entity's component, looked up by ID, marking it as
+    /// changed.
+    pub fn reflect_mut(
+        &self,
+        entities: &EntitySystem,
+        e: Entity,
+        component: &str,
+        f: &mut dyn FnMut(&mut dyn Reflect),
+    ) -> Result<(), ReflectError> {
+        let rc = self.reflected(component)?;
+        Ok((rc.reflect_mut)(entities, e, f)?)
+    }
+
+    fn reflected(&self, component: &str) -> Result<&ReflectedComponent, ReflectError> {
+        self.reflected
+            .iter()
+            .find(|c| c.id == component)
+            .ok_or_else(|| ReflectError::UnknownComponent(component.to_string()))
+    }
+
+    fn insert(&mut self, component: RegisteredComponent) {
+        self.components.retain(|c| c.id != component.id);
+        self.components.push(component);
+    }
+}
+
+fn save_component<T: Component + Serialize + 'static>(
+    entities: &EntitySystem,
+    e: Entity,
+) -> Option<Result<toml::Value, String>> {
+    let c = entities.get_component::<T>(e).ok()?;
+    Some(toml::Value::try_from(&*c).map_err(|e| e.to_string()))
+}
+
+/// Everything needed to recreate a world. The component values are keyed by
+/// component ID.
+#[derive(Serialize, Deserialize)]
+pub struct SavedScene {
+    /// The saved ID of the entity we were looking through
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub camera: Option<EntityID>,
+    #[serde(default, rename = "entity")]
+    pub entities: Vec<SavedEntity>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SavedEntity {
+    /// The entity's ID at save time, which is what references to it from
+    /// other saved components use
+    pub id: EntityID,
+    pub components: BTreeMap<String, toml::Value>,
+}
+
+impl SavedScene {
+    /// Captures every live entity and all of its registered components.
+    pub fn capture(game_state: &GameState) -> Result<Self, SaveError> {
+        let registry = &game_state.registry;
+        let entities = (0..game_state.entities.entity_count)
+            .filter_map(|eid| game_state.entities.get_current_entity_from_id(eid))
+            .map(|e| {
+                let mut components = BTreeMap::new();
+                for rc in registry.components.iter() {
+                    if let Some(value) = (rc.save)(&game_state.entities, e) {
+                        let value = value.map_err(|message| SaveError::Component {
+                            component: rc.id.to_string(),
+                            entity: e.id,
+                            message,
+                        })?;
+                        components.insert(rc.id.to_string(), value);
+                    }
+                }
+                Ok(SavedEntity {
+                    id: e.id,
+                    components,
+                })
+            })
+            .collect::<Result<Vec<_>, SaveError>>()?;
+
+        Ok(Self {
+            camera: game_state.camera.map(|c| c.id),
+            entities,
+        })
+    }
+
+    pub fn to_bytes(&self, format: SaveFormat) -> Result<Vec<u8>, SaveError> {
+        match format {
+            SaveFormat::Toml => toml::to_string(self)
+                .map(String::into_bytes)
+                .map_err(|e| SaveError::Encode(e.to_string())),
+            SaveFormat::MessagePack => {
+                rmp_serde::to_vec_named(self).map_err(|e| SaveError::Encode(e.to_string()))
+            }
+        }
+    }
+
+    pub fn from_bytes(bytes: &[u8], format: SaveFormat) -> Result<Self, SaveError> {
+        match format {
+            SaveFormat::Toml => {
+                let s = std::str::from_utf8(bytes).map_err(|e| SaveError::Decode(e.to_string()))?;
+                toml::from_str(s).map_err(|e| SaveError::Decode(e.to_string()))
+            }
+            SaveFormat::MessagePack => {
+                rmp_serde::from_slice(bytes).map_err(|e| SaveError::Decode(e.to_string()))
+            }
+        }
+    }
+
+    /// Spawns a fresh entity for every saved one and gives it its
+    /// components, remapping references between them. Returns the new
+    /// entities in the same order as `self.entities`.
+    ///
+    /// Every component is decoded before anything is spawned, so nothing
+    /// is left behind if part of the scene is broken.
+    pub fn spawn(self, game_state: &mut GameState) -> Result<Vec<Entity>, SaveError> {
+        let registry = &game_state.registry;
+        for saved in self.entities.iter() {
+            if let Some(unknown) = saved
+                .components
+                .keys()
+                .find(|id| !registry.components.iter().any(|rc| rc.id == id.as_str()))
+            {
+                return Err(SaveError::UnknownComponent(unknown.clone()));
+            }
+        }
+
+        // Decoded components in registry order, so they get added in that
+        // order too
+        let mut pending = vec![];
+        for rc in registry.components.iter() {
+            for (i, saved) in self.entities.iter().enumerate() {
+                if let Some(value) = saved.components.get(rc.id) {
+                    let insert =
+                        (rc.decode)(value.clone()).map_err(|message| SaveError::Component {
+                            component: rc.id.to_string(),
+                            entity: saved.id,
+                            message,
+                        })?;
+                    pending.push((i, insert));
+                }
+            }
+        }
+
+        let new_entities: Vec<Entity> = self
+            .entities
+            .iter()
+            .map(|_| game_state.gen_entity())
+            .collect();
+        let map: EntityMap = self
+            .entities
+            .iter()
+            .map(|saved| saved.id)
+            .zip(new_entities.iter().copied())
+            .collect();
+
+        for (i, insert) in pending {
+            insert(game_state, new_entities[i], &map);
+        }
+
+        if let Some(camera) = self.camera.and_then(|c| map.get(&c)) {
+            game_state.register_camera(*camera);
+        }
+        Ok(new_entities)
+    }
+}
+
+impl GameState {
+    /// Saves every entity and its registered components to `path`, as TOML
+    /// if it ends in `.toml` and as MessagePack otherwise.
+    pub fn save_scene(&self, path: &str) -> Result<(), SaveError> {
+        let bytes = SavedScene::capture(self)?.to_bytes(SaveFormat::from_path(path))?;
+        std::fs::write(path, bytes)?;
+        info!("Saved scene to {}", path);
+        Ok(())
+    }
+
+    /// Loads a scene saved with `save_scene` into the world (alongside
+    /// whatever's already there).
+    pub fn load_saved_scene(&mut self, path: &str) -> Result<Vec<Entity>, SaveError> {
+        let bytes = std::fs::read(path)?;
+        let entities = SavedScene::from_bytes(&bytes, SaveFormat::from_path(path))?.spawn(self)?;
+        info!(
+            "Loaded {} entities from saved scene {}",
+            entities.len(),
+            path
+        );
+        Ok(entities)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{entity::light_component::Attenuation, resource_manager::ResourceManager};
+
+    fn transform(x: f32) -> TransformComponent {
+        TransformComponent::new_from_rot_trans(
+            glam::vec3(0.1 * x, 0.2, 0.3),
+            glam::vec3(x, -x, 2.0 * x),
+            false,
+        )
+        .with_scale(glam::vec3(1.0, x, 1.0))
+    }
+
+    /// A camera at the root, with a light under it and a third entity under
+    /// that, so the hierarchy is more than one level deep.
+    fn build(game_state: &mut GameState) -> [Entity; 3] {
+        let root = game_state.gen_entity();
+        game_state.add_component(root, transform(1.0)).unwrap();
+        game_state
+            .add_component(root, CameraComponent { fov: 70.0 })
+            .unwrap();
+        game_state.register_camera(root);
+
+        let light = game_state.gen_entity();
+        game_state.add_component(light, transform(2.0)).unwrap();
+        game_state
+            .add_component(
+                light,
+                LightComponent::Point {
+                    color: glam::vec3(1.0, 0.5, 0.25),
+                    ambient: glam::Vec3::splat(0.1),
+                    attenuation: Attenuation {
+                        constant: 1.0,
+                        linear: 0.09,
+                        quadratic: 0.032,
+                    },
+                },
+            )
+            .unwrap();
+        game_state
+            .add_component(light, HierarchyComponent::new(root))
+            .unwrap();
+
+        let leaf = game_state.gen_entity();
+        game_state.add_component(leaf, transform(3.0)).unwrap();
+        game_state
+            .add_component(leaf, HierarchyComponent::new(light))
+            .unwrap();
+
+        [root, light, leaf]
+    }
+
+    fn round_trip(format: SaveFormat) {
+        let mut saved = GameState::new(ResourceManager::new());
+        let [root, light, leaf] = build(&mut saved);
+        let bytes = SavedScene::capture(&saved)
+            .unwrap()
+            .to_bytes(format)
+            .unwrap();
+
+        // Make sure nothing loads back in with the same IDs it was saved with,
+        // so links only come out right if they were actually remapped
+        let mut loaded = GameState::new(ResourceManager::new());
+        for _ in 0..5 {
+            loaded.gen_entity();
+        }
+        let spawned = SavedScene::from_bytes(&bytes, format)
+            .unwrap()
+            .spawn(&mut loaded)
+            .unwrap();
+        assert_eq!(spawned.len(), 3);
+        let [new_root, new_light, new_leaf] = [spawned[0], spawned[1], spawned[2]];
+        assert_ne!(new_root.id, root.id);
+
+        for (old, new) in [(root, new_root), (light, new_light), (leaf, new_leaf)] {
+            assert_eq!(
+                saved
+                    .entities
+                    .get_component::<TransformComponent>(old)
+                    .unwrap()
+                    .transform,
+                loaded
+                    .entities
+                    .get_component::<TransformComponent>(new)
+                    .unwrap()
+                    .transform,
+            );
+        }
+
+        assert_eq!(
+            loaded
+                .entities
+                .get_component::<CameraComponent>(new_root)
+                .unwrap()
+                .fov,
+            70.0
+        );
+        assert_eq!(*loaded.camera, Some(new_root));
+
+        match &*loaded
+            .entities
+            .get_component::<LightComponent>(new_light)
+            .unwrap()
+        {
+            LightComponent::Point {
+                color, attenuation, ..
+            } => {
+                assert_eq!(*color, glam::vec3(1.0, 0.5, 0.25));
+                assert_eq!(attenuation.quadratic, 0.032);
+            }
+            _ => panic!("Light came back as a different kind of light"),
+        }
+        assert_eq!(*loaded.lights, vec![new_light]);
+
+        let entities = &loaded.entities;
+        assert_eq!(entities.parent_of(new_root), None);
+        assert_eq!(entities.parent_of(new_light), Some(new_root));
+        assert_eq!(entities.parent_of(new_leaf), Some(new_light));
+        assert_eq!(entities.children_of(new_root), vec![new_light]);
+        assert_eq!(entities.children_of(new_light), vec![new_leaf]);
+        assert_eq!(
+            entities
+                .get_component::<HierarchyComponent>(new_leaf)
+                .unwrap()
+                .depth,
+            2
+        );
+    }
+
+    #[test]
+    fn round_trips_through_toml() {
+        round_trip(SaveFormat::Toml);
+    }
+
+    #[test]
+    fn round_trips_through_messagepack() {
+        round_trip(SaveFormat::MessagePack);
+    }
+}
@@ -6,119 +6,249 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
-use crate::entity::light_component::{Attenuation, LightComponent};
-use crate::entity::mesh_component::{MeshNode, Model, ModelComponent};
-use crate::entity::Entity;
-use crate::render_gl::data::InstanceTransformVertex;
-use crate::render_gl::objects::Buffer;
-use crate::render_gl::shaders::Program;
 use crate::*;
-use entity::camera_component::CameraComponent;
-use entity::transform_component::TransformComponent;
-use gl::Gl;
-use rand::Rng;
-use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
-use render_gl::shaders;
+use entity::transform_component::{GlobalTransformComponent, TransformComponent};
+use entity::{change_detection::Tick, EntityID, EntitySystem};
 
 use self::entity::hierarchy_component::HierarchyComponent;
 
-pub fn load_entities(scene: &mut GameState) {
-    let e = scene.gen_entity();
-    scene.add_component(
-        e,
-        TransformComponent::new_from_rot_trans(glam::Vec3::Y, glam::vec3(0.0, 0.0, -3.0), true),
-    );
-    scene.add_component(e, CameraComponent { fov: 90.0 });
-    scene.add_component(
-        e,
-        LightComponent::Spot {
-            color: glam::vec3(14.0, 16.0, 18.0),
-            ambient: glam::vec3(0.0, 0.0, 0.0),
-            cutoff: 0.0,
-            fade_exponent: 15.0,
-            attenuation: Attenuation {
-                constant: 0.2,
-                linear: 9.0,
-                quadratic: 1.9,
-            },
-        },
-    );
-    scene.register_camera(e);
-
-    let mut trng = rand::thread_rng();
-    let colors = &[
-        glam::vec3(0.1, 30.2, 0.1),
-        glam::vec3(20.2, 0.1, 0.1),
-        glam::vec3(0.1, 0.1, 10.2),
-    ];
-    for i in 0..30 {
-        let e = scene.gen_entity();
-        scene.add_component(
-            e,
-            TransformComponent::new_from_rot_trans(
-                glam::Vec3::ZERO,
-                glam::vec3(
-                    trng.gen_range(0..25) as f32,
-                    trng.gen_range(0..25) as f32 * 2.0,
-                    trng.gen_range(0..16) as f32,
-                ),
-                true,
-            ),
-        );
-        scene.add_component(
-            e,
-            LightComponent::Point {
-                color: colors[trng.gen_range(0..colors.len())],
-                ambient: glam::vec3(0.0, 0.0, 0.0),
-                attenuation: Attenuation {
-                    constant: 1.5,
-                    linear: 9.0,
-                    quadratic: 1.9,
+/// Recomputes the world transform of every entity whose own transform, or any
+/// of whose ancestors' transforms, changed after the tick `since`. Returns the
+/// world transforms that were updated.
+///
+/// Entities are processed in order of hierarchy depth, so by the time we get
+/// to any entity, its ancestors' world transforms are already up to date.
+/// Ancestors without a transform of their own are skipped over, so an entity
+/// follows the nearest ancestor that has one.
+pub fn propagate_transforms(entities: &EntitySystem, since: Tick) -> Vec<(EntityID, glam::Mat4)> {
+    let Some(tcs) = entities.get_component_vec::<TransformComponent>() else {
+        return vec![];
+    };
+    let mut gtcs = entities
+        .get_component_vec_mut::<GlobalTransformComponent>()
+        .expect("Entities with transforms must have global transforms");
+    let hcs = entities.get_component_vec::<HierarchyComponent>();
+
+    let mut order: Vec<(usize, EntityID, Option<EntityID>)> = tcs
+        .entities()
+        .iter()
+        .map(|eid| {
+            let hc = hcs.as_ref().and_then(|hcs| hcs.get(*eid));
+            (
+                hc.map_or(0, |hc| hc.depth),
+                *eid,
+                hc.and_then(|hc| hc.parent).map(|p| p.id),
+            )
+        })
+        .collect();
+    order.sort_by_key(|(depth, _, _)| *depth);
+
+    let mut updated: Vec<(EntityID, glam::Mat4)> = vec![];
+    let mut updated_set = std::collections::HashSet::new();
+    for (_, eid, parent) in order {
+        let tc = tcs.get(eid).unwrap();
+        let mut ancestor = parent;
+        while let Some(a) = ancestor {
+            if gtcs.get(a).is_some() {
+                break;
+            }
+            ancestor = hcs
+                .as_ref()
+                .and_then(|hcs| hcs.get(a))
+                .and_then(|hc| hc.parent)
+                .map(|p| p.id);
+        }
+
+        let changed = tcs.ticks(eid).unwrap().is_changed_since(since);
+        let ancestor_updated = ancestor.is_some_and(|a| updated_set.contains(&a));
+        if !changed && !ancestor_updated {
+            continue;
+        }
+
+        let parent_matrix = ancestor
+            .and_then(|a| gtcs.get(a))
+            .map_or(glam::Mat4::IDENTITY, |agtc| agtc.matrix);
+        let matrix = parent_matrix * tc.transform.to_matrix();
+
+        if let Some(mut gtc) = gtcs.get_mut(eid) {
+            gtc.matrix = matrix;
+        } else {
+            gtcs.insert(eid, GlobalTransformComponent { matrix });
+        }
+        updated.push((eid, matrix));
+        updated_set.insert(eid);
+    }
+
+    updated
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::entity::{transform_component::Transform, Entity};
+
+    fn spawn(entities: &mut EntitySystem, transform: Transform, parent: Option<Entity>) -> Entity {
+        let e = entities.gen_entity();
+        entities
+            .add_component(
+                e,
+                TransformComponent {
+                    transform,
+                    grounded: false,
                 },
-            },
-        );
+            )
+            .unwrap();
+        entities
+            .add_component(e, GlobalTransformComponent::default())
+            .unwrap();
+        if let Some(parent) = parent {
+            entities.set_parent(e, parent).unwrap();
+        }
+        e
     }
 
-    let parent = scene.gen_entity();
-    scene.add_component(
-        parent,
-        TransformComponent::new_from_rot_trans(glam::Vec3::ZERO, glam::Vec3::ZERO, false),
-    );
-    let data = ["./data/models/heroine.glb"];
-    for i in 0..10000 {
-        let thing = scene.gen_entity();
-        scene.add_component(
-            thing,
-            ModelComponent {
-                path: data[trng.gen_range(0..data.len())].to_string(),
-                shader_program: 0,
-            },
+    fn transform(trans: glam::Vec3, rot: glam::Quat, scale: glam::Vec3) -> Transform {
+        Transform { trans, rot, scale }
+    }
+
+    fn local(entities: &EntitySystem, e: Entity) -> glam::Mat4 {
+        entities
+            .get_component::<TransformComponent>(e)
+            .unwrap()
+            .transform
+            .to_matrix()
+    }
+
+    fn world(entities: &EntitySystem, e: Entity) -> glam::Mat4 {
+        entities
+            .get_component::<GlobalTransformComponent>(e)
+            .unwrap()
+            .matrix
+    }
+
+    /// Propagates everything that's changed since `since`, returning the IDs
+    /// that were updated and the tick to use next time.
+    fn propagate(entities: &EntitySystem, since: Tick) -> (Vec<EntityID>, Tick) {
+        let updated = propagate_transforms(entities, since)
+            .into_iter()
+            .map(|(id, _)| id)
+            .collect();
+        (updated, entities.increment_change_tick())
+    }
+
+    #[test]
+    fn propagates_through_three_levels() {
+        let mut entities = EntitySystem::new();
+        let grandparent = spawn(
+            &mut entities,
+            transform(
+                glam::vec3(1.0, 2.0, 3.0),
+                glam::Quat::from_rotation_y(0.5),
+                glam::vec3(2.0, 2.0, 2.0),
+            ),
+            None,
         );
-        if i < 100 {
-            scene.add_component(thing, HierarchyComponent::new(parent))
-        }
-        scene.add_component(
-            thing,
-            TransformComponent::new_from_rot_trans(
-                glam::Vec3::ZERO,
-                glam::vec3(
-                    (i % 25) as f32 * 1.0,
-                    ((i / 25) % 25) as f32 * 2.0,
-                    (i / 625) as f32 * 1.0,
-                ),
-                false,
+        let parent = spawn(
+            &mut entities,
+            transform(
+                glam::vec3(0.0, 1.0, -4.0),
+                glam::Quat::from_rotation_x(-1.2),
+                glam::vec3(1.0, 0.5, 3.0),
             ),
+            Some(grandparent),
         );
+        let child = spawn(
+            &mut entities,
+            transform(
+                glam::vec3(5.0, 0.0, 0.5),
+                glam::Quat::from_rotation_z(2.0),
+                glam::vec3(0.25, 1.0, 1.0),
+            ),
+            Some(parent),
+        );
+        // Another branch off the grandparent, and a whole other tree
+        let uncle = spawn(
+            &mut entities,
+            transform(glam::Vec3::X, glam::Quat::IDENTITY, glam::Vec3::ONE),
+            Some(grandparent),
+        );
+        let cousin = spawn(
+            &mut entities,
+            transform(glam::Vec3::Y, glam::Quat::IDENTITY, glam::Vec3::ONE),
+            Some(uncle),
+        );
+        let stranger = spawn(
+            &mut entities,
+            transform(glam::Vec3::Z, glam::Quat::IDENTITY, glam::Vec3::ONE),
+            None,
+        );
+
+        let (updated, since) = propagate(&entities, 0);
+        assert_eq!(updated.len(), 6);
+
+        let composed = |entities: &EntitySystem| {
+            local(entities, grandparent) * local(entities, parent) * local(entities, child)
+        };
+        assert!(world(&entities, child).abs_diff_eq(composed(&entities), 1e-5));
+
+        // Move the root: its whole tree follows, and nothing else
+        entities
+            .get_component_mut::<TransformComponent>(grandparent)
+            .unwrap()
+            .displace_by(glam::vec3(-3.0, 0.0, 7.0));
+        let (updated, since) = propagate(&entities, since);
+        for e in [grandparent, parent, child, uncle, cousin] {
+            assert!(updated.contains(&e.id));
+        }
+        assert!(!updated.contains(&stranger.id));
+        assert!(world(&entities, child).abs_diff_eq(composed(&entities), 1e-5));
+
+        // Move the middle: only it and what's below it get recomputed, not
+        // the sibling subtree
+        entities
+            .get_component_mut::<TransformComponent>(parent)
+            .unwrap()
+            .scale_by(glam::vec3(1.5, 1.5, 1.5));
+        let (updated, _) = propagate(&entities, since);
+        assert_eq!(updated.len(), 2);
+        assert!(updated.contains(&parent.id) && updated.contains(&child.id));
+        assert!(!updated.contains(&uncle.id) && !updated.contains(&cousin.id));
+        assert!(world(&entities, child).abs_diff_eq(composed(&entities), 1e-5));
     }
-}
 
-pub fn load_entity_models(scene: &mut GameState, new_entities: &Vec<Entity>) {}
+    #[test]
+    fn skips_ancestors_without_transforms() {
+        let mut entities = EntitySystem::new();
+        let grandparent = spawn(
+            &mut entities,
+            transform(
+                glam::vec3(1.0, 2.0, 3.0),
+                glam::Quat::from_rotation_y(0.5),
+                glam::Vec3::ONE,
+            ),
+            None,
+        );
+        // Just there to group things, with no transform
+        let parent = entities.gen_entity();
+        entities.set_parent(parent, grandparent).unwrap();
+        let child = spawn(
+            &mut entities,
+            transform(glam::Vec3::X, glam::Quat::IDENTITY, glam::Vec3::ONE),
+            Some(parent),
+        );
 
-pub fn physics(game_state: &mut GameState, dt: f32, time: u128) {
-    let transforms = &mut game_state
-        .entities
-        .get_component_vec_mut::<TransformComponent>()
-        .unwrap();
-    let e = &mut transforms[31];
-    e.as_mut().unwrap().displace_by(glam::vec3(0.0, 0.0, 0.005));
+        let (_, since) = propagate(&entities, 0);
+        let composed =
+            |entities: &EntitySystem| local(entities, grandparent) * local(entities, child);
+        assert!(world(&entities, child).abs_diff_eq(composed(&entities), 1e-5));
+
+        // Moving the grandparent still moves the child
+        entities
+            .get_component_mut::<TransformComponent>(grandparent)
+            .unwrap()
+            .displace_by(glam::vec3(-3.0, 0.0, 7.0));
+        let (updated, _) = propagate(&entities, since);
+        assert!(updated.contains(&child.id));
+        assert!(world(&entities, child).abs_diff_eq(composed(&entities), 1e-5));
+    }
 }
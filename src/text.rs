@@ -153,7 +153,7 @@ impl FontRenderer {
             &self.text_proj.to_cols_array(),
         );
         let mut advance = 0;
-        for (i, c) in string.chars().enumerate() {
+        for c in string.chars() {
             let ch = &self.get_char(c).unwrap();
 
             let x_pos = x + scale * (advance as f32 + ch.bearing.x as f32);
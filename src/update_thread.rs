@@ -6,65 +6,42 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
-use crossbeam_channel::{unbounded, Receiver, Sender};
-use gltf::scene::Transform;
-use rayon::slice::ParallelSlice;
+use crossbeam_channel::{Receiver, Sender};
+use rand::{rngs::StdRng, SeedableRng};
 use std::{
-    collections::{BinaryHeap, HashMap},
-    fmt::Debug,
+    collections::HashMap,
     ops::{Deref, DerefMut},
-    sync::{atomic::AtomicBool, Arc, RwLock},
-    thread::panicking,
+    sync::{atomic::AtomicBool, Arc},
+    thread::JoinHandle,
     time::Duration,
 };
 
 use crate::{
+    commands::CommandBuffer,
+    controllers::ControllerRequest,
     dead_drop::DeadDrop,
     entity::{
-        camera_component::CameraComponent, hierarchy_component::HierarchyComponent,
-        light_component::LightComponent, mesh_component::ModelComponent,
-        transform_component::TransformComponent, Component, EntityID,
+        camera_component::CameraComponent, light_component::LightComponent,
+        mesh_component::ModelComponent, transform_component::TransformComponent, Component,
+        EntityID,
     },
+    event_bus::{EventBus, EventPhase, EventWriter},
     events,
+    input::{FrameInput, InputState},
+    prefab::PrefabLibrary,
     render_thread::{light_component_to_shader_light, RenderCameraState, RenderWorldState},
+    replay::{ReplayMode, ReplayTiming},
     resource_manager::ResourceManager,
-    systems, utils, CONFIG,
+    resources::Resources,
+    scene,
+    scheduler::{Scheduler, System, SystemContext},
+    serialization::ComponentRegistry,
+    systems,
+    utils::config::GameConfig,
+    CONFIG,
 };
 
-use crate::entity::{Entity, EntitySystem};
-
-pub struct EntityTransformationUpdate {
-    eid: usize,
-    depth: usize,
-    matrix: glam::Mat4,
-    parent_matrix: Option<glam::Mat4>,
-}
-
-impl Ord for EntityTransformationUpdate {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        if self.depth < other.depth {
-            std::cmp::Ordering::Greater
-        } else if self.depth == other.depth {
-            std::cmp::Ordering::Less
-        } else {
-            std::cmp::Ordering::Equal
-        }
-    }
-}
-
-impl PartialOrd for EntityTransformationUpdate {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
-    }
-}
-
-impl PartialEq for EntityTransformationUpdate {
-    fn eq(&self, other: &Self) -> bool {
-        self.depth == other.depth && self.matrix == other.matrix && self.eid == other.eid
-    }
-}
-
-impl Eq for EntityTransformationUpdate {}
+use crate::entity::{change_detection::Tick, Entity, EntityError, EntitySystem};
 
 pub type Direction = glam::Vec3;
 pub type PitchYawRoll = glam::Vec3;
@@ -74,12 +51,42 @@ pub enum SceneCommand {
     DisplaceEntity(Entity, glam::Vec3),
 }
 
+/// Starts the update thread: sets up the game state, loads the initial
+/// scene, and runs the update loop until `running` is cleared (or a replay
+/// runs out).
+pub fn spawn_update_thread(
+    resource_manager: ResourceManager,
+    render_world_state: DeadDrop<RenderWorldState>,
+    event_receiver: Receiver<GameStateEvent>,
+    controller_sender: Sender<ControllerRequest>,
+    size: (u32, u32),
+    running: Arc<AtomicBool>,
+    setup: impl FnOnce(&mut GameState) + Send + 'static,
+) -> std::io::Result<JoinHandle<()>> {
+    std::thread::Builder::new()
+        .name("update".to_string())
+        .spawn(move || {
+            let pinned =
+                core_affinity::get_core_ids().map(|ids| core_affinity::set_for_current(ids[0]));
+            if !pinned.is_some_and(|p| p) {
+                warn!("Couldn't pin the update thread to a core");
+            }
+            let mut game_state = GameState::new(resource_manager);
+            setup(&mut game_state);
+            info!("Update thread started");
+            game_state.update_loop(
+                render_world_state,
+                event_receiver,
+                controller_sender,
+                size,
+                running,
+            );
+        })
+}
+
 pub enum GameStateEvent {
     SDLEvent(sdl2::event::Event),
-    FrameEvent(
-        Vec<(sdl2::keyboard::Scancode, bool)>,
-        sdl2::mouse::RelativeMouseState,
-    ),
+    FrameEvent(FrameInput),
 }
 
 pub struct Accessor<T> {
@@ -94,6 +101,20 @@ impl<T> Accessor<T> {
             inner: val,
         }
     }
+
+    /// Whether the value has been mutably accessed since it was created or
+    /// last marked clean.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty_flag
+    }
+
+    pub fn mark_clean(&mut self) {
+        self.dirty_flag = false;
+    }
+
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
 }
 
 impl<T> Deref for Accessor<T> {
@@ -116,20 +137,70 @@ pub struct GameState {
     pub lights: Accessor<Vec<Entity>>,
     pub command_queue: Accessor<Vec<SceneCommand>>,
     pub entities: EntitySystem,
-    transform_update_queue: BinaryHeap<EntityTransformationUpdate>,
+    pub scheduler: Scheduler,
+    /// Which component types get saved by `save_scene`
+    pub registry: ComponentRegistry,
+    /// Templates for `spawn_prefab`
+    pub prefabs: PrefabLibrary,
+    pub events: EventBus,
+    /// Engine-wide singletons, see `insert_resource`
+    pub resources: Resources,
+    /// Structural changes waiting for `update` to apply them
+    pub commands: CommandBuffer,
+    /// Actions and axes, as of this step
+    pub input: InputState,
+    /// Whether input is being recorded or replayed
+    pub replay: ReplayMode,
+    /// The config as of this step. The update loop keeps it up to date when
+    /// the config is reloaded.
+    pub config: Arc<GameConfig>,
+    /// World transforms updated since the last render state was sent
     entity_transforms: HashMap<EntityID, glam::Mat4>,
+    /// World transforms as of the last render state sent, of whatever's
+    /// moved since
+    previous_transforms: HashMap<EntityID, glam::Mat4>,
+    /// The last known world transform of everything, along with the
+    /// generation of the entity it belonged to
+    world_transforms: HashMap<EntityID, (usize, glam::Mat4)>,
+    /// Where the camera was looking from as of the last step, and as of the
+    /// last render state sent
+    camera_view: Option<(Entity, glam::Mat4)>,
+    previous_camera_view: Option<(Entity, glam::Mat4)>,
+    /// Change ticks as of the last transform propagation and the last render
+    /// state sent, so each only has to look at what's changed since
+    last_propagation: Tick,
+    last_render_sync: Tick,
 }
 
 impl GameState {
     pub fn new(resource_manager: ResourceManager) -> Self {
+        // Anything random in the game should come from here, so that it can
+        // be reseeded for replays
+        let mut resources = Resources::default();
+        resources.insert(StdRng::from_entropy());
+        let config = CONFIG.load();
         Self {
             resource_manager,
             entity_transforms: HashMap::new(),
+            previous_transforms: HashMap::new(),
+            world_transforms: HashMap::new(),
+            camera_view: None,
+            previous_camera_view: None,
+            last_propagation: 0,
+            last_render_sync: 0,
             camera: Accessor::new(None),
             command_queue: Accessor::new(vec![]),
             entities: EntitySystem::new(),
+            scheduler: Scheduler::new(),
+            registry: ComponentRegistry::default(),
+            prefabs: PrefabLibrary::default(),
+            events: EventBus::default(),
+            resources,
+            commands: CommandBuffer::default(),
+            input: InputState::from_config(&config.controls),
+            replay: ReplayMode::Live,
+            config,
             lights: Accessor::new(vec![]),
-            transform_update_queue: BinaryHeap::new(),
         }
     }
 
@@ -137,9 +208,49 @@ impl GameState {
         self.entities.gen_entity()
     }
 
-    pub fn add_component<T: Component + 'static>(&mut self, e: Entity, mut c: T) {
-        c.add_hook(e, self);
-        self.entities.add_component(e, c);
+    pub fn add_component<T: Component + 'static>(
+        &mut self,
+        e: Entity,
+        mut c: T,
+    ) -> Result<(), EntityError> {
+        // Don't let hooks go poking at the world on behalf of a dead entity
+        self.entities.check_alive(e)?;
+        match self.entities.remove_component::<T>(e)? {
+            Some(old) => c.replace_hook(old, e, self),
+            None => c.add_hook(e, self),
+        }
+        self.entities.add_component(e, c)
+    }
+
+    /// Takes the component off the entity, runs its remove hook, and hands
+    /// it back, if the entity had one.
+    pub fn remove_component<T: Component + 'static>(
+        &mut self,
+        e: Entity,
+    ) -> Result<Option<T>, EntityError> {
+        let mut removed = self.entities.remove_component::<T>(e)?;
+        if let Some(c) = removed.as_mut() {
+            c.remove_hook(e, self);
+        }
+        Ok(removed)
+    }
+
+    /// Deletes the entity, running the remove hooks of all of its components.
+    pub fn delete_entity(&mut self, e: Entity) -> Result<(), EntityError> {
+        for hook in self.entities.take_all_components(e)? {
+            hook(e, self);
+        }
+        self.entities.delete_entity(e)
+    }
+
+    /// Deletes the entity and everything below it in the hierarchy, running
+    /// remove hooks for all of them.
+    pub fn despawn_recursive(&mut self, e: Entity) -> Result<(), EntityError> {
+        self.entities.check_alive(e)?;
+        for descendant in self.entities.descendants_of(e).into_iter().rev() {
+            self.delete_entity(descendant)?;
+        }
+        self.delete_entity(e)
     }
 
     /// Adds an entity to the list of entities we're treating as active light
@@ -148,47 +259,80 @@ impl GameState {
         self.lights.push(e);
     }
 
+    /// Stops treating the entity as a light source.
+    pub fn deregister_light(&mut self, e: Entity) {
+        self.lights.retain(|l| *l != e);
+    }
+
     /// Sets the current camera to the provided entity (assumes it has a camera and transform component)
     pub fn register_camera(&mut self, e: Entity) {
         self.camera.replace(e);
     }
 
+    /// Leaves the world without a camera, if the entity was the current one.
+    pub fn deregister_camera(&mut self, e: Entity) {
+        if *self.camera == Some(e) {
+            self.camera.take();
+        }
+    }
+
     // Sends a request to load whatever model the given entity has
     pub fn load_model_for(&mut self, e: Entity, c: &ModelComponent) {
         self.resource_manager
             .request_models(vec![(c.path.clone(), e)]);
     }
 
+    // Tells the resource manager the entity isn't using its model anymore
+    pub fn unload_model_for(&mut self, e: Entity, c: &ModelComponent) {
+        self.resource_manager
+            .request_unload_models(vec![(c.path.clone(), e)]);
+    }
+
     /// Queue world state changes
     pub fn queue_commands(&mut self, cs: Vec<SceneCommand>) {
         self.command_queue.extend(cs);
     }
 
     pub fn move_camera_by_vector(&mut self, d: Direction, dt: f32) {
-        let camera_entity = self.camera.expect("No camera found");
-        let mut camera_transform = self
+        let Some(camera_entity) = *self.camera else {
+            return;
+        };
+        // The camera may have been despawned out from under us
+        let Ok(mut camera_transform) = self
             .entities
             .get_component_mut::<TransformComponent>(camera_entity)
-            .expect("Camera needs to have TransformComponent");
+        else {
+            return;
+        };
 
-        camera_transform.displace_by(d * CONFIG.controls.motion_speed * (dt as f32 / 1000.0));
+        camera_transform.displace_by(d * self.config.controls.motion_speed * (dt as f32 / 1000.0));
     }
 
     pub fn rotate_camera(&mut self, pyr: PitchYawRoll, dt: f32) {
-        let camera_entity = self.camera.expect("No camera found");
-        let mut camera_transform = self
+        let Some(camera_entity) = *self.camera else {
+            return;
+        };
+        // The camera may have been despawned out from under us
+        let Ok(mut camera_transform) = self
             .entities
             .get_component_mut::<TransformComponent>(camera_entity)
-            .expect("Camera needs to have TransformComponent");
+        else {
+            return;
+        };
 
-        camera_transform.rotate(pyr * CONFIG.controls.mouse_sensitivity * dt as f32 / 1000.0);
+        camera_transform.rotate(pyr * self.config.controls.mouse_sensitivity * dt as f32 / 1000.0);
     }
 
     pub fn displace_entity(&mut self, entity: Entity, rel_vec: glam::Vec3) {
-        self.entities
+        // Commands can outlive the entity they were about, or name one
+        // without a transform
+        let Ok(mut transform) = self
+            .entities
             .get_component_mut::<TransformComponent>(entity)
-            .expect("Displaced entity must have transform component")
-            .displace_by(rel_vec);
+        else {
+            return;
+        };
+        transform.displace_by(rel_vec);
     }
 
     /// Apply queued world state changes to the world state
@@ -205,169 +349,275 @@ impl GameState {
                 }
             }
         }
+
+        // Now that nothing's borrowing component storage, make whatever
+        // structural changes systems asked for
+        self.apply_commands();
+    }
+
+    /// Loads the prefabs in `prefabs` (if there is such a directory), then
+    /// the scene, which can use them.
+    pub fn load_initial_entities(&mut self, prefabs: &str, scene: &str) {
+        match PrefabLibrary::load_dir(prefabs) {
+            Ok(prefabs) => self.prefabs = prefabs,
+            Err(e) => panic!("Couldn't load prefabs: {}", e),
+        }
+        if let Err(e) = scene::load_scene(self, scene) {
+            panic!("Couldn't load initial scene: {}", e);
+        }
     }
 
-    pub fn load_initial_entities(&mut self) {
-        systems::load_entities(self);
+    /// Adds a system to be run every fixed update step. See `Scheduler` for
+    /// how systems get ordered and parallelized.
+    pub fn add_system(&mut self, system: System) {
+        self.scheduler.add_system(system);
     }
 
+    /// Switches over to a new config, including the input bindings in it.
+    pub fn set_config(&mut self, config: Arc<GameConfig>) {
+        if let Some(timing) = self.replay.timing() {
+            if timing != ReplayTiming::from_config(&config) {
+                warn!(
+                    "Step timing changed, but is being held at {:?} until the recording or replay is over",
+                    timing
+                );
+            }
+        }
+        self.input.configure(&config.controls);
+        self.config = config;
+    }
+
+    /// Whether anything the renderer cares about has changed since the last
+    /// render state was sent. Camera and light movement shows up as updated
+    /// world transforms.
     pub fn any_changed(&self) -> bool {
+        let since = self.last_render_sync;
         self.camera.dirty_flag
             || self.lights.dirty_flag
-            || self.entities.dirty()
-            || self.command_queue.dirty_flag
+            || !self.entity_transforms.is_empty()
+            || self.entities.any_changed_since::<LightComponent>(since)
+            || self.entities.any_changed_since::<CameraComponent>(since)
+    }
+
+    /// Advances the simulation by one fixed step: input, systems, actor
+    /// events, queued changes, and transform propagation, with the event
+    /// bus's phases in between.
+    fn step(&mut self, dt: f32, time: u128) {
+        events::handle_camera_controls(self, dt);
+        self.dispatch_events(EventPhase::PreUpdate);
+
+        let system_events = EventWriter::default();
+        self.scheduler.run(&SystemContext {
+            entities: &self.entities,
+            dt,
+            time,
+            last_run: 0,
+            events: &system_events,
+            commands: &self.commands,
+            input: &self.input,
+            resources: &self.resources,
+        });
+        self.events.extend(system_events);
+
+        self.entities.process_actor_events();
+        self.update(dt);
+        self.dispatch_events(EventPhase::PostUpdate);
+
+        self.propagate_transforms();
+        self.dispatch_events(EventPhase::PreRender);
+
+        // Anything pressed or released has been seen by this step, and
+        // shouldn't be seen again by the next one
+        self.input.finish_step();
+    }
+
+    /// Brings world transforms up to date, keeping track of where everything
+    /// that moved was as of the last render state sent, so the renderer can
+    /// interpolate from there. Several steps can run between render states,
+    /// so only the first previous transform counts.
+    fn propagate_transforms(&mut self) {
+        let changed = systems::propagate_transforms(&self.entities, self.last_propagation);
+        self.last_propagation = self.entities.increment_change_tick();
+
+        for (id, transform) in changed {
+            let generation = self.entities.entity_generations[id];
+            // Recycled IDs shouldn't look like they came from wherever the
+            // last entity with the ID was
+            let previous = match self.world_transforms.insert(id, (generation, transform)) {
+                Some((g, previous)) if g == generation => previous,
+                _ => transform,
+            };
+            self.previous_transforms.entry(id).or_insert(previous);
+            self.entity_transforms.insert(id, transform);
+        }
+
+        self.camera_view = self.camera.and_then(|camera| {
+            let ct = self
+                .entities
+                .get_component::<TransformComponent>(camera)
+                .ok()?;
+            Some((camera, ct.point_of_view()))
+        });
+    }
+
+    /// `alpha` is how far between the last step and the next one we are, in
+    /// real time.
+    fn send_render_state(
+        &mut self,
+        rws_sender: &DeadDrop<RenderWorldState>,
+        (width, height): (u32, u32),
+        alpha: f32,
+        step: f32,
+    ) {
+        // The camera can go away (its component removed or its entity
+        // deleted), in which case there's just nothing to look through until
+        // another one is registered
+        let active_camera = self.camera.and_then(|camera| {
+            let cc = self
+                .entities
+                .get_component::<CameraComponent>(camera)
+                .ok()?;
+            let ct = self
+                .entities
+                .get_component::<TransformComponent>(camera)
+                .ok()?;
+            let view = ct.point_of_view();
+            let previous_view = match self.previous_camera_view {
+                Some((previous_camera, previous_view)) if previous_camera == camera => {
+                    previous_view
+                }
+                _ => view,
+            };
+            Some(RenderCameraState {
+                view,
+                previous_view,
+                proj: cc.project(width, height),
+            })
+        });
+
+        // Only the transforms that changed get sent, so if the renderer
+        // hasn't picked up the last state yet, fold this one into it instead
+        // of dropping those changes
+        rws_sender.send_or_merge(
+            RenderWorldState {
+                // Same goes for lights, which just stop shining
+                lights: self
+                    .lights
+                    .iter()
+                    .filter_map(|e| {
+                        let lc = self.entities.get_component::<LightComponent>(*e).ok()?;
+                        let tc = self.entities.get_component::<TransformComponent>(*e).ok()?;
+                        Some(light_component_to_shader_light(&lc, &tc))
+                    })
+                    .collect(),
+                active_camera,
+                entity_generations: self.entities.entity_generations.clone(),
+                entity_transforms: std::mem::take(&mut self.entity_transforms),
+                previous_transforms: std::mem::take(&mut self.previous_transforms),
+                alpha,
+                step,
+            },
+            RenderWorldState::merge_unsent,
+        );
+        self.previous_camera_view = self.camera_view;
+        self.last_render_sync = self.entities.increment_change_tick();
+        self.lights.dirty_flag = false;
+        self.camera.dirty_flag = false;
     }
 
     pub fn update_loop(
         mut self,
         rws_sender: DeadDrop<RenderWorldState>,
         event_receiver: Receiver<GameStateEvent>,
+        controller_sender: Sender<ControllerRequest>,
 
         (width, height): (u32, u32),
 
         running: Arc<AtomicBool>,
     ) {
-        let interval = CONFIG.performance.update_interval as f32;
+        // Subscribe before looking at the config, so no reload gets missed
+        let config_changes = CONFIG.subscribe();
+        self.set_config(CONFIG.load());
 
         let time = std::time::Instant::now();
-        let start_time = time.elapsed().as_millis();
         let mut last_time = time.elapsed().as_millis();
         let mut dt: f32;
         let mut lag = 0.0;
+        let mut tick: u64 = 0;
+        // Simulated time, counted up from the steps themselves rather than
+        // the clock, so replays see the same times the recording did
+        let mut elapsed: u128 = 0;
         while running.load(std::sync::atomic::Ordering::SeqCst) {
+            if let Some(config) = config_changes.try_iter().last() {
+                self.set_config(config);
+            }
+            let timing = self.step_timing();
+            let interval = timing.update_interval as f32;
+            let max_steps = timing.max_catch_up_steps;
+
             let current_time = time.elapsed().as_millis();
             dt = (current_time - last_time) as f32;
-            lag += dt;
             last_time = current_time;
 
-            let missed_frames = (lag / interval).round() as usize;
-            let events = event_receiver.try_iter().collect::<Vec<_>>();
-            // Catch up with things that require a maximum step size to be stable
-            while lag > interval {
-                let delta_time = lag.min(interval);
-                systems::physics(&mut self, delta_time, current_time - start_time);
+            let events = event_receiver.try_iter().collect();
+            let Some((tick_dt, events)) = self.replay_tick(tick, dt, events) else {
+                info!("Replay finished after {} updates", tick);
+                running.store(false, std::sync::atomic::Ordering::SeqCst);
+                break;
+            };
+            dt = tick_dt;
+            lag += dt;
+            tick += 1;
 
-                lag -= interval;
+            // Input is kept as state rather than acted on event by event, so
+            // everything that's come in since the last step counts, even if
+            // no step runs this time around
+            for event in events {
+                events::handle_event(&mut self, event);
             }
-            for event in events.into_iter().rev().take(missed_frames).rev() {
-                match event {
-                    GameStateEvent::FrameEvent(scancodes, mouse_state) => {
-                        events::handle_keyboard(&mut self, scancodes, dt);
-                        events::handle_mouse(&mut self, &mouse_state, dt);
-                    }
-                    _ => events::handle_event(&mut self, event, dt),
+
+            // Catch up on however many fixed steps have built up, unless
+            // we've fallen so far behind that catching up would just make
+            // us fall further behind
+            let mut steps = 0;
+            while lag >= interval {
+                if steps == max_steps {
+                    warn!(
+                        "Update loop fell behind, skipping {} ms of simulation",
+                        lag - lag % interval
+                    );
+                    lag %= interval;
+                    break;
                 }
+                self.step(interval, elapsed);
+                elapsed += interval as u128;
+                lag -= interval;
+                steps += 1;
             }
 
-            if self.entities.dirty() {
-                let mut tcs = self
-                    .entities
-                    .get_component_vec_mut::<TransformComponent>()
-                    .unwrap();
-                let hcs = self.entities.get_component_vec::<HierarchyComponent>();
-                for eid in 0..tcs.len() {
-                    let (a, b) = tcs.split_at_mut(eid);
-                    let (item, c) = b.split_at_mut(1);
-                    if let Some(tc) = &mut item[0] {
-                        // We can't use a normal get_component::<T> because we
-                        // need to access the parent from the split transform
-                        // arrays above, so we have to do a little
-                        // monad-juggling
-                        let hc = hcs.as_ref().and_then(|hcs| hcs[eid].as_ref());
-                        if let Some(parent) = hc.and_then(|hc| {
-                            // If there is a hierarchy component, get the parent on it
-                            let p_ref = hc.parent;
-                            // Look up the parent's ID in the left slice, and if it isn't there, in the right slice.
-                            a.get_mut(p_ref.id).or(c.get_mut(p_ref.id)).and_then(|ptc| {
-                                // If there's a row with that ID in one of those
-                                // slices, only return Some if that row actually
-                                // had Some in it *and* the generation matches
-                                ptc.as_mut().filter(|_| {
-                                    self.entities
-                                        .entity_generations
-                                        .get(&p_ref.id)
-                                        .map(|gen| *gen == p_ref.generation)
-                                        .unwrap_or(false)
-                                })
-                            })
-                        }) {
-                            if tc.dirty_flag || parent.dirty_flag {
-                                self.transform_update_queue
-                                    .push(EntityTransformationUpdate {
-                                        depth: hc.map_or(0, |x| x.depth),
-                                        eid,
-                                        matrix: tc.transform.to_matrix(),
-                                        parent_matrix: Some(parent.transform.to_matrix()),
-                                    });
-                            }
-                        } else {
-                            if tc.dirty_flag {
-                                self.transform_update_queue
-                                    .push(EntityTransformationUpdate {
-                                        depth: 0,
-                                        eid,
-                                        matrix: tc.transform.to_matrix(),
-                                        parent_matrix: None,
-                                    });
-                            }
-                        }
-                    }
-                    for update in self.transform_update_queue.drain() {
-                        self.entity_transforms.insert(
-                            update.eid,
-                            if let Some(pm) = update.parent_matrix {
-                                update.matrix * pm
-                            } else {
-                                update.matrix
-                            },
-                        );
-                    }
-                }
+            for request in self.input.take_controller_requests() {
+                let _ = controller_sender.send(request);
             }
 
-            // Catch up with events
-
-            if self.any_changed() {
-                let camera = self.camera.expect("Must have camera");
-                let cc = self
-                    .entities
-                    .get_component::<CameraComponent>(camera)
-                    .expect("Camera must still exist and have camera component!");
-                let ct = self
-                    .entities
-                    .get_component::<TransformComponent>(camera)
-                    .expect("Camera must still exist and have transform component!");
-
-                rws_sender.send(RenderWorldState {
-                    lights: self
-                        .lights
-                        .iter()
-                        .map(|e| {
-                            let lc = self.entities.get_component::<LightComponent>(*e).unwrap();
-                            let tc = self
-                                .entities
-                                .get_component::<TransformComponent>(*e)
-                                .unwrap();
-                            light_component_to_shader_light(&lc, &tc)
-                        })
-                        .collect(),
-                    active_camera: Some(RenderCameraState {
-                        view: ct.point_of_view(),
-                        proj: cc.project(width, height),
-                    }),
-                    entity_generations: self.entities.entity_generations.clone(),
-                    entity_transforms: self.entity_transforms.clone(),
-                });
-                self.lights.dirty_flag = false;
-                self.camera.dirty_flag = false;
-                self.entities.clear_dirty();
+            if steps > 0 && self.any_changed() {
+                self.send_render_state(&rws_sender, (width, height), lag / interval, interval);
             }
 
+            // Every system has seen removals up to the last time it ran, so
+            // nobody needs to hear about them anymore
+            let seen_by_all = self
+                .scheduler
+                .oldest_last_run()
+                .unwrap_or_else(|| self.entities.change_tick());
+            self.entities.clear_removed_through(seen_by_all);
+
             // Cap update FPS (ignores option not to because it really doesn't even function right if you do that)
-            let sleep_time = interval - dt;
-            if sleep_time > 0.0 {
+            let sleep_time = interval - lag;
+            if sleep_time > 0.0 && !self.replay.is_replaying() {
                 std::thread::sleep(Duration::from_millis(sleep_time as u64));
             }
         }
+
+        self.finish_replay();
     }
 }
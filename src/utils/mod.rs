@@ -6,27 +6,9 @@
  * file, You can obtain one at http://mozilla.org/MPL/2.0/.
  */
 
-use std::{cell::RefMut, collections::HashMap, ffi::CString};
-
-use gl::Gl;
-use glam::Vec4Swizzles;
-
-use crate::{
-    entity::{
-        camera_component::CameraComponent,
-        light_component::*,
-        transform_component::{self, TransformComponent},
-        Entity, EntityID, EntitySystem,
-    },
-    render_gl::{
-        objects::{Buffer, BufferObject},
-        shaders::{self, Program},
-    },
-    render_thread::{
-        light_component_to_shader_light, RenderCameraState, RendererState, ShaderLight,
-    },
-    CONFIG,
-};
+use std::{collections::HashMap, ffi::CString};
+
+use crate::entity::{Entity, EntityID};
 
 pub type Degrees = f32;
 pub type Radians = f32;
@@ -46,12 +28,62 @@ macro_rules! zip {
 }
 pub use zip;
 
+/// Lays `layer` over `base`. Tables get merged key by key, so a layer only
+/// replaces what it actually mentions; anything else just replaces whatever
+/// was there. Used for both config layers and prefab inheritance.
+pub fn merge(base: &mut toml::Table, layer: toml::Table) {
+    for (key, value) in layer {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(layer)) => merge(base, layer),
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
 pub mod config {
+    //! The game's settings, put together from several layers, each of which
+    //! only has to mention what it wants to change from the one before:
+    //!
+    //! 1. the defaults built into the engine
+    //! 2. the game's own config, `./data/config.toml` unless `--config`
+    //!    points somewhere else
+    //! 3. the player's config, `$XDG_CONFIG_HOME/embryo/config.toml` (or
+    //!    `~/.config/embryo/config.toml`)
+    //! 4. `--set section.key=value` on the command line
+    //!
+    //! Files that don't exist are skipped. Once everything's merged, every
+    //! value is checked, and all the bad ones are reported together.
+    //!
+    //! The config files are watched while the game runs (see `watch`), and
+    //! reloaded whenever they change. Anything holding onto the config can
+    //! `CONFIG.subscribe()` to hear about it.
+
+    use super::merge;
+    use crossbeam_channel::{unbounded, Receiver, Sender};
     use serde::Deserialize;
-    use std::io::prelude::*;
+    use std::{
+        collections::HashMap,
+        fmt::{self, Display},
+        path::{Path, PathBuf},
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc, Mutex, OnceLock, RwLock,
+        },
+        thread::JoinHandle,
+        time::{Duration, SystemTime},
+    };
+
+    pub const DEFAULT_CONFIG_PATH: &str = "./data/config.toml";
+
     #[derive(Deserialize)]
+    #[serde(default)]
     pub struct PerfConfig {
         pub update_interval: usize,
+        /// How many fixed steps the update loop will run at once to catch up
+        /// after falling behind, before it gives up and skips ahead
+        pub max_catch_up_steps: usize,
         pub cap_update_fps: bool,
         pub cap_render_fps: bool,
         pub max_batch_size: usize,
@@ -60,20 +92,132 @@ pub mod config {
         pub max_quadtree_entities: usize,
     }
 
+    impl Default for PerfConfig {
+        fn default() -> Self {
+            Self {
+                update_interval: 16,
+                max_catch_up_steps: 5,
+                cap_update_fps: true,
+                cap_render_fps: true,
+                max_batch_size: 1000,
+                max_lights: 32,
+                max_quadtree_depth: 6,
+                max_quadtree_entities: 30,
+            }
+        }
+    }
+
     #[derive(Deserialize)]
+    #[serde(default)]
     pub struct ControlConfig {
         pub mouse_sensitivity: f32,
         pub motion_speed: f32,
+        /// How far gamepad sticks (and triggers) have to be pushed, from 0.0
+        /// to 1.0, before they count
+        pub stick_deadzone: f32,
+        pub trigger_deadzone: f32,
+        /// Named buttons, each with a list of bindings like `"Key:E"`,
+        /// `"Mouse:Left"`, or `"Pad:a"` (see `input::InputButton`)
+        pub actions: HashMap<String, Vec<String>>,
+        /// Named axes, each with a list of bindings that get added together
+        pub axes: HashMap<String, Vec<AxisBindingConfig>>,
     }
 
-    #[derive(Deserialize)]
+    impl Default for ControlConfig {
+        fn default() -> Self {
+            Self {
+                mouse_sensitivity: 1.0,
+                motion_speed: 10.0,
+                stick_deadzone: 0.2,
+                trigger_deadzone: 0.1,
+                actions: default_actions(),
+                axes: default_axes(),
+            }
+        }
+    }
+
+    /// Either a pair of buttons, one pushing the axis each way, or an
+    /// analog axis like `"Mouse:X"` or `"Pad:leftx"`.
+    #[derive(Deserialize, Clone)]
+    #[serde(untagged)]
+    pub enum AxisBindingConfig {
+        Buttons {
+            positive: String,
+            negative: String,
+        },
+        Analog {
+            axis: String,
+            #[serde(default)]
+            invert: bool,
+            #[serde(default = "default_axis_scale")]
+            scale: f32,
+        },
+    }
+
+    fn default_axis_scale() -> f32 {
+        1.0
+    }
+
+    fn default_actions() -> HashMap<String, Vec<String>> {
+        let action = |name: &str, bindings: &[&str]| {
+            (
+                name.to_string(),
+                bindings.iter().map(|b| b.to_string()).collect(),
+            )
+        };
+        HashMap::from([
+            action("Use", &["Key:Return", "Mouse:Left", "Pad:a"]),
+            action("Crouch", &["Key:Left Ctrl", "Pad:b"]),
+        ])
+    }
+
+    fn default_axes() -> HashMap<String, Vec<AxisBindingConfig>> {
+        let buttons = |positive: &str, negative: &str| AxisBindingConfig::Buttons {
+            positive: positive.to_string(),
+            negative: negative.to_string(),
+        };
+        let analog = |axis: &str, invert: bool, scale: f32| AxisBindingConfig::Analog {
+            axis: axis.to_string(),
+            invert,
+            scale,
+        };
+        HashMap::from([
+            (
+                "MoveForward".to_string(),
+                vec![buttons("Key:W", "Key:S"), analog("Pad:lefty", true, 1.0)],
+            ),
+            (
+                "MoveRight".to_string(),
+                vec![buttons("Key:D", "Key:A"), analog("Pad:leftx", false, 1.0)],
+            ),
+            ("MoveUp".to_string(), vec![buttons("Key:E", "Key:F")]),
+            (
+                "LookX".to_string(),
+                vec![
+                    analog("Mouse:X", false, 1.0),
+                    analog("Pad:rightx", false, 10.0),
+                ],
+            ),
+            (
+                "LookY".to_string(),
+                vec![
+                    analog("Mouse:Y", false, 1.0),
+                    analog("Pad:righty", false, 10.0),
+                ],
+            ),
+        ])
+    }
+
+    #[derive(Deserialize, Default, PartialEq, Debug)]
     pub enum WindowMode {
         Windowed,
+        #[default]
         WindowedFullscreen,
         Fullscreen,
     }
 
     #[derive(Deserialize)]
+    #[serde(default)]
     pub struct GraphicsConfig {
         pub min_log_luminence: f32,
         pub max_log_luminence: f32,
@@ -90,83 +234,397 @@ pub mod config {
         pub attenuation_cutoff: f32,
     }
 
-    #[derive(Deserialize)]
+    impl Default for GraphicsConfig {
+        fn default() -> Self {
+            Self {
+                min_log_luminence: -8.0,
+                max_log_luminence: 3.5,
+                auto_exposure_speed_factor: 1.1,
+                bloom: true,
+                min_bloom_threshold: 0.8,
+                max_bloom_threshold: 1.2,
+                bloom_factor: 1.0,
+                scene_factor: 1.0,
+                fullscreen_mode: WindowMode::default(),
+                fxaa: true,
+                window_width: 1920,
+                window_height: 1080,
+                attenuation_cutoff: 51.2,
+            }
+        }
+    }
+
+    #[derive(Deserialize, Default)]
+    #[serde(default)]
     pub struct GameConfig {
         pub performance: PerfConfig,
         pub controls: ControlConfig,
         pub graphics: GraphicsConfig,
     }
 
-    pub fn read_config() -> GameConfig {
-        let mut contents = String::new();
-        info!("Loading configuration file at ./data/config.toml");
-        if let Ok(mut file) = std::fs::OpenOptions::new()
-            .create(true)
-            .read(true)
-            .write(true)
-            .open("./data/config.toml")
-        {
-            file.read_to_string(&mut contents).unwrap();
-            if contents.len() == 0 {
-                warn!("Configuration file was empty, inserting fresh contents.");
-                contents = r#"
-[performance]
-update_interval = 16
-cap_render_fps = true
-cap_update_fps = true
-max_batch_size = 1000
-max_lights = 32
-max_quadtree_depth = 6
-max_quadtree_entities = 30
-
-[graphics]
-min_log_luminence = -8.0
-max_log_luminence = 3.5
-auto_exposure_speed_factor = 1.1
-bloom = true
-min_bloom_threshold = 0.8
-max_bloom_threshold = 1.2
-bloom_factor = 1.0
-scene_factor = 1.0
-fxaa = true
-fullscreen_mode = "WindowedFullscreen"
-window_width = 1920
-window_height = 1080
-attenuation_cutoff = 51.2
-
-[controls]
-mouse_sensitivity = 1.0
-motion_speed = 10.0
-"#
-                .into();
-                file.write(contents.as_bytes()).unwrap();
-                warn!("de novo configuration file created");
-            } else {
-                debug!("Configuration file contents:\n {contents}");
+    /// A value that's out of bounds, and what it should have been.
+    #[derive(Debug, Clone)]
+    pub struct InvalidValue {
+        pub field: &'static str,
+        pub value: String,
+        pub allowed: &'static str,
+    }
+
+    impl Display for InvalidValue {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(
+                f,
+                "{} is {}, but must be {}",
+                self.field, self.value, self.allowed
+            )
+        }
+    }
+
+    impl GameConfig {
+        /// Checks every value that has limits, returning all the ones that are
+        /// outside them.
+        pub fn validate(&self) -> Vec<InvalidValue> {
+            let mut invalid = vec![];
+            let mut check = |field, value: &dyn Display, ok: bool, allowed| {
+                if !ok {
+                    invalid.push(InvalidValue {
+                        field,
+                        value: value.to_string(),
+                        allowed,
+                    });
+                }
+            };
+
+            let perf = &self.performance;
+            check(
+                "performance.update_interval",
+                &perf.update_interval,
+                (1..=33).contains(&perf.update_interval),
+                "between 1 and 33",
+            );
+            check(
+                "performance.max_catch_up_steps",
+                &perf.max_catch_up_steps,
+                perf.max_catch_up_steps >= 1,
+                "at least 1",
+            );
+            check(
+                "performance.max_batch_size",
+                &perf.max_batch_size,
+                perf.max_batch_size >= 1,
+                "at least 1",
+            );
+            check(
+                "performance.max_lights",
+                &perf.max_lights,
+                (1..=32).contains(&perf.max_lights),
+                "between 1 and 32",
+            );
+            check(
+                "performance.max_quadtree_depth",
+                &perf.max_quadtree_depth,
+                perf.max_quadtree_depth >= 4,
+                "at least 4",
+            );
+            check(
+                "performance.max_quadtree_entities",
+                &perf.max_quadtree_entities,
+                (10..=1000).contains(&perf.max_quadtree_entities),
+                "between 10 and 1000",
+            );
+
+            let controls = &self.controls;
+            check(
+                "controls.mouse_sensitivity",
+                &controls.mouse_sensitivity,
+                controls.mouse_sensitivity >= 1.0,
+                "at least 1.0",
+            );
+            check(
+                "controls.motion_speed",
+                &controls.motion_speed,
+                controls.motion_speed > 0.0,
+                "greater than 0.0",
+            );
+            check(
+                "controls.stick_deadzone",
+                &controls.stick_deadzone,
+                (0.0..1.0).contains(&controls.stick_deadzone),
+                "at least 0.0 and less than 1.0",
+            );
+            check(
+                "controls.trigger_deadzone",
+                &controls.trigger_deadzone,
+                (0.0..1.0).contains(&controls.trigger_deadzone),
+                "at least 0.0 and less than 1.0",
+            );
+
+            invalid
+        }
+
+        /// The settings that differ between this config and `new` that only
+        /// get looked at when the game starts, so changing them won't do
+        /// anything until it's restarted.
+        pub fn needs_restart(&self, new: &GameConfig) -> Vec<&'static str> {
+            let mut changed = vec![];
+            let mut check = |field, differs: bool| {
+                if differs {
+                    changed.push(field);
+                }
+            };
+            check(
+                "graphics.fullscreen_mode",
+                self.graphics.fullscreen_mode != new.graphics.fullscreen_mode,
+            );
+            check(
+                "graphics.window_width",
+                self.graphics.window_width != new.graphics.window_width,
+            );
+            check(
+                "graphics.window_height",
+                self.graphics.window_height != new.graphics.window_height,
+            );
+            check(
+                "performance.cap_render_fps",
+                self.performance.cap_render_fps != new.performance.cap_render_fps,
+            );
+            changed
+        }
+    }
+
+    /// The current config, which can be swapped out from under everyone
+    /// using it. `load` hands out the config as it is right then, which
+    /// stays the same for as long as you hang onto it, so hang onto it for
+    /// as long as you need things to stay consistent (a step, a frame) and
+    /// then get a fresh one.
+    pub struct ConfigHandle {
+        current: RwLock<Arc<GameConfig>>,
+        subscribers: Mutex<Vec<Sender<Arc<GameConfig>>>>,
+    }
+
+    impl ConfigHandle {
+        pub fn new(config: GameConfig) -> Self {
+            Self {
+                current: RwLock::new(Arc::new(config)),
+                subscribers: Mutex::new(vec![]),
             }
         }
-        let config: GameConfig = toml::from_str(&contents).unwrap();
-        if config.performance.update_interval > 33
-            || config.performance.max_batch_size < 1
-            || (config.performance.max_lights > 32 || config.performance.max_lights < 1)
-            || config.controls.mouse_sensitivity < 1.0
-            || config.controls.motion_speed <= 0.0
-            || config.performance.max_quadtree_depth < 4
-            || config.performance.max_quadtree_entities < 10
-            || config.performance.max_quadtree_entities > 1000
-        {
-            panic!("Invalid values in config file.");
+
+        pub fn load(&self) -> Arc<GameConfig> {
+            self.current.read().unwrap().clone()
+        }
+
+        /// Replaces the config, and sends the new one to everyone who's
+        /// subscribed.
+        pub fn store(&self, config: GameConfig) {
+            let config = Arc::new(config);
+            *self.current.write().unwrap() = config.clone();
+            self.subscribers
+                .lock()
+                .unwrap()
+                .retain(|subscriber| subscriber.send(config.clone()).is_ok());
+        }
+
+        /// Gets every config stored from now on. Dropping the receiver
+        /// unsubscribes.
+        pub fn subscribe(&self) -> Receiver<Arc<GameConfig>> {
+            let (sender, receiver) = unbounded();
+            self.subscribers.lock().unwrap().push(sender);
+            receiver
+        }
+    }
+
+    /// Where each layer of the config comes from.
+    #[derive(Clone, Debug)]
+    pub struct ConfigSources {
+        pub game: PathBuf,
+        pub user: Option<PathBuf>,
+        /// `section.key=value` pairs, applied in order
+        pub overrides: Vec<String>,
+    }
+
+    impl Default for ConfigSources {
+        fn default() -> Self {
+            Self {
+                game: PathBuf::from(DEFAULT_CONFIG_PATH),
+                user: user_config_path(),
+                overrides: vec![],
+            }
+        }
+    }
+
+    fn user_config_path() -> Option<PathBuf> {
+        let base = std::env::var_os("XDG_CONFIG_HOME")
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+        Some(base.join("embryo").join("config.toml"))
+    }
+
+    #[derive(Debug)]
+    pub enum ConfigError {
+        Io(PathBuf, std::io::Error),
+        Parse(PathBuf, toml::de::Error),
+        /// A `--set` that isn't `section.key=value`
+        BadOverride(String),
+        /// Everything merged fine, but doesn't fit together into a config
+        Deserialize(toml::de::Error),
+        Invalid(Vec<InvalidValue>),
+    }
+
+    impl Display for ConfigError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            match self {
+                ConfigError::Io(path, e) => write!(f, "{}: {}", path.display(), e),
+                ConfigError::Parse(path, e) => write!(f, "{}: {}", path.display(), e),
+                ConfigError::BadOverride(o) => {
+                    write!(f, "{:?} should look like section.key=value", o)
+                }
+                ConfigError::Deserialize(e) => write!(f, "{}", e),
+                ConfigError::Invalid(invalid) => {
+                    write!(f, "invalid values in config:")?;
+                    for value in invalid {
+                        write!(f, "\n    {}", value)?;
+                    }
+                    Ok(())
+                }
+            }
+        }
+    }
+
+    static SOURCES: OnceLock<ConfigSources> = OnceLock::new();
+
+    /// Sets where `CONFIG` will be read from. This only does anything before
+    /// the config is first used, which is why `App` does it as soon as it's
+    /// made.
+    pub fn set_sources(sources: ConfigSources) {
+        if SOURCES.set(sources).is_err() {
+            warn!("Config sources were already set, ignoring the new ones");
+        }
+    }
+
+    pub fn sources() -> &'static ConfigSources {
+        SOURCES.get_or_init(ConfigSources::default)
+    }
+
+    /// Loads and validates the config from every layer.
+    pub fn load(sources: &ConfigSources) -> Result<GameConfig, ConfigError> {
+        let mut merged = toml::Table::new();
+        for path in std::iter::once(&sources.game).chain(sources.user.as_ref()) {
+            match std::fs::read_to_string(path) {
+                Ok(contents) => {
+                    info!("Loading configuration file at {}", path.display());
+                    let layer = contents
+                        .parse::<toml::Table>()
+                        .map_err(|e| ConfigError::Parse(path.clone(), e))?;
+                    merge(&mut merged, layer);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                    debug!("No configuration file at {}", path.display());
+                }
+                Err(e) => return Err(ConfigError::Io(path.clone(), e)),
+            }
+        }
+        for o in &sources.overrides {
+            merge(&mut merged, parse_override(o)?);
+        }
+
+        let config: GameConfig = toml::Value::Table(merged)
+            .try_into()
+            .map_err(ConfigError::Deserialize)?;
+        let invalid = config.validate();
+        if invalid.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError::Invalid(invalid))
+        }
+    }
+
+    /// Turns `graphics.fxaa=false` into `{ graphics = { fxaa = false } }`.
+    /// Values are TOML, except that anything that doesn't parse is taken as
+    /// a string, so `graphics.fullscreen_mode=Windowed` doesn't need quotes.
+    fn parse_override(o: &str) -> Result<toml::Table, ConfigError> {
+        let bad = || ConfigError::BadOverride(o.to_string());
+        let (path, value) = o.split_once('=').ok_or_else(bad)?;
+        let value = format!("value = {}", value.trim())
+            .parse::<toml::Table>()
+            .ok()
+            .and_then(|mut t| t.remove("value"))
+            .unwrap_or_else(|| toml::Value::String(value.trim().to_string()));
+
+        let keys: Vec<&str> = path.trim().split('.').collect();
+        if keys.iter().any(|k| k.is_empty()) {
+            return Err(bad());
+        }
+        let mut table = toml::Table::new();
+        let (last, parents) = keys.split_last().unwrap();
+        table.insert(last.to_string(), value);
+        for key in parents.iter().rev() {
+            let mut parent = toml::Table::new();
+            parent.insert(key.to_string(), toml::Value::Table(table));
+            table = parent;
+        }
+        Ok(table)
+    }
+
+    /// Checks the config files for changes every `interval`, and reloads the
+    /// config when they do. A config that doesn't load is logged and
+    /// otherwise ignored, so the game keeps going with the last good one
+    /// while the file's being fixed.
+    ///
+    /// Stops once `running` is false. Unpark the thread after clearing it to
+    /// stop straight away instead of at the end of the current wait.
+    pub fn watch(interval: Duration, running: Arc<AtomicBool>) -> std::io::Result<JoinHandle<()>> {
+        let sources = sources().clone();
+        std::thread::Builder::new()
+            .name("config watcher".into())
+            .spawn(move || {
+                let modified = || -> Vec<Option<SystemTime>> {
+                    std::iter::once(&sources.game)
+                        .chain(sources.user.as_ref())
+                        .map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+                        .collect()
+                };
+                let mut last_modified = modified();
+                while running.load(Ordering::SeqCst) {
+                    std::thread::park_timeout(interval);
+                    if !running.load(Ordering::SeqCst) {
+                        break;
+                    }
+                    let now_modified = modified();
+                    if now_modified == last_modified {
+                        continue;
+                    }
+                    last_modified = now_modified;
+
+                    match load(&sources) {
+                        Ok(config) => {
+                            for field in crate::CONFIG.load().needs_restart(&config) {
+                                warn!("{} changed, but won't take effect until restart", field);
+                            }
+                            crate::CONFIG.store(config);
+                            info!("Reloaded configuration");
+                        }
+                        Err(e) => error!("Couldn't reload config, keeping the old one: {}", e),
+                    }
+                }
+            })
+    }
+
+    pub fn read_config() -> GameConfig {
+        match load(sources()) {
+            Ok(config) => {
+                info!("Successfully read configuration");
+                config
+            }
+            Err(e) => panic!("Couldn't load config: {}", e),
         }
-        info!("Successfully read configuration file");
-        config
     }
 }
 
 pub mod quadtree {
     use std::collections::VecDeque;
 
-    use glam::Vec2Swizzles;
-
     use crate::{entity::Entity, CONFIG};
 
     #[derive(Clone)]
@@ -176,6 +634,12 @@ pub mod quadtree {
         bb_size: (usize, usize),
     }
 
+    impl QuadtreeEntity {
+        pub fn entity(&self) -> Entity {
+            self.entity
+        }
+    }
+
     #[derive(Clone, Debug, PartialEq, Eq)]
     pub enum QuadtreeNodeType {
         Interior,
@@ -192,12 +656,14 @@ pub mod quadtree {
     }
 
     impl QuadtreeNode {
-        pub fn new(half_size: (usize, usize), center: (usize, usize)) -> Self {
+        /// `max_entities` is how many entities the node holds before it
+        /// splits (`performance.max_quadtree_entities`).
+        pub fn new(half_size: (usize, usize), center: (usize, usize), max_entities: usize) -> Self {
             Self {
                 node_type: QuadtreeNodeType::Leaf,
                 half_size,
                 center,
-                entities: Vec::with_capacity(CONFIG.performance.max_quadtree_entities),
+                entities: Vec::with_capacity(max_entities),
             }
         }
     }
@@ -211,7 +677,11 @@ pub mod quadtree {
 
     impl Quadtree {
         pub fn new(map_width: usize, map_height: usize) -> Self {
-            let size = 4_u32.pow(CONFIG.performance.max_quadtree_depth as u32) as usize;
+            // One snapshot for the whole build, so a reload halfway through
+            // can't give us nodes of different sizes
+            let config = CONFIG.load();
+            let max_entities = config.performance.max_quadtree_entities;
+            let size = 4_u32.pow(config.performance.max_quadtree_depth as u32) as usize;
             let mut tree = Self {
                 map_width,
                 map_height,
@@ -220,6 +690,7 @@ pub mod quadtree {
             tree.nodes[0] = QuadtreeNode::new(
                 (map_width / 2, map_height / 2),
                 (map_width / 2, map_height / 2),
+                max_entities,
             );
 
             let mut frontier = VecDeque::from([0]);
@@ -233,6 +704,7 @@ pub mod quadtree {
                     tree.nodes.push(QuadtreeNode::new(
                         (half_size.0 / 2, half_size.1 / 2),
                         (center.0 / 2, center.1 + center.1 / 2),
+                        max_entities,
                     ));
                     frontier.push_back(4 * node_index);
 
@@ -240,6 +712,7 @@ pub mod quadtree {
                     tree.nodes.push(QuadtreeNode::new(
                         (half_size.0 / 2, half_size.1 / 2),
                         (center.0 + center.0 / 2, center.1 + center.1 / 2),
+                        max_entities,
                     ));
                     frontier.push_back(4 * node_index + 1);
 
@@ -247,6 +720,7 @@ pub mod quadtree {
                     tree.nodes.push(QuadtreeNode::new(
                         (half_size.0 / 2, half_size.1 / 2),
                         (center.0 + center.0 / 2, center.1 / 2),
+                        max_entities,
                     ));
                     frontier.push_back(4 * node_index + 1);
 
@@ -254,6 +728,7 @@ pub mod quadtree {
                     tree.nodes.push(QuadtreeNode::new(
                         (half_size.0 / 2, half_size.1 / 2),
                         (center.0 / 2, center.1 / 2),
+                        max_entities,
                     ));
                     frontier.push_back(4 * node_index + 1);
                 }
@@ -261,7 +736,12 @@ pub mod quadtree {
             tree
         }
 
-        pub fn insert(
+        pub fn insert(&mut self, entity: QuadtreeEntity, start_node: usize) {
+            let max_entities = CONFIG.load().performance.max_quadtree_entities;
+            self.insert_with(entity, start_node, max_entities);
+        }
+
+        fn insert_with(
             &mut self,
             entity @ QuadtreeEntity {
                 upper_left: (px, py),
@@ -269,6 +749,7 @@ pub mod quadtree {
                 ..
             }: QuadtreeEntity,
             start_node: usize,
+            max_entities: usize,
         ) {
             let mut current_node = start_node;
             while current_node < self.nodes.len() {
@@ -278,12 +759,7 @@ pub mod quadtree {
                     break;
                 }
 
-                let QuadtreeNode {
-                    node_type,
-                    half_size,
-                    center,
-                    entities,
-                } = &self.nodes[current_node];
+                let QuadtreeNode { node_type, .. } = &self.nodes[current_node];
 
                 if *node_type == QuadtreeNodeType::Interior {
                     // Still need to find a home
@@ -306,22 +782,18 @@ pub mod quadtree {
                         current_node = 4 * current_node + 3;
                     }
                 } else {
-                    if self.nodes[current_node].entities.len()
-                        < CONFIG.performance.max_quadtree_entities
-                    {
+                    if self.nodes[current_node].entities.len() < max_entities {
                         // There aren't many nodes here yet, so no need to split.
                         self.nodes[current_node].entities.push(entity.clone());
-                    } else if self.nodes[current_node].entities.len()
-                        == CONFIG.performance.max_quadtree_entities
-                    {
+                    } else if self.nodes[current_node].entities.len() == max_entities {
                         // Break all the entities out to lower nodes
                         self.nodes[current_node].node_type = QuadtreeNodeType::Interior;
                         let entities: Vec<_> = self.nodes[current_node]
                             .entities
-                            .drain(0..CONFIG.performance.max_quadtree_entities)
+                            .drain(0..max_entities)
                             .collect();
                         for e in entities {
-                            self.insert(e, current_node);
+                            self.insert_with(e, current_node, max_entities);
                         }
 
                         // Continue the search next loop, starting at the same node!
@@ -559,18 +1031,33 @@ pub mod necronomicon {
         }
     }
 
-    pub fn fhtengen<T>(x: &RefCell<RefCell<T>>) -> YogSothoth<T> {
+    pub fn fhtengen<T>(x: &RefCell<RefCell<T>>) -> YogSothoth<'_, T> {
         YogSothoth { inner: x.borrow() }
     }
 }
 
+/// Blends between two transforms, `alpha` of the way from `from` to `to`.
+/// They're decomposed first, so rotations stay rotations.
+pub fn interpolate_transform(from: &glam::Mat4, to: &glam::Mat4, alpha: f32) -> glam::Mat4 {
+    if alpha >= 1.0 || from == to {
+        return *to;
+    }
+    let (from_scale, from_rot, from_trans) = from.to_scale_rotation_translation();
+    let (to_scale, to_rot, to_trans) = to.to_scale_rotation_translation();
+    glam::Mat4::from_scale_rotation_translation(
+        from_scale.lerp(to_scale, alpha),
+        from_rot.slerp(to_rot, alpha),
+        from_trans.lerp(to_trans, alpha),
+    )
+}
+
 pub fn get_entity_transform<'a>(
-    entity_generations: &'a HashMap<EntityID, usize>,
+    entity_generations: &'a [usize],
     entity_transforms: &'a HashMap<EntityID, glam::Mat4>,
     e: Entity,
 ) -> Option<&'a glam::Mat4> {
     if entity_generations
-        .get(&e.id)
+        .get(e.id)
         .filter(|gen| **gen == e.generation)
         .is_some()
     {
@@ -0,0 +1,88 @@
+/*
+ * Copyright (C) 2023 Alexis Purslane <alexispurslane@pm.me>
+ *
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/.
+ */
+
+//! Controller handling, end to end, with a virtual pad standing in for a real
+//! one: SDL hotplug events go through `Controllers`, and everything the pad
+//! does ends up in `InputState`. This needs SDL, but not a display.
+
+use std::time::{Duration, Instant};
+
+use embryo::{
+    controllers::{Controllers, VirtualController},
+    input::InputState,
+    utils::config::ControlConfig,
+};
+use sdl2::{
+    controller::{Axis, Button},
+    EventPump,
+};
+
+/// Hands every event SDL has for us over to the controllers and the input
+/// state, waiting a bit for stragglers, since virtual pad changes only turn
+/// into events when SDL gets around to checking on the pad.
+fn pump(event_pump: &mut EventPump, controllers: &mut Controllers, input: &mut InputState) {
+    let until = Instant::now() + Duration::from_millis(200);
+    while Instant::now() < until {
+        if let Some(event) = event_pump.wait_event_timeout(10) {
+            controllers.handle_event(&event);
+            input.handle_sdl_event(&event);
+        }
+    }
+}
+
+fn stick(value: f32) -> i16 {
+    (value * i16::MAX as f32) as i16
+}
+
+#[test]
+fn virtual_pad_drives_input() {
+    sdl2::hint::set("SDL_JOYSTICK_ALLOW_BACKGROUND_EVENTS", "1");
+    let sdl_context = sdl2::init().unwrap();
+    let mut controllers = Controllers::new(sdl_context.game_controller().unwrap());
+    let mut event_pump = sdl_context.event_pump().unwrap();
+    let mut input = InputState::from_config(&ControlConfig {
+        stick_deadzone: 0.2,
+        ..ControlConfig::default()
+    });
+
+    let mut pad = VirtualController::attach().expect("Couldn't attach a virtual controller");
+    pump(&mut event_pump, &mut controllers, &mut input);
+    assert_eq!(controllers.connected(), 1, "hotplug didn't pick up the pad");
+
+    // Use is bound to Pad:a by default
+    pad.set_button(Button::A, true);
+    pump(&mut event_pump, &mut controllers, &mut input);
+    assert!(input.held("Use"));
+
+    // MoveRight is bound to Pad:leftx by default. Inside the deadzone
+    // counts for nothing...
+    pad.set_axis(Axis::LeftX, stick(0.15));
+    pump(&mut event_pump, &mut controllers, &mut input);
+    assert_eq!(input.axis("MoveRight"), 0.0);
+
+    // ...but the deadzone is radial, so pushing the stick diagonally past it
+    // counts, even though neither axis is past it on its own
+    pad.set_axis(Axis::LeftY, stick(0.15));
+    pump(&mut event_pump, &mut controllers, &mut input);
+    assert!(input.axis("MoveRight") > 0.0);
+
+    // Past the deadzone gets stretched back out to the full range
+    pad.set_axis(Axis::LeftY, 0);
+    pad.set_axis(Axis::LeftX, stick(0.6));
+    pump(&mut event_pump, &mut controllers, &mut input);
+    let expected = (0.6 - 0.2) / (1.0 - 0.2);
+    assert!((input.axis("MoveRight") - expected).abs() < 0.01);
+
+    pad.set_button(Button::A, false);
+    pump(&mut event_pump, &mut controllers, &mut input);
+    assert!(!input.held("Use"));
+
+    drop(pad);
+    pump(&mut event_pump, &mut controllers, &mut input);
+    assert_eq!(controllers.connected(), 0, "unplugging didn't drop the pad");
+}